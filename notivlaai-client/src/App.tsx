@@ -7,7 +7,9 @@ import { OrderComponent, OrderComponentType } from './OrderComponent';
 import { OrderContainer } from './components';
 import useTimedListener from './Listener';
 import { NotivlaaiStore } from './store';
-import { isAddOrder, isInitialize, isRemoveOrder } from './messages';
+import {
+  isAddOrder, isInitialize, isPaymentWarning, isRemoveOrder, isUpdateFailed,
+} from './messages';
 import playBell from "./bell";
 
 interface OrderRoomProps {
@@ -56,6 +58,16 @@ export default function OrderRoom({
       else if (isInitialize(notification)) replaceOrders(notification.initialize);
       // Remove an order when requested
       else if (isRemoveOrder(notification)) removeOrder(notification.removeOrder);
+      // The order stays as it is, the server could not update it
+      else if (isUpdateFailed(notification)) {
+        const { id, reason } = notification.updateFailed;
+        console.warn(`Could not update order ${id}: ${reason}`);
+      }
+      // The order has been picked up, but still has to be paid
+      else if (isPaymentWarning(notification)) {
+        const { id, outstandingCents } = notification.paymentWarning;
+        console.warn(`Order ${id} was picked up with €${(outstandingCents / 100).toFixed(2)} outstanding`);
+      }
       else throw new Error('Cannot decode web-socket message');
     }, [notification]);
   } else {
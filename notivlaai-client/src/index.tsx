@@ -14,10 +14,11 @@ const [useStoreHook] = setupStore();
 
 // Get suggestions function
 const getSuggestions = async (find: string) => {
-  const response = await fetch(`customer/find/${find}`);
+  const response = await fetch(`customers/search?q=${encodeURIComponent(find)}`);
   if (response.ok) {
+    // Only the first page of suggestions is shown
     const json = await response.json();
-    return json as [number, string][];
+    return json.items as [number, string][];
   }
 
   return [];
@@ -34,9 +35,28 @@ const getOrders = async (id: number) => {
   return [];
 };
 
+// Change the status of an order, retrying when the network fails. The idempotency key makes
+// sure the server changes the order only once, even when a retry repeats a request that
+// did arrive
+const changeStatus = async (id: number, status: string) => {
+  const key = `${id}-${status}-${Date.now()}-${Math.random().toString(36).slice(2)}`;
+  for (let attempt = 1; ; attempt += 1) {
+    try {
+      // eslint-disable-next-line no-await-in-loop
+      return await fetch(`orders/${id}`, {
+        method: 'PATCH',
+        headers: { 'Content-Type': 'application/json', 'Idempotency-Key': key },
+        body: JSON.stringify({ status }),
+      });
+    } catch (e) {
+      if (attempt >= 3) throw e;
+    }
+  }
+};
+
 // Set in transit function
 const inTransit = async (id: number) => {
-  const response = await fetch(`order/in_transit/${id}`);
+  const response = await changeStatus(id, 'inTransit');
   if (!response.ok) {
     throw new Error('Cannot set order in transit');
   }
@@ -45,7 +65,7 @@ const inTransit = async (id: number) => {
 // Set order as retrieved
 const orderRetrieved = async (id: number) => {
   // Ok we have retrieved this order
-  const response = await fetch(`order/retrieved/${id}`);
+  const response = await changeStatus(id, 'pickedUp');
   if (!response.ok) {
     throw new Error('Cannot set order as retrieved');
   }
@@ -59,8 +79,8 @@ function Application() {
   useEffect(() => {
     if (!started) {
       const { location } = window;
-      const { hostname } = location;
-      const url = `ws://${hostname}:9001`;
+      const protocol = location.protocol === 'https:' ? 'wss:' : 'ws:';
+      const url = `${protocol}//${location.host}/ws/orders`;
       console.log(url);
       const webSocketWrapper = createWebSocketWrapper(url);
       webSocketWrapper.onMessage((e) => {
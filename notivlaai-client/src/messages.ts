@@ -12,11 +12,24 @@ interface RemoveOrderMessage {
   removeOrder: number;
 }
 
+interface UpdateFailedMessage {
+  updateFailed: { id: number; reason: string };
+}
+
+interface PaymentWarningMessage {
+  paymentWarning: { id: number; outstandingCents: number };
+}
+
 /**
  * All types of messages
  *
  */
-export type NotificationMessage = InitializeMessage | AddOrderMessage | RemoveOrderMessage;
+export type NotificationMessage =
+  | InitializeMessage
+  | AddOrderMessage
+  | RemoveOrderMessage
+  | UpdateFailedMessage
+  | PaymentWarningMessage;
 
 /**
  * Type guard for initialize message
@@ -41,3 +54,19 @@ export function isRemoveOrder(message: NotificationMessage): message is RemoveOr
   if ((message as RemoveOrderMessage).removeOrder) return true;
   return false;
 }
+
+/**
+ * Type guard for a failed update message
+ */
+export function isUpdateFailed(message: NotificationMessage): message is UpdateFailedMessage {
+  if ((message as UpdateFailedMessage).updateFailed) return true;
+  return false;
+}
+
+/**
+ * Type guard for an order that was picked up without being paid in full
+ */
+export function isPaymentWarning(message: NotificationMessage): message is PaymentWarningMessage {
+  if ((message as PaymentWarningMessage).paymentWarning) return true;
+  return false;
+}
@@ -23,7 +23,10 @@ function innerSetupStore() {
     orders: [],
     notification: null,
     notify: (notificationMessage: NotificationMessage) => set((state) => ({notification: notificationMessage})),
-    addOrder: (order: OrderType) => set((state) => ({ orders: [...state.orders, order] })),
+    // An order that is already shown is replaced, e.g. when it becomes ready
+    addOrder: (order: OrderType) => set((state) => ({
+      orders: [...state.orders.filter((v: OrderType) => v.id !== order.id), order],
+    })),
     replaceOrders: (orders: [OrderType]) => set(() => ({ orders: [...orders] })),
     removeOrder: async (id: number) => {
       set((state) => ({ orders: [...state.orders.filter((v: OrderType) => v.id !== id)] }));
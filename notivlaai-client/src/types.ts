@@ -46,4 +46,7 @@ export interface OrderType {
   inTransit: boolean;
   pickedUp: boolean;
   rows: Array<OrderRow>;
+  totalCents?: number;
+  paidCents?: number;
+  outstandingCents?: number;
 }
@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE `order` ADD COLUMN in_transit BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE `order` ADD COLUMN picked_up BOOLEAN NOT NULL DEFAULT false;
+
+UPDATE `order` SET
+    in_transit = status IN ('in_transit', 'ready'),
+    picked_up = status = 'picked_up';
+
+ALTER TABLE `order` DROP COLUMN status;
@@ -0,0 +1,12 @@
+-- Replace the in_transit/picked_up booleans with a single status column
+ALTER TABLE `order` ADD COLUMN status VARCHAR NOT NULL DEFAULT 'new'
+    CHECK (status IN ('new', 'in_transit', 'ready', 'picked_up', 'cancelled'));
+
+UPDATE `order` SET status = CASE
+    WHEN picked_up THEN 'picked_up'
+    WHEN in_transit THEN 'in_transit'
+    ELSE 'new'
+END;
+
+ALTER TABLE `order` DROP COLUMN in_transit;
+ALTER TABLE `order` DROP COLUMN picked_up;
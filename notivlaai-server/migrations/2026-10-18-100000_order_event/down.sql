@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+drop index order_event_order_id;
+drop table order_event;
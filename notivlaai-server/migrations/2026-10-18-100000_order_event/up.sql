@@ -0,0 +1,12 @@
+-- Every status change of an order is recorded here
+CREATE TABLE order_event (
+    id INTEGER NOT NULL PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    previous_status VARCHAR,
+    new_status VARCHAR NOT NULL,
+    operator VARCHAR,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(order_id) REFERENCES `Order`(id)
+);
+
+CREATE INDEX order_event_order_id ON order_event(order_id);
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE vlaai DROP COLUMN description;
+ALTER TABLE vlaai DROP COLUMN image;
+ALTER TABLE vlaai DROP COLUMN active;
+ALTER TABLE vlaai DROP COLUMN price_cents;
@@ -0,0 +1,5 @@
+-- The vlaaien that can be ordered, with their price and how they are shown
+ALTER TABLE vlaai ADD COLUMN price_cents INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE vlaai ADD COLUMN active BOOLEAN NOT NULL DEFAULT true;
+ALTER TABLE vlaai ADD COLUMN image VARCHAR;
+ALTER TABLE vlaai ADD COLUMN description VARCHAR;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+drop index payment_order_id;
+drop table payment;
@@ -0,0 +1,12 @@
+-- Payments received for an order, a refund has a negative amount
+CREATE TABLE payment (
+    id INTEGER NOT NULL PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    amount_cents INTEGER NOT NULL,
+    method VARCHAR NOT NULL,
+    operator VARCHAR,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(order_id) REFERENCES `Order`(id)
+);
+
+CREATE INDEX payment_order_id ON payment(order_id);
@@ -0,0 +1,4 @@
+-- Orders created by the importer, a later import of the form may change or remove them
+ALTER TABLE "order" ADD COLUMN imported BOOLEAN NOT NULL DEFAULT false;
+-- Until now every order was loaded from the form or seeded
+UPDATE "order" SET imported = true;
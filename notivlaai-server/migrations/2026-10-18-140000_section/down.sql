@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX customer_section_id;
+ALTER TABLE customer DROP COLUMN section_id;
+DROP TABLE section;
@@ -0,0 +1,11 @@
+-- The sections (speltakken) of the scouting group, the vlaaien are sold and handed out per section
+CREATE TABLE section (
+    id INTEGER NOT NULL PRIMARY KEY,
+    name VARCHAR NOT NULL
+);
+
+CREATE UNIQUE INDEX section_name ON section(name COLLATE NOCASE);
+
+ALTER TABLE customer ADD COLUMN section_id INTEGER REFERENCES section(id);
+
+CREATE INDEX customer_section_id ON customer(section_id);
@@ -0,0 +1,336 @@
+//! Errors of the HTTP API, every route reports these as a JSON body like
+//! `{"error": "Order 42 does not exist", "code": "notFound"}`
+use crate::db::{
+    CatalogueError, CustomerError, DatabaseError, OrderError, SectionError, StatusError,
+};
+use crate::idempotency::StoredResponse;
+use crate::status_updater::UpdateError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::fmt;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+/// Something that went wrong handling a request
+#[derive(Clone, Debug, PartialEq)]
+pub enum ApiError {
+    /// The requested item does not exist
+    NotFound(String),
+    /// The request conflicts with the current state, e.g. a duplicate or a status change
+    /// that is not allowed
+    Conflict(String),
+    /// The request itself is not valid
+    Validation(String),
+    /// The database could not be reached, trying again later may help
+    Unavailable(String),
+    /// The database returned an error
+    Database(String),
+    /// The request cannot be handled for another reason, e.g. it uses the wrong method
+    Request(StatusCode, String),
+    /// Anything else
+    Internal(String),
+}
+
+/// The JSON body of an error response
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    code: &'static str,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Validation(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Request(status, _) => *status,
+            ApiError::Database(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Identifies the kind of error for clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotFound(_) => "notFound",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Validation(_) => "validation",
+            ApiError::Unavailable(_) => "unavailable",
+            ApiError::Database(_) => "database",
+            ApiError::Request(..) => "request",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::Validation(message)
+            | ApiError::Unavailable(message)
+            | ApiError::Database(message)
+            | ApiError::Request(_, message)
+            | ApiError::Internal(message) => message,
+        }
+    }
+
+    /// The JSON body of the response
+    pub fn body(&self) -> serde_json::Value {
+        serde_json::to_value(&ErrorBody {
+            error: self.message(),
+            code: self.code(),
+        })
+        .expect("Could not convert error to json")
+    }
+
+    /// Convert an error from the `db` module, where a missing row means `what` does not exist
+    pub fn from_anyhow(e: anyhow::Error, what: impl fmt::Display) -> ApiError {
+        if let Some(DieselError::NotFound) = e.downcast_ref::<DieselError>() {
+            return ApiError::NotFound(format!("{} does not exist", what));
+        }
+        match e.downcast::<DieselError>() {
+            Ok(e) => ApiError::from(e),
+            Err(e) => match e.downcast::<StatusError>() {
+                Ok(e) => ApiError::from(e),
+                Err(e) => ApiError::Internal(e.to_string()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl warp::reject::Reject for ApiError {}
+
+impl Reply for ApiError {
+    fn into_response(self) -> warp::reply::Response {
+        warp::reply::with_status(warp::reply::json(&self.body()), self.status()).into_response()
+    }
+}
+
+impl From<DieselError> for ApiError {
+    fn from(e: DieselError) -> Self {
+        match e {
+            DieselError::NotFound => ApiError::NotFound("Not found".to_string()),
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info)
+            | DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, info) => {
+                ApiError::Conflict(info.message().to_string())
+            }
+            // SQLite reports a locked database as an unknown error
+            DieselError::DatabaseError(_, info) if info.message().contains("locked") => {
+                ApiError::Unavailable(info.message().to_string())
+            }
+            e => ApiError::Database(e.to_string()),
+        }
+    }
+}
+
+impl From<DatabaseError> for ApiError {
+    fn from(e: DatabaseError) -> Self {
+        ApiError::Unavailable(e.to_string())
+    }
+}
+
+impl From<StatusError> for ApiError {
+    fn from(e: StatusError) -> Self {
+        match e {
+            StatusError::NotFound(_) => ApiError::NotFound(e.to_string()),
+            StatusError::InvalidTransition { .. } => ApiError::Conflict(e.to_string()),
+            StatusError::Database(e) => ApiError::from(e),
+        }
+    }
+}
+
+impl From<CustomerError> for ApiError {
+    fn from(e: CustomerError) -> Self {
+        match e {
+            CustomerError::NotFound(_) => ApiError::NotFound(e.to_string()),
+            CustomerError::Invalid(message) => ApiError::Validation(message),
+            CustomerError::Duplicate(_)
+            | CustomerError::OpenOrders { .. }
+            | CustomerError::ClosedOrders { .. } => ApiError::Conflict(e.to_string()),
+            CustomerError::Database(e) => ApiError::from(e),
+        }
+    }
+}
+
+impl From<CatalogueError> for ApiError {
+    fn from(e: CatalogueError) -> Self {
+        match e {
+            CatalogueError::NotFound(_) => ApiError::NotFound(e.to_string()),
+            CatalogueError::Invalid(message) => ApiError::Validation(message),
+            CatalogueError::Duplicate(message) => ApiError::Conflict(message),
+            CatalogueError::Database(e) => ApiError::from(e),
+        }
+    }
+}
+
+impl From<SectionError> for ApiError {
+    fn from(e: SectionError) -> Self {
+        match e {
+            SectionError::NotFound(_) => ApiError::NotFound(e.to_string()),
+            SectionError::Invalid(message) => ApiError::Validation(message),
+            SectionError::Duplicate(message) => ApiError::Conflict(message),
+            SectionError::Database(e) => ApiError::from(e),
+        }
+    }
+}
+
+impl From<OrderError> for ApiError {
+    fn from(e: OrderError) -> Self {
+        match e {
+            OrderError::NotFound(_) | OrderError::CustomerNotFound(_) => {
+                ApiError::NotFound(e.to_string())
+            }
+            OrderError::VlaaiNotFound(_) | OrderError::Invalid(_) => {
+                ApiError::Validation(e.to_string())
+            }
+            OrderError::Closed { .. } => ApiError::Conflict(e.to_string()),
+            OrderError::Database(e) => ApiError::from(e),
+        }
+    }
+}
+
+impl From<UpdateError> for ApiError {
+    fn from(e: UpdateError) -> Self {
+        match e {
+            UpdateError::NotFound(message) => ApiError::NotFound(message),
+            UpdateError::Conflict(message) => ApiError::Conflict(message),
+            UpdateError::Failed(message) => ApiError::Internal(message),
+        }
+    }
+}
+
+impl From<ApiError> for Rejection {
+    fn from(e: ApiError) -> Self {
+        warp::reject::custom(e)
+    }
+}
+
+impl From<&ApiError> for StoredResponse {
+    fn from(e: &ApiError) -> Self {
+        StoredResponse {
+            status: e.status().as_u16(),
+            body: e.body(),
+        }
+    }
+}
+
+/// Turn the rejections of all routes into JSON error responses
+pub async fn recover(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    use warp::reject::{
+        InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader,
+        PayloadTooLarge, UnsupportedMediaType,
+    };
+
+    let error = if let Some(e) = rejection.find::<ApiError>() {
+        e.clone()
+    } else if rejection.is_not_found() {
+        ApiError::NotFound("Not found".to_string())
+    } else if let Some(e) = rejection.find::<warp::body::BodyDeserializeError>() {
+        ApiError::Validation(e.to_string())
+    } else if let Some(e) = rejection.find::<InvalidQuery>() {
+        ApiError::Validation(e.to_string())
+    } else if let Some(e) = rejection.find::<MissingHeader>() {
+        ApiError::Validation(e.to_string())
+    } else if let Some(e) = rejection.find::<InvalidHeader>() {
+        ApiError::Validation(e.to_string())
+    } else if let Some(e) = rejection.find::<MethodNotAllowed>() {
+        ApiError::Request(StatusCode::METHOD_NOT_ALLOWED, e.to_string())
+    } else if let Some(e) = rejection.find::<LengthRequired>() {
+        ApiError::Request(StatusCode::LENGTH_REQUIRED, e.to_string())
+    } else if let Some(e) = rejection.find::<PayloadTooLarge>() {
+        ApiError::Request(StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+    } else if let Some(e) = rejection.find::<UnsupportedMediaType>() {
+        ApiError::Request(StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string())
+    } else {
+        ApiError::Internal(format!("Unhandled rejection: {:?}", rejection))
+    };
+
+    if error.status().is_server_error() {
+        log::error!("{}", error);
+    }
+    Ok(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ApiError;
+    use crate::db::OrderStatus;
+    use crate::db::StatusError;
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    #[test]
+    fn status_errors() {
+        let error = ApiError::from(StatusError::NotFound(42));
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+        assert_eq!(error.body()["error"], "Order 42 does not exist");
+        assert_eq!(error.body()["code"], "notFound");
+
+        let error = ApiError::from(StatusError::InvalidTransition {
+            order_id: 1,
+            from: OrderStatus::New,
+            to: OrderStatus::PickedUp,
+        });
+        assert_eq!(error.status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn missing_rows() {
+        let error = ApiError::from_anyhow(diesel::result::Error::NotFound.into(), "Customer 3");
+        assert_eq!(
+            error,
+            ApiError::NotFound("Customer 3 does not exist".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn recovered_rejections() {
+        let routes = warp::path!("fails")
+            .and_then(|| async {
+                Err::<String, _>(warp::reject::custom(ApiError::Validation("No".to_string())))
+            })
+            .or(warp::path!("json")
+                .and(warp::post())
+                .and(warp::body::json())
+                .map(|v: serde_json::Value| v.to_string()))
+            .recover(super::recover);
+
+        let resp = warp::test::request().path("/fails").reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["code"], "validation");
+
+        let resp = warp::test::request().path("/nothing").reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["code"], "notFound");
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/json")
+            .header("content-type", "application/json")
+            .body("{")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/json")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["code"], "request");
+    }
+}
@@ -0,0 +1,466 @@
+//! Configuration of the server, which can be set from a TOML file, environment variables
+//! (or the `.env` file) and command-line flags. Flags take precedence over the environment,
+//! which takes precedence over the file.
+use crate::db::ConnectionOptions;
+use serde::Deserialize;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// The configuration file that is used when `--config` is not given, if it exists
+pub const DEFAULT_CONFIG_FILE: &str = "notivlaai.toml";
+
+/// Whether we are developing or running the real thing
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Only listen on localhost and use `DATABASE_URL`
+    Dev,
+    /// Listen on all interfaces and use `DATABASE_URL_PROD`
+    Prod,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dev" | "development" => Ok(Mode::Dev),
+            "prod" | "production" => Ok(Mode::Prod),
+            other => Err(format!("'{}' is not a mode, use 'dev' or 'prod'", other)),
+        }
+    }
+}
+
+/// Settings that have not been validated yet, every layer of configuration fills in
+/// some of these
+#[derive(Deserialize, StructOpt, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Run in `dev` or `prod` mode
+    #[structopt(long)]
+    pub mode: Option<Mode>,
+    /// Path of the SQLite database
+    #[structopt(long)]
+    pub database_url: Option<String>,
+    /// Directory with the files of the client
+    #[structopt(long, parse(from_os_str))]
+    pub static_files: Option<PathBuf>,
+    /// Address to listen on, defaults to localhost in dev mode and all interfaces otherwise
+    #[structopt(long)]
+    pub bind_address: Option<IpAddr>,
+    /// Port of the HTTP server
+    #[structopt(long)]
+    pub http_port: Option<u16>,
+    /// Also serve the order websocket on its own port, next to `/ws/orders`
+    #[structopt(long)]
+    pub standalone_ws: Option<bool>,
+    /// Port of the standalone websocket server
+    #[structopt(long)]
+    pub ws_port: Option<u16>,
+    /// Number of order updates that can wait to be processed
+    #[structopt(long)]
+    pub update_queue_capacity: Option<usize>,
+    /// Number of published order changes a slow websocket client may lag behind
+    #[structopt(long)]
+    pub broadcast_capacity: Option<usize>,
+    /// Milliseconds to wait for a locked database, 0 disables waiting
+    #[structopt(long)]
+    pub busy_timeout_ms: Option<u64>,
+    /// Enforce foreign keys in the database
+    #[structopt(long)]
+    pub foreign_keys: Option<bool>,
+    /// Keep serving the deprecated `GET /order/retrieved/:id`, `GET /order/in_transit/:id` and
+    /// `GET /customer/find/:name`
+    #[structopt(long)]
+    pub legacy_get_routes: Option<bool>,
+}
+
+/// Command-line arguments of the server
+#[derive(StructOpt, Debug, Default)]
+#[structopt(name = "notivlaai-server")]
+pub struct Args {
+    /// TOML configuration file, `notivlaai.toml` is used if it exists
+    #[structopt(short, long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    #[structopt(flatten)]
+    pub settings: Settings,
+}
+
+/// Errors that can occur when loading the configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read
+    Read(PathBuf, std::io::Error),
+    /// The configuration file is not valid TOML or contains unknown settings
+    Parse(PathBuf, toml::de::Error),
+    /// An environment variable could not be parsed
+    Environment { variable: String, message: String },
+    /// The combined configuration is not valid
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Could not parse {}: {}", path.display(), e),
+            ConfigError::Environment { variable, message } => {
+                write!(f, "Invalid value for {}: {}", variable, message)
+            }
+            ConfigError::Invalid(message) => write!(f, "Invalid configuration: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Read(_, e) => Some(e),
+            ConfigError::Parse(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Parse an environment variable, if it is set
+fn parse_var<T, F>(lookup: &F, variable: &str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+    F: Fn(&str) -> Option<String>,
+{
+    match lookup(variable) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e: T::Err| ConfigError::Environment {
+                variable: variable.to_string(),
+                message: e.to_string(),
+            }),
+        None => Ok(None),
+    }
+}
+
+impl Settings {
+    /// Read the settings from a TOML file
+    pub fn from_file(path: &Path) -> Result<Settings, ConfigError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    /// Read the settings from environment variables using `lookup`, the database is read
+    /// from `DATABASE_URL` in dev mode and from `DATABASE_URL_PROD` otherwise
+    pub fn from_env<F>(lookup: F, mode: Mode) -> Result<Settings, ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let database_variable = match mode {
+            Mode::Dev => "DATABASE_URL",
+            Mode::Prod => "DATABASE_URL_PROD",
+        };
+        Ok(Settings {
+            mode: parse_var(&lookup, "MODE")?,
+            database_url: lookup(database_variable),
+            static_files: lookup("STATIC_FILES").map(PathBuf::from),
+            bind_address: parse_var(&lookup, "BIND_ADDRESS")?,
+            http_port: parse_var(&lookup, "HTTP_PORT")?,
+            standalone_ws: parse_var(&lookup, "STANDALONE_WS")?,
+            ws_port: parse_var(&lookup, "WS_PORT")?,
+            update_queue_capacity: parse_var(&lookup, "UPDATE_QUEUE_CAPACITY")?,
+            broadcast_capacity: parse_var(&lookup, "BROADCAST_CAPACITY")?,
+            busy_timeout_ms: parse_var(&lookup, "BUSY_TIMEOUT_MS")?,
+            foreign_keys: parse_var(&lookup, "FOREIGN_KEYS")?,
+            legacy_get_routes: parse_var(&lookup, "LEGACY_GET_ROUTES")?,
+        })
+    }
+
+    /// Use our settings, and fall back on `other` for those we do not have
+    pub fn or(self, other: Settings) -> Settings {
+        Settings {
+            mode: self.mode.or(other.mode),
+            database_url: self.database_url.or(other.database_url),
+            static_files: self.static_files.or(other.static_files),
+            bind_address: self.bind_address.or(other.bind_address),
+            http_port: self.http_port.or(other.http_port),
+            standalone_ws: self.standalone_ws.or(other.standalone_ws),
+            ws_port: self.ws_port.or(other.ws_port),
+            update_queue_capacity: self.update_queue_capacity.or(other.update_queue_capacity),
+            broadcast_capacity: self.broadcast_capacity.or(other.broadcast_capacity),
+            busy_timeout_ms: self.busy_timeout_ms.or(other.busy_timeout_ms),
+            foreign_keys: self.foreign_keys.or(other.foreign_keys),
+            legacy_get_routes: self.legacy_get_routes.or(other.legacy_get_routes),
+        }
+    }
+}
+
+/// The validated configuration of the server
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub mode: Mode,
+    pub database_url: String,
+    pub static_files: PathBuf,
+    pub bind_address: IpAddr,
+    pub http_port: u16,
+    pub standalone_ws: bool,
+    pub ws_port: u16,
+    pub update_queue_capacity: usize,
+    pub broadcast_capacity: usize,
+    pub busy_timeout: Option<Duration>,
+    pub foreign_keys: bool,
+    /// Serve the old GET routes that change the status of an order
+    pub legacy_get_routes: bool,
+}
+
+impl Config {
+    /// Load the configuration from the file, the environment and the command-line arguments
+    pub fn load(args: &Args) -> Result<Config, ConfigError> {
+        dotenv::dotenv().ok();
+        Config::load_with(args, |variable| std::env::var(variable).ok())
+    }
+
+    /// Load the configuration, reading environment variables with `lookup`
+    pub fn load_with<F>(args: &Args, lookup: F) -> Result<Config, ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let file = match &args.config {
+            Some(path) => Settings::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Settings::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Settings::default(),
+        };
+
+        // The mode decides which environment variable holds the database
+        let mode = match args.settings.mode {
+            Some(mode) => mode,
+            None => parse_var(&lookup, "MODE")?
+                .or(file.mode)
+                .unwrap_or(Mode::Dev),
+        };
+        let env = Settings::from_env(lookup, mode)?;
+
+        Config::from_settings(args.settings.clone().or(env).or(file))
+    }
+
+    /// Fill in the defaults and validate the settings
+    pub fn from_settings(settings: Settings) -> Result<Config, ConfigError> {
+        let mode = settings.mode.unwrap_or(Mode::Dev);
+        let config = Config {
+            mode,
+            database_url: settings.database_url.ok_or_else(|| {
+                ConfigError::Invalid(
+                    "no database configured, set database_url in the configuration file, \
+                     DATABASE_URL (DATABASE_URL_PROD in prod mode) or --database-url"
+                        .to_string(),
+                )
+            })?,
+            static_files: settings
+                .static_files
+                .unwrap_or_else(|| PathBuf::from("static")),
+            bind_address: settings.bind_address.unwrap_or(match mode {
+                Mode::Dev => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                Mode::Prod => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            }),
+            http_port: settings.http_port.unwrap_or(3030),
+            standalone_ws: settings.standalone_ws.unwrap_or(false),
+            ws_port: settings.ws_port.unwrap_or(9001),
+            update_queue_capacity: settings.update_queue_capacity.unwrap_or(100),
+            broadcast_capacity: settings.broadcast_capacity.unwrap_or(100),
+            busy_timeout: match settings.busy_timeout_ms.unwrap_or(10_000) {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            foreign_keys: settings.foreign_keys.unwrap_or(true),
+            legacy_get_routes: settings.legacy_get_routes.unwrap_or(false),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.database_url.trim().is_empty() {
+            return Err(ConfigError::Invalid("database_url is empty".to_string()));
+        }
+        if self.http_port == 0 || self.ws_port == 0 {
+            return Err(ConfigError::Invalid("ports cannot be 0".to_string()));
+        }
+        if self.standalone_ws && self.http_port == self.ws_port {
+            return Err(ConfigError::Invalid(format!(
+                "http_port and ws_port are both {}",
+                self.http_port
+            )));
+        }
+        if self.update_queue_capacity == 0 || self.broadcast_capacity == 0 {
+            return Err(ConfigError::Invalid(
+                "channel capacities must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Address of the HTTP server
+    pub fn http_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.http_port)
+    }
+
+    /// Address of the standalone websocket server
+    pub fn ws_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.ws_port)
+    }
+
+    /// Options applied to every database connection
+    pub fn connection_options(&self) -> ConnectionOptions {
+        ConnectionOptions {
+            enable_foreign_keys: self.foreign_keys,
+            busy_timeout: self.busy_timeout,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Args, Config, ConfigError, Mode, Settings};
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use structopt::StructOpt;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |variable| vars.get(variable).cloned()
+    }
+
+    /// Writes the configuration file for a test, and removes it afterwards
+    struct ConfigFile(std::path::PathBuf);
+
+    impl ConfigFile {
+        fn new(name: &str, contents: &str) -> ConfigFile {
+            let path = std::env::temp_dir().join(format!(
+                "notivlaai-config-{}-{}.toml",
+                std::process::id(),
+                name
+            ));
+            std::fs::write(&path, contents).unwrap();
+            ConfigFile(path)
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let args = Args::from_iter(&["notivlaai-server"]);
+        let config =
+            Config::load_with(&args, env(&[("DATABASE_URL", "notivlaai.sqlite3")])).unwrap();
+        assert_eq!(config.mode, Mode::Dev);
+        assert_eq!(config.database_url, "notivlaai.sqlite3");
+        assert_eq!(config.http_addr().to_string(), "127.0.0.1:3030");
+        assert!(!config.standalone_ws);
+        assert!(!config.legacy_get_routes);
+        assert_eq!(config.ws_addr().to_string(), "127.0.0.1:9001");
+        assert_eq!(config.busy_timeout, Some(Duration::from_secs(10)));
+        assert!(config.foreign_keys);
+    }
+
+    #[test]
+    fn precedence() {
+        let file = ConfigFile::new(
+            "precedence",
+            r#"
+            mode = "prod"
+            database_url = "file.sqlite3"
+            http_port = 8080
+            ws_port = 8081
+            broadcast_capacity = 10
+            "#,
+        );
+        let path = file.0.to_string_lossy().to_string();
+
+        // The file sets the mode, so the production database is read from the environment
+        let args = Args::from_iter(&["notivlaai-server", "--config", &path, "--http-port", "80"]);
+        let config = Config::load_with(
+            &args,
+            env(&[
+                ("DATABASE_URL", "dev.sqlite3"),
+                ("DATABASE_URL_PROD", "prod.sqlite3"),
+                ("WS_PORT", "9000"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.mode, Mode::Prod);
+        assert_eq!(config.database_url, "prod.sqlite3");
+        assert_eq!(config.http_addr().to_string(), "0.0.0.0:80");
+        assert_eq!(config.ws_port, 9000);
+        assert_eq!(config.broadcast_capacity, 10);
+
+        // A flag overrides the mode of the file and the environment
+        let args = Args::from_iter(&["notivlaai-server", "-c", &path, "--mode", "dev"]);
+        let config = Config::load_with(
+            &args,
+            env(&[("MODE", "prod"), ("DATABASE_URL", "dev.sqlite3")]),
+        )
+        .unwrap();
+        assert_eq!(config.mode, Mode::Dev);
+        assert_eq!(config.database_url, "dev.sqlite3");
+    }
+
+    #[test]
+    fn validation() {
+        let settings = Settings {
+            database_url: Some("notivlaai.sqlite3".to_string()),
+            ..Default::default()
+        };
+        assert!(Config::from_settings(settings.clone()).is_ok());
+
+        assert!(matches!(
+            Config::from_settings(Settings::default()),
+            Err(ConfigError::Invalid(_))
+        ));
+        // The websocket port only matters when the standalone server is used
+        assert!(Config::from_settings(Settings {
+            ws_port: Some(3030),
+            ..settings.clone()
+        })
+        .is_ok());
+        assert!(matches!(
+            Config::from_settings(Settings {
+                ws_port: Some(3030),
+                standalone_ws: Some(true),
+                ..settings.clone()
+            }),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            Config::from_settings(Settings {
+                update_queue_capacity: Some(0),
+                ..settings
+            }),
+            Err(ConfigError::Invalid(_))
+        ));
+
+        let args = Args::from_iter(&["notivlaai-server"]);
+        assert!(matches!(
+            Config::load_with(&args, env(&[("HTTP_PORT", "http")])),
+            Err(ConfigError::Environment { .. })
+        ));
+
+        let file = ConfigFile::new("unknown", "http_prot = 3030");
+        let args = Args::from_iter(&["notivlaai-server", "-c", &file.0.to_string_lossy()]);
+        assert!(matches!(
+            Config::load_with(&args, env(&[])),
+            Err(ConfigError::Parse(..))
+        ));
+    }
+}
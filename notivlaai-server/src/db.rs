@@ -1,24 +1,200 @@
+use crate::config::Config;
 use crate::schema::*;
+use crate::search::{escape_like, SearchFields};
 use connection::SimpleConnection;
+use diesel::deserialize::{self, FromSql};
 use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::{Bool, Integer, Nullable, Text};
+use diesel::sqlite::Sqlite;
 use diesel::SqliteConnection;
 use diesel::*;
-use lazy_static::lazy_static;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
 
-#[derive(Associations, Identifiable, Queryable)]
+#[derive(Associations, Identifiable, Queryable, Serialize, Clone, Debug, PartialEq)]
 #[table_name = "customer"]
+#[serde(rename_all = "camelCase")]
 pub struct Customer {
     pub id: i32,
     pub name: String,
     pub email: Option<String>,
+    pub section_id: Option<i32>,
 }
 
-#[derive(Associations, Identifiable, Queryable)]
+/// A section (speltak) of the scouting group, customers buy their vlaaien through a section
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug, PartialEq)]
+#[table_name = "section"]
+#[serde(rename_all = "camelCase")]
+pub struct Section {
+    pub id: i32,
+    pub name: String,
+}
+
+/// A vlaai in the catalogue
+#[derive(Associations, Identifiable, Queryable, Serialize, Clone, Debug, PartialEq)]
 #[table_name = "vlaai"]
+#[serde(rename_all = "camelCase")]
 pub struct Vlaai {
     pub id: i32,
     pub name: String,
+    pub price_cents: i32,
+    /// Only active vlaaien can be added to orders
+    pub active: bool,
+    pub image: Option<String>,
+    pub description: Option<String>,
+}
+
+/// The lifecycle of an order, stored in the `status` column of the order table
+#[derive(
+    AsExpression, FromSqlRow, Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash,
+)]
+#[sql_type = "Text"]
+#[serde(rename_all = "camelCase")]
+pub enum OrderStatus {
+    /// Order has been placed but not dispatched yet
+    New,
+    /// Order has been dispatched to the delivery room
+    InTransit,
+    /// Order is waiting at the counter
+    Ready,
+    /// Order has been picked up by the customer
+    PickedUp,
+    /// Order has been cancelled
+    Cancelled,
+}
+
+impl OrderStatus {
+    /// The value as it is stored in the database
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OrderStatus::New => "new",
+            OrderStatus::InTransit => "in_transit",
+            OrderStatus::Ready => "ready",
+            OrderStatus::PickedUp => "picked_up",
+            OrderStatus::Cancelled => "cancelled",
+        }
+    }
+
+    /// Is the order on its way to, or waiting in, the delivery room
+    pub fn is_pending(self) -> bool {
+        matches!(self, OrderStatus::InTransit | OrderStatus::Ready)
+    }
+
+    /// Has the order not been picked up or cancelled yet
+    pub fn is_open(self) -> bool {
+        !matches!(self, OrderStatus::PickedUp | OrderStatus::Cancelled)
+    }
+
+    /// Checks if we are allowed to move from this status to the `next` one,
+    /// an order goes New -> InTransit -> Ready -> PickedUp, where the Ready step may be skipped.
+    /// Open orders can be cancelled, and cancelled or picked up orders can be reopened
+    pub fn can_transition_to(self, next: OrderStatus) -> bool {
+        use OrderStatus::*;
+        matches!(
+            (self, next),
+            (New, InTransit)
+                | (InTransit, Ready)
+                | (InTransit, PickedUp)
+                | (Ready, PickedUp)
+                | (New, Cancelled)
+                | (InTransit, Cancelled)
+                | (Ready, Cancelled)
+                | (Cancelled, New)
+                | (PickedUp, New)
+        )
+    }
+}
+
+impl fmt::Display for OrderStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for OrderStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "new" => Ok(OrderStatus::New),
+            "in_transit" => Ok(OrderStatus::InTransit),
+            "ready" => Ok(OrderStatus::Ready),
+            "picked_up" => Ok(OrderStatus::PickedUp),
+            "cancelled" => Ok(OrderStatus::Cancelled),
+            other => Err(anyhow::anyhow!("Unknown order status '{}'", other)),
+        }
+    }
+}
+
+impl ToSql<Text, Sqlite> for OrderStatus {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Sqlite>) -> serialize::Result {
+        <str as ToSql<Text, Sqlite>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Sqlite> for OrderStatus {
+    fn from_sql(
+        bytes: Option<&<Sqlite as backend::Backend>::RawValue>,
+    ) -> deserialize::Result<Self> {
+        let value = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+        Ok(value.parse()?)
+    }
+}
+
+/// How an order has been paid, stored in the `method` column of the payment table
+#[derive(
+    AsExpression, FromSqlRow, Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash,
+)]
+#[sql_type = "Text"]
+#[serde(rename_all = "camelCase")]
+pub enum PaymentMethod {
+    Cash,
+    BankTransfer,
+}
+
+impl PaymentMethod {
+    /// The value as it is stored in the database
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PaymentMethod::Cash => "cash",
+            PaymentMethod::BankTransfer => "bank_transfer",
+        }
+    }
+}
+
+impl fmt::Display for PaymentMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for PaymentMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cash" => Ok(PaymentMethod::Cash),
+            "bank_transfer" => Ok(PaymentMethod::BankTransfer),
+            other => Err(anyhow::anyhow!("Unknown payment method '{}'", other)),
+        }
+    }
+}
+
+impl ToSql<Text, Sqlite> for PaymentMethod {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Sqlite>) -> serialize::Result {
+        <str as ToSql<Text, Sqlite>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Sqlite> for PaymentMethod {
+    fn from_sql(
+        bytes: Option<&<Sqlite as backend::Backend>::RawValue>,
+    ) -> deserialize::Result<Self> {
+        let value = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+        Ok(value.parse()?)
+    }
 }
 
 #[derive(Associations, Identifiable, Queryable, Copy, Clone)]
@@ -27,9 +203,38 @@ pub struct Vlaai {
 pub struct Order {
     pub id: i32,
     pub customer_id: i32,
-    pub in_transit: bool,
-    pub picked_up: bool,
     pub order_number: Option<i32>,
+    pub status: OrderStatus,
+    /// Created by the importer, importing the form again may change or remove it
+    pub imported: bool,
+}
+
+/// A single status change of an order
+#[derive(Associations, Identifiable, Queryable, Serialize, Clone, Debug, PartialEq)]
+#[belongs_to(Order)]
+#[table_name = "order_event"]
+#[serde(rename_all = "camelCase")]
+pub struct OrderEvent {
+    pub id: i32,
+    pub order_id: i32,
+    pub previous_status: Option<OrderStatus>,
+    pub new_status: OrderStatus,
+    pub operator: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+/// Money received for an order
+#[derive(Associations, Identifiable, Queryable, Serialize, Clone, Debug, PartialEq)]
+#[belongs_to(Order)]
+#[table_name = "payment"]
+#[serde(rename_all = "camelCase")]
+pub struct Payment {
+    pub id: i32,
+    pub order_id: i32,
+    pub amount_cents: i32,
+    pub method: PaymentMethod,
+    pub operator: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
 }
 
 #[derive(Associations, Identifiable, Queryable)]
@@ -46,7 +251,14 @@ pub struct VlaaiToOrder {
 #[table_name = "customer"]
 pub struct NewCustomer<'a> {
     pub name: &'a str,
-    pub email: &'a str,
+    pub email: Option<&'a str>,
+    pub section_id: Option<i32>,
+}
+
+#[derive(Insertable)]
+#[table_name = "section"]
+pub struct NewSection<'a> {
+    pub name: &'a str,
 }
 
 #[derive(Insertable)]
@@ -55,13 +267,48 @@ pub struct NewVlaai<'a> {
     pub name: &'a str,
 }
 
+/// Adds a vlaai to the catalogue or changes one, all fields are replaced
+#[derive(Insertable, AsChangeset, Deserialize, Clone, Debug)]
+#[table_name = "vlaai"]
+#[changeset_options(treat_none_as_null = "true")]
+#[serde(rename_all = "camelCase")]
+pub struct VlaaiChange {
+    pub name: String,
+    pub price_cents: i32,
+    #[serde(default = "active_by_default")]
+    pub active: bool,
+    pub image: Option<String>,
+    pub description: Option<String>,
+}
+
+fn active_by_default() -> bool {
+    true
+}
+
 #[derive(Insertable)]
 #[table_name = "order"]
 pub struct NewOrder {
     pub customer_id: i32,
-    pub in_transit: bool,
-    pub picked_up: bool,
     pub order_number: Option<i32>,
+    pub status: OrderStatus,
+}
+
+#[derive(Insertable)]
+#[table_name = "order_event"]
+pub struct NewOrderEvent<'a> {
+    pub order_id: i32,
+    pub previous_status: Option<OrderStatus>,
+    pub new_status: OrderStatus,
+    pub operator: Option<&'a str>,
+}
+
+#[derive(Insertable)]
+#[table_name = "payment"]
+pub struct NewPayment<'a> {
+    pub order_id: i32,
+    pub amount_cents: i32,
+    pub method: PaymentMethod,
+    pub operator: Option<&'a str>,
 }
 
 #[derive(Insertable)]
@@ -72,6 +319,14 @@ pub struct NewVlaaiToOrder {
     pub amount: i32,
 }
 
+/// A vlaai and how many of them are ordered, when creating or changing an order
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RowChange {
+    pub vlaai_id: i32,
+    pub amount: i32,
+}
+
 #[derive(Clone, Debug, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct OrderRow {
@@ -83,13 +338,343 @@ pub struct OrderRow {
 #[serde(rename_all = "camelCase")]
 pub struct PendingOrder {
     pub id: u32,
+    pub status: OrderStatus,
     pub in_transit: bool,
     pub picked_up: bool,
     pub customer_name: String,
     pub rows: Vec<OrderRow>,
+    /// The price of all vlaaien on the order
+    pub total_cents: i32,
+    pub paid_cents: i32,
+    /// What still has to be paid, negative if too much has been paid
+    pub outstanding_cents: i32,
+}
+
+impl PendingOrder {
+    /// Has the order been paid in full
+    pub fn is_paid(&self) -> bool {
+        self.outstanding_cents <= 0
+    }
+}
+
+/// Number of items on a page, when the request does not say
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+/// The largest page that can be requested
+pub const MAX_PAGE_SIZE: i64 = 200;
+
+/// Part of a list, pass `next_cursor` to get the items after it
+#[derive(Clone, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Missing on the last page
+    pub next_cursor: Option<String>,
+}
+
+/// Which page of a list to get
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PageQuery {
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+/// Position of the last item of a page in the sort order, with the id to break ties
+struct Cursor {
+    id: i32,
+    key: String,
+}
+
+impl Cursor {
+    fn encode(id: i32, key: impl fmt::Display) -> String {
+        format!("{}:{}", id, key)
+    }
+
+    fn decode(cursor: &str) -> Option<Cursor> {
+        let mut parts = cursor.splitn(2, ':');
+        let id = parts.next()?.parse().ok()?;
+        let key = parts.next()?.to_string();
+        Some(Cursor { id, key })
+    }
+}
+
+/// The number of items to load for a page
+pub(crate) fn page_size(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+}
+
+/// Cut the loaded items, one more than the page size, to a page
+fn to_page<T>(mut items: Vec<T>, limit: i64, cursor: impl Fn(&T) -> String) -> Page<T> {
+    let next_cursor = if items.len() as i64 > limit {
+        items.truncate(limit as usize);
+        items.last().map(cursor)
+    } else {
+        None
+    };
+    Page { items, next_cursor }
+}
+
+/// How to sort the orders in a list
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum OrderSort {
+    #[default]
+    Id,
+    /// Orders without an order number come first
+    OrderNumber,
+    Customer,
+}
+
+/// Which orders to list, all filters have to match
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderQuery {
+    pub status: Option<OrderStatus>,
+    pub customer_id: Option<i32>,
+    /// Orders with this vlaai
+    pub vlaai_id: Option<i32>,
+    /// Orders of the customers in this section
+    pub section_id: Option<i32>,
+    pub min_order_number: Option<i32>,
+    pub max_order_number: Option<i32>,
+    #[serde(default)]
+    pub sort: OrderSort,
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
 }
 
+/// Errors that can occur when changing the status of an order
 #[derive(Debug)]
+pub enum StatusError {
+    /// There is no order with this id
+    NotFound(i32),
+    /// The order cannot move from its current status to the requested one
+    InvalidTransition {
+        order_id: i32,
+        from: OrderStatus,
+        to: OrderStatus,
+    },
+    /// The database returned an error
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatusError::NotFound(id) => write!(f, "Order {} does not exist", id),
+            StatusError::InvalidTransition { order_id, from, to } => write!(
+                f,
+                "Order {} cannot go from status '{}' to '{}'",
+                order_id, from, to
+            ),
+            StatusError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StatusError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StatusError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<diesel::result::Error> for StatusError {
+    fn from(e: diesel::result::Error) -> Self {
+        StatusError::Database(e)
+    }
+}
+
+/// Errors that can occur when changing customers
+#[derive(Debug)]
+pub enum CustomerError {
+    /// There is no customer with this id
+    NotFound(i32),
+    /// The name or email is not acceptable
+    Invalid(String),
+    /// Another customer already has this name or email
+    Duplicate(String),
+    /// The customer still has orders that have not been picked up or cancelled
+    OpenOrders { customer_id: i32, orders: usize },
+    /// The customer has orders that have been picked up or cancelled, which are kept for their
+    /// payments and history
+    ClosedOrders { customer_id: i32, orders: usize },
+    /// The database returned an error
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for CustomerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CustomerError::NotFound(id) => write!(f, "Customer {} does not exist", id),
+            CustomerError::Invalid(reason) => f.write_str(reason),
+            CustomerError::Duplicate(reason) => f.write_str(reason),
+            CustomerError::OpenOrders {
+                customer_id,
+                orders,
+            } => write!(
+                f,
+                "Customer {} still has {} open order(s)",
+                customer_id, orders
+            ),
+            CustomerError::ClosedOrders {
+                customer_id,
+                orders,
+            } => write!(
+                f,
+                "Customer {} has {} order(s) that have been picked up or cancelled",
+                customer_id, orders
+            ),
+            CustomerError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CustomerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CustomerError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<diesel::result::Error> for CustomerError {
+    fn from(e: diesel::result::Error) -> Self {
+        CustomerError::Database(e)
+    }
+}
+
+/// Errors that can occur when creating, changing or deleting orders
+#[derive(Debug)]
+pub enum OrderError {
+    /// There is no order with this id
+    NotFound(i32),
+    /// There is no customer with this id
+    CustomerNotFound(i32),
+    /// There is no vlaai with this id
+    VlaaiNotFound(i32),
+    /// The rows of the order are not acceptable
+    Invalid(String),
+    /// The order has been picked up or cancelled and cannot be changed anymore
+    Closed { order_id: i32, status: OrderStatus },
+    /// The database returned an error
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for OrderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderError::NotFound(id) => write!(f, "Order {} does not exist", id),
+            OrderError::CustomerNotFound(id) => write!(f, "Customer {} does not exist", id),
+            OrderError::VlaaiNotFound(id) => write!(f, "Vlaai {} does not exist", id),
+            OrderError::Invalid(reason) => f.write_str(reason),
+            OrderError::Closed { order_id, status } => write!(
+                f,
+                "Order {} has status '{}' and cannot be changed",
+                order_id, status
+            ),
+            OrderError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OrderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OrderError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<diesel::result::Error> for OrderError {
+    fn from(e: diesel::result::Error) -> Self {
+        OrderError::Database(e)
+    }
+}
+
+/// Errors that can occur when changing the vlaai catalogue
+#[derive(Debug)]
+pub enum CatalogueError {
+    /// There is no vlaai with this id
+    NotFound(i32),
+    /// The name or price is not acceptable
+    Invalid(String),
+    /// Another vlaai already has this name
+    Duplicate(String),
+    /// The database returned an error
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for CatalogueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CatalogueError::NotFound(id) => write!(f, "Vlaai {} does not exist", id),
+            CatalogueError::Invalid(reason) => f.write_str(reason),
+            CatalogueError::Duplicate(reason) => f.write_str(reason),
+            CatalogueError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CatalogueError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CatalogueError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<diesel::result::Error> for CatalogueError {
+    fn from(e: diesel::result::Error) -> Self {
+        CatalogueError::Database(e)
+    }
+}
+
+/// Errors that can occur when adding a section
+#[derive(Debug)]
+pub enum SectionError {
+    /// There is no section with this id
+    NotFound(i32),
+    /// The name is not acceptable
+    Invalid(String),
+    /// Another section already has this name
+    Duplicate(String),
+    /// The database returned an error
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for SectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SectionError::NotFound(id) => write!(f, "Section {} does not exist", id),
+            SectionError::Invalid(reason) => f.write_str(reason),
+            SectionError::Duplicate(reason) => f.write_str(reason),
+            SectionError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SectionError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<diesel::result::Error> for SectionError {
+    fn from(e: diesel::result::Error) -> Self {
+        SectionError::Database(e)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct ConnectionOptions {
     pub enable_foreign_keys: bool,
     pub busy_timeout: Option<std::time::Duration>,
@@ -97,6 +682,9 @@ pub struct ConnectionOptions {
 
 impl ConnectionOptions {
     pub fn apply(&self, conn: &SqliteConnection) -> QueryResult<()> {
+        normalize::register_impl(conn, |text: Option<String>| {
+            text.map(|text| crate::search::normalize(&text))
+        })?;
         if self.enable_foreign_keys {
             conn.batch_execute("PRAGMA foreign_keys = ON;")?;
         }
@@ -125,38 +713,75 @@ impl diesel::r2d2::CustomizeConnection<SqliteConnection, diesel::r2d2::Error>
     }
 }
 
-/// Get the database url depending if we are in production or development
-fn get_database_url() -> String {
-    if dotenv::var("MODE").unwrap() == "dev" {
-        dotenv::var("DATABASE_URL").expect("DATABASE_URL must be set")
-    } else {
-        dotenv::var("DATABASE_URL_PROD").expect("DATABASE_URL_PROD must be set")
-    }
-}
-
 /// This is a connction pool
+pub type ConnectionPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// A connection taken from the connection pool
 pub type PooledConnection =
     diesel::r2d2::PooledConnection<diesel::r2d2::ConnectionManager<diesel::SqliteConnection>>;
 
-// Create the pool singleton here
-lazy_static! {
-    /// Create pool singleton
-    static ref POOL: Pool<diesel::r2d2::ConnectionManager<SqliteConnection>> = {
-        let database_url = get_database_url();
-        Pool::builder()
-            .connection_customizer(Box::new(ConnectionOptions::default()))
-            .build(ConnectionManager::<SqliteConnection>::new(database_url))
-            .unwrap()
-    };
+embed_migrations!();
+
+/// Bring the database up to date with the migrations in the `migrations` directory
+pub fn run_migrations(conn: &SqliteConnection) -> anyhow::Result<()> {
+    embedded_migrations::run(conn)?;
+    Ok(())
+}
+
+/// Errors that can occur when opening the database
+#[derive(Debug)]
+pub enum DatabaseError {
+    /// The pool could not create a connection to the database
+    Pool(diesel::r2d2::PoolError),
 }
 
-/// Create a connection from the connection pool
-pub fn establish_connection(is_test: bool) -> PooledConnection {
-    dotenv::dotenv().ok();
-    if is_test {
-        std::env::set_var("MODE", "dev")
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseError::Pool(e) => write!(f, "Could not connect to the database: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DatabaseError::Pool(e) => Some(e),
+        }
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for DatabaseError {
+    fn from(e: diesel::r2d2::PoolError) -> Self {
+        DatabaseError::Pool(e)
+    }
+}
+
+/// Handle to a database, holds a pool of connections that all have the same options applied.
+/// Cloning is cheap and gives a handle to the same pool
+#[derive(Clone)]
+pub struct Database {
+    pool: ConnectionPool,
+}
+
+impl Database {
+    /// Open a connection pool to the database at `database_url`
+    pub fn open(database_url: &str, options: ConnectionOptions) -> Result<Database, DatabaseError> {
+        let pool = Pool::builder()
+            .connection_customizer(Box::new(options))
+            .build(ConnectionManager::<SqliteConnection>::new(database_url))?;
+        Ok(Database { pool })
+    }
+
+    /// Open the database from the configuration
+    pub fn from_config(config: &Config) -> Result<Database, DatabaseError> {
+        Database::open(&config.database_url, config.connection_options())
+    }
+
+    /// Get a connection from the pool
+    pub fn conn(&self) -> Result<PooledConnection, DatabaseError> {
+        Ok(self.pool.get()?)
     }
-    POOL.get().expect("Could not get connection")
 }
 
 /// Get the name of a vlaai for a specific id
@@ -168,122 +793,1073 @@ pub fn get_vlaai_name(conn: &SqliteConnection, vlaai_id: i32) -> anyhow::Result<
     Ok(vlaai_name)
 }
 
-/// Retrieve all pending orders
-pub fn all_pending_orders(conn: &SqliteConnection) -> anyhow::Result<Vec<PendingOrder>> {
-    // Get all orders in transit
-    let orders: Vec<Order> = order::table
-        .filter(order::in_transit.eq(true).and(order::picked_up.eq(false)))
-        .order_by(order::order_number)
-        .load(conn)?;
+/// An order joined with its customer name, the amount paid and one of its rows with the
+/// price of the vlaai, if it has any
+type PendingRow = (Order, String, i32, Option<(String, i32, i32)>);
 
-    let mut pending_orders = Vec::new();
-    // Map onto customers and vlaaien
-    for order in orders {
-        // Find customer
-        let customer: Customer = customer::table.find(order.id).get_result(conn)?;
-        // Find vlaaien
-        let order_rows = vlaai_to_order::table
-            .filter(vlaai_to_order::order_id.eq(order.id))
-            .load::<VlaaiToOrder>(conn)?;
-
-        // Create the pending order
-        let mut pending_order = PendingOrder {
-            id: order.id as u32,
-            picked_up: order.picked_up,
-            in_transit: order.in_transit,
-            customer_name: customer.name,
-            rows: Default::default(),
-        };
+/// The amount paid for the order in the query
+fn paid_cents() -> diesel::expression::SqlLiteral<diesel::sql_types::Integer> {
+    diesel::dsl::sql(
+        "(SELECT COALESCE(SUM(payment.amount_cents), 0) FROM payment \
+         WHERE payment.order_id = `order`.`id`)",
+    )
+}
+
+/// Group the joined rows into pending orders, the rows of an order should be consecutive
+fn group_pending(rows: Vec<PendingRow>) -> Vec<PendingOrder> {
+    let mut pending_orders: Vec<PendingOrder> = Vec::new();
+    for (order, customer_name, paid_cents, row) in rows {
+        let is_same_order = pending_orders
+            .last()
+            .map(|p| p.id == order.id as u32)
+            .unwrap_or(false);
+        if !is_same_order {
+            pending_orders.push(PendingOrder {
+                id: order.id as u32,
+                status: order.status,
+                picked_up: order.status == OrderStatus::PickedUp,
+                in_transit: order.status.is_pending(),
+                customer_name,
+                rows: Default::default(),
+                total_cents: 0,
+                paid_cents,
+                outstanding_cents: -paid_cents,
+            });
+        }
 
-        // Fill in the order rows
-        for order_row in order_rows {
+        if let (Some(pending_order), Some((vlaai, amount, price_cents))) =
+            (pending_orders.last_mut(), row)
+        {
             pending_order.rows.push(OrderRow {
-                vlaai: get_vlaai_name(conn, order_row.vlaai_id)?,
-                amount: order_row.amount as u32,
+                vlaai,
+                amount: amount as u32,
             });
+            pending_order.total_cents += amount * price_cents;
+            pending_order.outstanding_cents += amount * price_cents;
         }
-        pending_orders.push(pending_order);
     }
-
-    Ok(pending_orders)
-}
-
-/// Convert an existing order to a pending one
-pub fn to_pending(conn: &SqliteConnection, order: Order) -> anyhow::Result<PendingOrder> {
-    let customer = customer::table
-        .find(order.customer_id)
-        .get_result::<Customer>(conn)?;
-
-    let order_rows = vlaai_to_order::table
-        .filter(vlaai_to_order::order_id.eq(order.id))
-        .load::<VlaaiToOrder>(conn)?
-        .into_iter()
-        .map(|v| {
-            get_vlaai_name(conn, v.vlaai_id).map(|name| OrderRow {
-                vlaai: name,
-                amount: v.amount as u32,
-            })
-        })
-        .collect::<Result<Vec<_>, _>>();
-
-    Ok(PendingOrder {
-        id: order.id as u32,
-        picked_up: order.picked_up,
-        in_transit: order.in_transit,
-        customer_name: customer.name,
-        rows: order_rows?,
-    })
+    pending_orders
 }
 
-#[allow(dead_code)]
-pub fn customer_with_name<T: AsRef<str>>(
+/// The orders with these ids, in the same order as the ids
+pub(crate) fn pending_orders_by_id(
     conn: &SqliteConnection,
-    name: T,
-) -> anyhow::Result<Vec<Customer>> {
-    Ok(customer::table
-        .filter(customer::name.like(name.as_ref()))
-        .load(conn)?)
-}
+    ids: &[i32],
+) -> QueryResult<Vec<PendingOrder>> {
+    let rows: Vec<PendingRow> = order::table
+        .inner_join(customer::table)
+        .left_join(vlaai_to_order::table.inner_join(vlaai::table))
+        .filter(order::id.eq_any(ids))
+        .select((
+            order::all_columns,
+            customer::name,
+            paid_cents(),
+            (vlaai::name, vlaai_to_order::amount, vlaai::price_cents).nullable(),
+        ))
+        .order_by((order::id, vlaai_to_order::id))
+        .load(conn)?;
 
-pub fn orders_for_customer(conn: &SqliteConnection, id: i32) -> anyhow::Result<Vec<Order>> {
-    // Find the customer
-    let customer: Customer = customer::table.find(id).get_result(conn)?;
-    Ok(Order::belonging_to(&customer).load(conn)?)
+    let mut orders = group_pending(rows);
+    orders.sort_by_key(|o| ids.iter().position(|&id| id == o.id as i32));
+    Ok(orders)
 }
 
-pub fn update_order_in_transit(
+/// A page of the orders that match the query
+pub fn list_orders(
     conn: &SqliteConnection,
-    order_id: i32,
-    order_number: i32,
-) -> anyhow::Result<Order> {
-    diesel::update(order::table.find(order_id))
-        .set((
-            order::in_transit.eq(true),
-            order::picked_up.eq(false),
-            order::order_number.eq(order_number),
+    query: &OrderQuery,
+) -> Result<Page<PendingOrder>, OrderError> {
+    let limit = page_size(query.limit);
+    let cursor = match &query.cursor {
+        Some(cursor) => Some(
+            Cursor::decode(cursor)
+                .ok_or_else(|| OrderError::Invalid(format!("Invalid cursor '{}'", cursor)))?,
+        ),
+        None => None,
+    };
+    let order_number = || ifnull(order::order_number, -1);
+
+    let mut keys = order::table
+        .inner_join(customer::table)
+        .select((order::id, customer::name, order_number()))
+        .into_boxed();
+    if let Some(status) = query.status {
+        keys = keys.filter(order::status.eq(status));
+    }
+    if let Some(customer_id) = query.customer_id {
+        keys = keys.filter(order::customer_id.eq(customer_id));
+    }
+    if let Some(section_id) = query.section_id {
+        keys = keys.filter(customer::section_id.eq(section_id));
+    }
+    if let Some(vlaai_id) = query.vlaai_id {
+        keys = keys.filter(
+            order::id.eq_any(
+                vlaai_to_order::table
+                    .filter(vlaai_to_order::vlaai_id.eq(vlaai_id))
+                    .select(vlaai_to_order::order_id),
+            ),
+        );
+    }
+    if let Some(min) = query.min_order_number {
+        keys = keys.filter(order::order_number.ge(min));
+    }
+    if let Some(max) = query.max_order_number {
+        keys = keys.filter(order::order_number.le(max));
+    }
+
+    // Continue after the cursor in the sort order
+    keys = match query.sort {
+        OrderSort::Id => {
+            if let Some(cursor) = &cursor {
+                keys = keys.filter(order::id.gt(cursor.id));
+            }
+            keys.order_by(order::id)
+        }
+        OrderSort::OrderNumber => {
+            if let Some(cursor) = &cursor {
+                let number: i32 = cursor.key.parse().map_err(|_| {
+                    OrderError::Invalid(format!("Invalid order number '{}'", cursor.key))
+                })?;
+                keys = keys.filter(
+                    order_number()
+                        .gt(number)
+                        .or(order_number().eq(number).and(order::id.gt(cursor.id))),
+                );
+            }
+            keys.order_by((order_number(), order::id))
+        }
+        OrderSort::Customer => {
+            if let Some(cursor) = &cursor {
+                keys = keys.filter(
+                    customer::name
+                        .gt(&cursor.key)
+                        .or(customer::name.eq(&cursor.key).and(order::id.gt(cursor.id))),
+                );
+            }
+            keys.order_by((customer::name, order::id))
+        }
+    };
+
+    let keys: Vec<(i32, String, i32)> = keys.limit(limit + 1).load(conn)?;
+    let sort = query.sort;
+    let page = to_page(keys, limit, |(id, name, number)| match sort {
+        OrderSort::Id => Cursor::encode(*id, ""),
+        OrderSort::OrderNumber => Cursor::encode(*id, number),
+        OrderSort::Customer => Cursor::encode(*id, name),
+    });
+
+    let ids: Vec<i32> = page.items.iter().map(|(id, ..)| *id).collect();
+    Ok(Page {
+        items: pending_orders_by_id(conn, &ids)?,
+        next_cursor: page.next_cursor,
+    })
+}
+
+/// The ids of the orders with this order number
+pub fn order_ids_with_number(conn: &SqliteConnection, number: i32) -> QueryResult<Vec<i32>> {
+    order::table
+        .filter(order::order_number.eq(number))
+        .select(order::id)
+        .order_by(order::id)
+        .load(conn)
+}
+
+/// The ids of the orders of these customers, each with the id of its customer
+pub fn order_ids_of_customers(
+    conn: &SqliteConnection,
+    customer_ids: &[i32],
+) -> QueryResult<Vec<(i32, i32)>> {
+    order::table
+        .filter(order::customer_id.eq_any(customer_ids))
+        .select((order::id, order::customer_id))
+        .order_by(order::id)
+        .load(conn)
+}
+
+/// The rows of all orders with these vlaaien, as order id, vlaai id and amount
+pub fn rows_with_vlaaien(
+    conn: &SqliteConnection,
+    vlaai_ids: &[i32],
+) -> QueryResult<Vec<(i32, i32, i32)>> {
+    vlaai_to_order::table
+        .filter(vlaai_to_order::vlaai_id.eq_any(vlaai_ids))
+        .select((
+            vlaai_to_order::order_id,
+            vlaai_to_order::vlaai_id,
+            vlaai_to_order::amount,
         ))
-        .execute(conn)?;
-    Ok(order::table.find(order_id).get_result(conn)?)
+        .load(conn)
 }
 
-pub fn update_order_retrieved(conn: &SqliteConnection, order_id: i32) -> anyhow::Result<usize> {
-    Ok(diesel::update(order::table.find(order_id))
-        .set((
-            order::in_transit.eq(false),
-            order::picked_up.eq(true),
-            order::order_number.eq::<Option<i32>>(None),
+/// Retrieve all pending orders
+pub fn all_pending_orders<C>(conn: &C) -> anyhow::Result<Vec<PendingOrder>>
+where
+    C: Connection<Backend = Sqlite>,
+{
+    // Get all orders in transit with their customer and vlaaien in one go
+    let rows: Vec<PendingRow> = order::table
+        .inner_join(customer::table)
+        .left_join(vlaai_to_order::table.inner_join(vlaai::table))
+        .filter(order::status.eq_any(vec![OrderStatus::InTransit, OrderStatus::Ready]))
+        .select((
+            order::all_columns,
+            customer::name,
+            paid_cents(),
+            (vlaai::name, vlaai_to_order::amount, vlaai::price_cents).nullable(),
         ))
-        .execute(conn)?)
+        .order_by((order::order_number, order::id, vlaai_to_order::id))
+        .load(conn)?;
+
+    Ok(group_pending(rows))
+}
+
+/// Convert an existing order to a pending one
+pub fn to_pending<C>(conn: &C, order: Order) -> anyhow::Result<PendingOrder>
+where
+    C: Connection<Backend = Sqlite>,
+{
+    let rows: Vec<PendingRow> = order::table
+        .inner_join(customer::table)
+        .left_join(vlaai_to_order::table.inner_join(vlaai::table))
+        .filter(order::id.eq(order.id))
+        .select((
+            order::all_columns,
+            customer::name,
+            paid_cents(),
+            (vlaai::name, vlaai_to_order::amount, vlaai::price_cents).nullable(),
+        ))
+        .order_by(vlaai_to_order::id)
+        .load(conn)?;
+
+    group_pending(rows)
+        .pop()
+        .ok_or_else(|| anyhow::anyhow!("Order {} does not exist", order.id))
+}
+
+/// The order with this id as it is shown on the screen
+pub fn pending_order(conn: &SqliteConnection, order_id: i32) -> anyhow::Result<PendingOrder> {
+    let order: Order = order::table.find(order_id).get_result(conn)?;
+    to_pending(conn, order)
+}
+
+/// The customers with `name` in their name, the case of ASCII letters is ignored
+pub fn customer_with_name<T: AsRef<str>>(
+    conn: &SqliteConnection,
+    name: T,
+) -> anyhow::Result<Vec<Customer>> {
+    let pattern = format!("%{}%", escape_like(name.as_ref()));
+    Ok(customer::table
+        .filter(customer::name.like(pattern).escape('\\'))
+        .load(conn)?)
+}
+
+pub fn orders_for_customer(conn: &SqliteConnection, id: i32) -> anyhow::Result<Vec<Order>> {
+    // Find the customer
+    let customer: Customer = customer::table.find(id).get_result(conn)?;
+    Ok(Order::belonging_to(&customer).load(conn)?)
+}
+
+/// Move an order to a new status, the order number is determined from the current order.
+/// The change is recorded in the order history in the same transaction
+fn transition_order<F: FnOnce(&Order) -> Option<i32>>(
+    conn: &SqliteConnection,
+    order_id: i32,
+    next: OrderStatus,
+    operator: Option<&str>,
+    order_number: F,
+) -> Result<Order, StatusError> {
+    conn.transaction(|| {
+        let order: Order = order::table
+            .find(order_id)
+            .get_result(conn)
+            .optional()?
+            .ok_or(StatusError::NotFound(order_id))?;
+
+        if !order.status.can_transition_to(next) {
+            return Err(StatusError::InvalidTransition {
+                order_id,
+                from: order.status,
+                to: next,
+            });
+        }
+
+        diesel::update(order::table.find(order_id))
+            .set((
+                order::status.eq(next),
+                order::order_number.eq(order_number(&order)),
+            ))
+            .execute(conn)?;
+        diesel::insert_into(order_event::table)
+            .values(NewOrderEvent {
+                order_id,
+                previous_status: Some(order.status),
+                new_status: next,
+                operator,
+            })
+            .execute(conn)?;
+        Ok(order::table.find(order_id).get_result(conn)?)
+    })
+}
+
+/// Dispatch an order to the delivery room with the given order number
+pub fn update_order_in_transit(
+    conn: &SqliteConnection,
+    order_id: i32,
+    order_number: i32,
+    operator: Option<&str>,
+) -> Result<Order, StatusError> {
+    transition_order(conn, order_id, OrderStatus::InTransit, operator, |_| {
+        Some(order_number)
+    })
+}
+
+/// The order is waiting at the counter, it keeps its order number
+pub fn update_order_ready(
+    conn: &SqliteConnection,
+    order_id: i32,
+    operator: Option<&str>,
+) -> Result<Order, StatusError> {
+    transition_order(conn, order_id, OrderStatus::Ready, operator, |order| {
+        order.order_number
+    })
+}
+
+/// The order has been picked up by the customer
+pub fn update_order_retrieved(
+    conn: &SqliteConnection,
+    order_id: i32,
+    operator: Option<&str>,
+) -> Result<Order, StatusError> {
+    transition_order(conn, order_id, OrderStatus::PickedUp, operator, |_| None)
+}
+
+/// Reopen a cancelled or picked up order
+pub fn update_order_new(
+    conn: &SqliteConnection,
+    order_id: i32,
+    operator: Option<&str>,
+) -> Result<Order, StatusError> {
+    transition_order(conn, order_id, OrderStatus::New, operator, |_| None)
+}
+
+/// Cancel an order that has not been picked up yet
+pub fn cancel_order(
+    conn: &SqliteConnection,
+    order_id: i32,
+    operator: Option<&str>,
+) -> Result<Order, StatusError> {
+    transition_order(conn, order_id, OrderStatus::Cancelled, operator, |_| None)
+}
+
+/// All status changes of an order, oldest first
+pub fn order_history(conn: &SqliteConnection, order_id: i32) -> anyhow::Result<Vec<OrderEvent>> {
+    let order: Order = order::table.find(order_id).get_result(conn)?;
+    Ok(OrderEvent::belonging_to(&order)
+        .order_by(order_event::id)
+        .load(conn)?)
+}
+
+sql_function!(
+    /// [`crate::search::normalize`] in SQL, it is registered on every connection by
+    /// [`ConnectionOptions`]
+    fn normalize(x: Nullable<Text>) -> Nullable<Text>
+);
+sql_function!(fn lower(x: Text) -> Text);
+sql_function!(fn ifnull(x: Nullable<Integer>, y: Integer) -> Integer);
+no_arg_sql_function!(
+    last_insert_rowid,
+    diesel::sql_types::Integer,
+    "The rowid of the last inserted row"
+);
+
+/// All customers, sorted by name
+pub fn all_customers(conn: &SqliteConnection) -> anyhow::Result<Vec<Customer>> {
+    Ok(customer::table
+        .order_by((lower(customer::name), customer::id))
+        .load(conn)?)
+}
+
+/// The customers that might match a search. Every group of `parts` needs one part that is in
+/// the name of the customer, or in the email or section name when `fields` says so. The texts
+/// are compared after [`crate::search::normalize`], so the parts should be normalized as well
+pub fn customer_candidates(
+    conn: &SqliteConnection,
+    parts: &[Vec<String>],
+    fields: SearchFields,
+) -> QueryResult<Vec<Customer>> {
+    let mut query = customer::table.order_by(customer::id).into_boxed();
+    for group in parts {
+        let mut any: Box<dyn BoxableExpression<customer::table, Sqlite, SqlType = Bool>> =
+            Box::new(false.into_sql::<Bool>());
+        for part in group {
+            let pattern = format!("%{}%", escape_like(part));
+            let name = normalize(customer::name.nullable()).like(pattern.clone());
+            any = Box::new(any.or(name.escape('\\')));
+            if fields.email {
+                let email = normalize(customer::email).like(pattern.clone());
+                any = Box::new(any.or(email.escape('\\')));
+            }
+            if fields.speltak {
+                let section = normalize(section::name.nullable()).like(pattern);
+                let sections = section::table
+                    .filter(section.escape('\\'))
+                    .select(section::id.nullable());
+                any = Box::new(any.or(customer::section_id.eq_any(sections)));
+            }
+        }
+        query = query.filter(any);
+    }
+    query.load(conn)
+}
+
+/// The customer with this id
+pub fn get_customer(conn: &SqliteConnection, customer_id: i32) -> Result<Customer, CustomerError> {
+    customer::table
+        .find(customer_id)
+        .get_result(conn)
+        .optional()?
+        .ok_or(CustomerError::NotFound(customer_id))
+}
+
+/// Whether `email` looks like an email address: something before the `@` and a domain with a dot
+pub fn is_email_address(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && !email.contains(char::is_whitespace)
+                && domain.split('.').count() > 1
+                && domain.split('.').all(|part| !part.is_empty())
+        }
+        None => false,
+    }
+}
+
+/// Trim the fields of a customer and check that they are valid and not used by a customer
+/// other than `existing`
+fn check_customer<'a>(
+    conn: &SqliteConnection,
+    customer: NewCustomer<'a>,
+    existing: Option<i32>,
+) -> Result<NewCustomer<'a>, CustomerError> {
+    let name = customer.name.trim();
+    if name.is_empty() {
+        return Err(CustomerError::Invalid(
+            "The name of a customer cannot be empty".to_string(),
+        ));
+    }
+    let email = customer.email.map(str::trim).filter(|e| !e.is_empty());
+    if let Some(email) = email {
+        if !is_email_address(email) {
+            return Err(CustomerError::Invalid(format!(
+                "'{}' is not an email address",
+                email
+            )));
+        }
+    }
+
+    if let Some(section_id) = customer.section_id {
+        let exists: Option<i32> = section::table
+            .find(section_id)
+            .select(section::id)
+            .get_result(conn)
+            .optional()?;
+        if exists.is_none() {
+            return Err(CustomerError::Invalid(format!(
+                "Section {} does not exist",
+                section_id
+            )));
+        }
+    }
+
+    // SQLite's lower() only knows ASCII, so the other customers are compared here
+    let others: Vec<Customer> = customer::table
+        .filter(customer::id.ne(existing.unwrap_or(-1)))
+        .load(conn)?;
+    // Customers in different sections may have the same name
+    let same_name = others.iter().find(|other| {
+        other.name.to_lowercase() == name.to_lowercase() && other.section_id == customer.section_id
+    });
+    if let Some(other) = same_name {
+        return Err(CustomerError::Duplicate(format!(
+            "Customer {} is already called '{}'",
+            other.id, name
+        )));
+    }
+    if let Some(email) = email {
+        let same_email = others.iter().find(|other| {
+            other
+                .email
+                .as_deref()
+                .is_some_and(|other| other.to_lowercase() == email.to_lowercase())
+        });
+        if let Some(other) = same_email {
+            return Err(CustomerError::Duplicate(format!(
+                "Customer {} already uses '{}'",
+                other.id, email
+            )));
+        }
+    }
+    Ok(NewCustomer {
+        name,
+        email,
+        section_id: customer.section_id,
+    })
+}
+
+/// Add a customer, names and emails must be unique regardless of case
+pub fn create_customer(
+    conn: &SqliteConnection,
+    customer: NewCustomer,
+) -> Result<Customer, CustomerError> {
+    conn.transaction(|| {
+        let customer = check_customer(conn, customer, None)?;
+        diesel::insert_into(customer::table)
+            .values(customer)
+            .execute(conn)?;
+        let customer_id: i32 = diesel::select(last_insert_rowid).get_result(conn)?;
+        Ok(customer::table.find(customer_id).get_result(conn)?)
+    })
+}
+
+/// Change the name, email and section of a customer
+pub fn update_customer(
+    conn: &SqliteConnection,
+    customer_id: i32,
+    customer: NewCustomer,
+) -> Result<Customer, CustomerError> {
+    conn.transaction(|| {
+        get_customer(conn, customer_id)?;
+        let customer = check_customer(conn, customer, Some(customer_id))?;
+        diesel::update(customer::table.find(customer_id))
+            .set((
+                customer::name.eq(customer.name),
+                customer::email.eq(customer.email),
+                customer::section_id.eq(customer.section_id),
+            ))
+            .execute(conn)?;
+        get_customer(conn, customer_id)
+    })
+}
+
+/// Remove a customer without orders. Open orders have to be removed first, orders that have
+/// been picked up or cancelled are kept for their payments and history
+pub fn delete_customer(conn: &SqliteConnection, customer_id: i32) -> Result<(), CustomerError> {
+    conn.transaction(|| {
+        let customer = get_customer(conn, customer_id)?;
+        let orders: Vec<Order> = Order::belonging_to(&customer).load(conn)?;
+        let open = orders.iter().filter(|o| o.status.is_open()).count();
+        if open > 0 {
+            return Err(CustomerError::OpenOrders {
+                customer_id,
+                orders: open,
+            });
+        }
+        if !orders.is_empty() {
+            return Err(CustomerError::ClosedOrders {
+                customer_id,
+                orders: orders.len(),
+            });
+        }
+
+        diesel::delete(customer::table.find(customer_id)).execute(conn)?;
+        Ok(())
+    })
+}
+
+/// The vlaaien in the catalogue, only the ones that are sold if `only_active` is set
+pub fn all_vlaaien(conn: &SqliteConnection, only_active: bool) -> anyhow::Result<Vec<Vlaai>> {
+    let mut query = vlaai::table.order_by(vlaai::id).into_boxed();
+    if only_active {
+        query = query.filter(vlaai::active.eq(true));
+    }
+    Ok(query.load(conn)?)
+}
+
+/// The vlaai with this id
+pub fn get_vlaai(conn: &SqliteConnection, vlaai_id: i32) -> Result<Vlaai, CatalogueError> {
+    vlaai::table
+        .find(vlaai_id)
+        .get_result(conn)
+        .optional()?
+        .ok_or(CatalogueError::NotFound(vlaai_id))
+}
+
+/// Trim the name of a vlaai and check that it is valid and not used by a vlaai other
+/// than `existing`
+fn check_vlaai(
+    conn: &SqliteConnection,
+    change: &VlaaiChange,
+    existing: Option<i32>,
+) -> Result<VlaaiChange, CatalogueError> {
+    let name = change.name.trim();
+    if name.is_empty() {
+        return Err(CatalogueError::Invalid(
+            "The name of a vlaai cannot be empty".to_string(),
+        ));
+    }
+    if change.price_cents < 0 {
+        return Err(CatalogueError::Invalid(format!(
+            "The price of {} cannot be negative",
+            name
+        )));
+    }
+    // SQLite's lower() only knows ASCII, so the names are compared here
+    let others: Vec<(i32, String)> = vlaai::table
+        .filter(vlaai::id.ne(existing.unwrap_or(-1)))
+        .select((vlaai::id, vlaai::name))
+        .load(conn)?;
+    let same_name = others
+        .into_iter()
+        .find(|(_, other)| other.to_lowercase() == name.to_lowercase());
+    if let Some((id, _)) = same_name {
+        return Err(CatalogueError::Duplicate(format!(
+            "Vlaai {} is already called '{}'",
+            id, name
+        )));
+    }
+    let optional = |text: &Option<String>| {
+        text.as_deref()
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(str::to_string)
+    };
+    Ok(VlaaiChange {
+        name: name.to_string(),
+        image: optional(&change.image),
+        description: optional(&change.description),
+        ..change.clone()
+    })
+}
+
+/// Add a vlaai to the catalogue
+pub fn create_vlaai(
+    conn: &SqliteConnection,
+    change: &VlaaiChange,
+) -> Result<Vlaai, CatalogueError> {
+    conn.transaction(|| {
+        let change = check_vlaai(conn, change, None)?;
+        diesel::insert_into(vlaai::table)
+            .values(&change)
+            .execute(conn)?;
+        let vlaai_id: i32 = diesel::select(last_insert_rowid).get_result(conn)?;
+        get_vlaai(conn, vlaai_id)
+    })
+}
+
+/// Change a vlaai in the catalogue, orders keep referring to it by id
+pub fn update_vlaai(
+    conn: &SqliteConnection,
+    vlaai_id: i32,
+    change: &VlaaiChange,
+) -> Result<Vlaai, CatalogueError> {
+    conn.transaction(|| {
+        get_vlaai(conn, vlaai_id)?;
+        let change = check_vlaai(conn, change, Some(vlaai_id))?;
+        diesel::update(vlaai::table.find(vlaai_id))
+            .set(&change)
+            .execute(conn)?;
+        get_vlaai(conn, vlaai_id)
+    })
+}
+
+/// All sections, sorted by name
+pub fn all_sections(conn: &SqliteConnection) -> anyhow::Result<Vec<Section>> {
+    Ok(section::table.order_by(lower(section::name)).load(conn)?)
+}
+
+/// The section with this id
+pub fn get_section(conn: &SqliteConnection, section_id: i32) -> Result<Section, SectionError> {
+    section::table
+        .find(section_id)
+        .get_result(conn)
+        .optional()?
+        .ok_or(SectionError::NotFound(section_id))
+}
+
+/// The section with this name, regardless of case
+pub fn section_with_name(conn: &SqliteConnection, name: &str) -> QueryResult<Option<Section>> {
+    // SQLite's lower() only knows ASCII, so the names are compared here
+    let name = name.trim().to_lowercase();
+    let sections: Vec<Section> = section::table.load(conn)?;
+    Ok(sections
+        .into_iter()
+        .find(|section| section.name.to_lowercase() == name))
+}
+
+/// Add a section, names must be unique regardless of case
+pub fn create_section(conn: &SqliteConnection, name: &str) -> Result<Section, SectionError> {
+    conn.transaction(|| {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(SectionError::Invalid(
+                "The name of a section cannot be empty".to_string(),
+            ));
+        }
+        if let Some(section) = section_with_name(conn, name)? {
+            return Err(SectionError::Duplicate(format!(
+                "Section {} is already called '{}'",
+                section.id, section.name
+            )));
+        }
+        diesel::insert_into(section::table)
+            .values(NewSection { name })
+            .execute(conn)?;
+        let section_id: i32 = diesel::select(last_insert_rowid).get_result(conn)?;
+        get_section(conn, section_id)
+    })
+}
+
+/// The customers in a section, sorted by name
+pub fn customers_in_section(
+    conn: &SqliteConnection,
+    section_id: i32,
+) -> anyhow::Result<Vec<Customer>> {
+    Ok(customer::table
+        .filter(customer::section_id.eq(section_id))
+        .order_by(lower(customer::name))
+        .load(conn)?)
+}
+
+/// How many of a vlaai are ordered
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VlaaiCount {
+    pub vlaai_id: i32,
+    pub name: String,
+    pub amount: i64,
+}
+
+/// What the customers of a section ordered, cancelled orders are left out
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SectionReport {
+    /// `None` for the customers without a section
+    pub section: Option<Section>,
+    pub customers: usize,
+    pub orders: usize,
+    /// Sorted by vlaai id
+    pub vlaaien: Vec<VlaaiCount>,
+    pub total_cents: i64,
+    pub paid_cents: i64,
+}
+
+/// The orders per section, sorted by the name of the section. Customers without a section are
+/// reported last, if there are any
+pub fn section_report(conn: &SqliteConnection) -> anyhow::Result<Vec<SectionReport>> {
+    let customers: Vec<Option<i32>> = customer::table.select(customer::section_id).load(conn)?;
+    let orders: Vec<Option<i32>> = order::table
+        .inner_join(customer::table)
+        .filter(order::status.ne(OrderStatus::Cancelled))
+        .select(customer::section_id)
+        .load(conn)?;
+    let rows: Vec<(Option<i32>, i32, String, i32, i32)> = vlaai_to_order::table
+        .inner_join(order::table.inner_join(customer::table))
+        .inner_join(vlaai::table)
+        .filter(order::status.ne(OrderStatus::Cancelled))
+        .order_by(vlaai::id)
+        .select((
+            customer::section_id,
+            vlaai::id,
+            vlaai::name,
+            vlaai::price_cents,
+            vlaai_to_order::amount,
+        ))
+        .load(conn)?;
+    let payments: Vec<(Option<i32>, i32)> = payment::table
+        .inner_join(order::table.inner_join(customer::table))
+        .filter(order::status.ne(OrderStatus::Cancelled))
+        .select((customer::section_id, payment::amount_cents))
+        .load(conn)?;
+
+    let mut sections: Vec<Option<Section>> = all_sections(conn)?.into_iter().map(Some).collect();
+    if customers.iter().any(Option::is_none) {
+        sections.push(None);
+    }
+    Ok(sections
+        .into_iter()
+        .map(|section| {
+            let id = section.as_ref().map(|s| s.id);
+            let mut report = SectionReport {
+                section,
+                customers: customers.iter().filter(|c| **c == id).count(),
+                orders: orders.iter().filter(|o| **o == id).count(),
+                vlaaien: Vec::new(),
+                total_cents: 0,
+                paid_cents: 0,
+            };
+            for (_, vlaai_id, name, price_cents, amount) in rows.iter().filter(|r| r.0 == id) {
+                report.total_cents += i64::from(*price_cents) * i64::from(*amount);
+                match report.vlaaien.iter_mut().find(|v| v.vlaai_id == *vlaai_id) {
+                    Some(count) => count.amount += i64::from(*amount),
+                    None => report.vlaaien.push(VlaaiCount {
+                        vlaai_id: *vlaai_id,
+                        name: name.clone(),
+                        amount: i64::from(*amount),
+                    }),
+                }
+            }
+            report.paid_cents = payments
+                .iter()
+                .filter(|p| p.0 == id)
+                .map(|p| i64::from(p.1))
+                .sum();
+            report
+        })
+        .collect())
+}
+
+/// Check that the rows name existing vlaaien, each vlaai at most once and with a positive amount.
+/// Vlaaien that are no longer sold are only accepted if they are on the `existing` order already
+fn check_rows(
+    conn: &SqliteConnection,
+    rows: &[RowChange],
+    existing: Option<i32>,
+) -> Result<(), OrderError> {
+    if rows.is_empty() {
+        return Err(OrderError::Invalid(
+            "An order needs at least one vlaai".to_string(),
+        ));
+    }
+    for (i, row) in rows.iter().enumerate() {
+        if row.amount <= 0 {
+            return Err(OrderError::Invalid(format!(
+                "The amount of vlaai {} should be positive",
+                row.vlaai_id
+            )));
+        }
+        if rows[..i].iter().any(|r| r.vlaai_id == row.vlaai_id) {
+            return Err(OrderError::Invalid(format!(
+                "Vlaai {} is ordered more than once",
+                row.vlaai_id
+            )));
+        }
+        let active: bool = vlaai::table
+            .find(row.vlaai_id)
+            .select(vlaai::active)
+            .get_result(conn)
+            .optional()?
+            .ok_or(OrderError::VlaaiNotFound(row.vlaai_id))?;
+        if !active {
+            let ordered: i64 = vlaai_to_order::table
+                .filter(vlaai_to_order::order_id.eq(existing.unwrap_or(-1)))
+                .filter(vlaai_to_order::vlaai_id.eq(row.vlaai_id))
+                .count()
+                .get_result(conn)?;
+            if ordered == 0 {
+                return Err(OrderError::Invalid(format!(
+                    "Vlaai {} is not sold anymore",
+                    row.vlaai_id
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn insert_rows(conn: &SqliteConnection, order_id: i32, rows: &[RowChange]) -> QueryResult<()> {
+    let rows: Vec<_> = rows
+        .iter()
+        .map(|row| NewVlaaiToOrder {
+            order_id,
+            vlaai_id: row.vlaai_id,
+            amount: row.amount,
+        })
+        .collect();
+    diesel::insert_into(vlaai_to_order::table)
+        .values(&rows)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Create a new order for a customer, recorded in the order history with the operator
+pub fn create_order(
+    conn: &SqliteConnection,
+    customer_id: i32,
+    rows: &[RowChange],
+    operator: Option<&str>,
+) -> Result<Order, OrderError> {
+    conn.transaction(|| {
+        let exists: Option<i32> = customer::table
+            .find(customer_id)
+            .select(customer::id)
+            .get_result(conn)
+            .optional()?;
+        if exists.is_none() {
+            return Err(OrderError::CustomerNotFound(customer_id));
+        }
+        check_rows(conn, rows, None)?;
+
+        diesel::insert_into(order::table)
+            .values(NewOrder {
+                customer_id,
+                order_number: None,
+                status: OrderStatus::New,
+            })
+            .execute(conn)?;
+        let order_id: i32 = diesel::select(last_insert_rowid).get_result(conn)?;
+        insert_rows(conn, order_id, rows)?;
+        diesel::insert_into(order_event::table)
+            .values(NewOrderEvent {
+                order_id,
+                previous_status: None,
+                new_status: OrderStatus::New,
+                operator,
+            })
+            .execute(conn)?;
+        Ok(order::table.find(order_id).get_result(conn)?)
+    })
+}
+
+/// Get an order that can still be changed
+fn open_order(conn: &SqliteConnection, order_id: i32) -> Result<Order, OrderError> {
+    let order: Order = order::table
+        .find(order_id)
+        .get_result(conn)
+        .optional()?
+        .ok_or(OrderError::NotFound(order_id))?;
+    if !order.status.is_open() {
+        return Err(OrderError::Closed {
+            order_id,
+            status: order.status,
+        });
+    }
+    Ok(order)
 }
 
-pub fn update_order_new(conn: &SqliteConnection, order_id: i32) -> anyhow::Result<usize> {
-    Ok(diesel::update(order::table.find(order_id))
-        .set((
-            order::in_transit.eq(false),
-            order::picked_up.eq(false),
-            order::order_number.eq::<Option<i32>>(None),
+/// Replace the vlaaien of an order that has not been picked up or cancelled
+pub fn set_order_rows(
+    conn: &SqliteConnection,
+    order_id: i32,
+    rows: &[RowChange],
+) -> Result<Order, OrderError> {
+    conn.transaction(|| {
+        let order = open_order(conn, order_id)?;
+        check_rows(conn, rows, Some(order_id))?;
+        diesel::delete(vlaai_to_order::table.filter(vlaai_to_order::order_id.eq(order_id)))
+            .execute(conn)?;
+        insert_rows(conn, order_id, rows)?;
+        Ok(order)
+    })
+}
+
+/// Remove an open order with its vlaaien and history, returns the order as it was. Orders that
+/// have been picked up or paid for are kept, those have to be cancelled instead
+pub fn delete_order(conn: &SqliteConnection, order_id: i32) -> Result<Order, OrderError> {
+    conn.transaction(|| {
+        let order = open_order(conn, order_id)?;
+        if has_payments(conn, order_id)? {
+            return Err(OrderError::Invalid(format!(
+                "Order {} has payments and cannot be removed, cancel it instead",
+                order_id
+            )));
+        }
+        diesel::delete(order_event::table.filter(order_event::order_id.eq(order_id)))
+            .execute(conn)?;
+        diesel::delete(vlaai_to_order::table.filter(vlaai_to_order::order_id.eq(order_id)))
+            .execute(conn)?;
+        diesel::delete(order::table.find(order_id)).execute(conn)?;
+        Ok(order)
+    })
+}
+
+/// Create an order for a customer on behalf of the importer, see [`imported_orders`]
+pub fn create_imported_order(
+    conn: &SqliteConnection,
+    customer_id: i32,
+    rows: &[RowChange],
+) -> Result<Order, OrderError> {
+    conn.transaction(|| {
+        let order = create_order(conn, customer_id, rows, Some("import"))?;
+        diesel::update(order::table.find(order.id))
+            .set(order::imported.eq(true))
+            .execute(conn)?;
+        Ok(Order {
+            imported: true,
+            ..order
+        })
+    })
+}
+
+/// The orders that were created by the importer with their vlaaien, ordered by id
+pub fn imported_orders(conn: &SqliteConnection) -> anyhow::Result<Vec<(Order, Vec<RowChange>)>> {
+    let orders: Vec<Order> = order::table
+        .filter(order::imported.eq(true))
+        .order_by(order::id)
+        .load(conn)?;
+    let rows: Vec<(i32, i32, i32)> = vlaai_to_order::table
+        .filter(vlaai_to_order::order_id.eq_any(orders.iter().map(|o| o.id)))
+        .order_by(vlaai_to_order::id)
+        .select((
+            vlaai_to_order::order_id,
+            vlaai_to_order::vlaai_id,
+            vlaai_to_order::amount,
         ))
-        .execute(conn)?)
+        .load(conn)?;
+    Ok(orders
+        .into_iter()
+        .map(|order| {
+            let rows = rows
+                .iter()
+                .filter(|(order_id, _, _)| *order_id == order.id)
+                .map(|(_, vlaai_id, amount)| RowChange {
+                    vlaai_id: *vlaai_id,
+                    amount: *amount,
+                })
+                .collect();
+            (order, rows)
+        })
+        .collect())
+}
+
+/// Record a payment for an order, refunds have a negative amount
+pub fn add_payment(
+    conn: &SqliteConnection,
+    order_id: i32,
+    amount_cents: i32,
+    method: PaymentMethod,
+    operator: Option<&str>,
+) -> Result<Payment, OrderError> {
+    conn.transaction(|| {
+        let exists: Option<i32> = order::table
+            .find(order_id)
+            .select(order::id)
+            .get_result(conn)
+            .optional()?;
+        if exists.is_none() {
+            return Err(OrderError::NotFound(order_id));
+        }
+        if amount_cents == 0 {
+            return Err(OrderError::Invalid("A payment needs an amount".to_string()));
+        }
+        diesel::insert_into(payment::table)
+            .values(NewPayment {
+                order_id,
+                amount_cents,
+                method,
+                operator,
+            })
+            .execute(conn)?;
+        let payment_id: i32 = diesel::select(last_insert_rowid).get_result(conn)?;
+        Ok(payment::table.find(payment_id).get_result(conn)?)
+    })
+}
+
+/// Whether any payment has been recorded for the order, refunds included
+pub fn has_payments(conn: &SqliteConnection, order_id: i32) -> QueryResult<bool> {
+    diesel::select(diesel::dsl::exists(
+        payment::table.filter(payment::order_id.eq(order_id)),
+    ))
+    .get_result(conn)
+}
+
+/// All payments of an order, oldest first
+pub fn order_payments(conn: &SqliteConnection, order_id: i32) -> anyhow::Result<Vec<Payment>> {
+    let order: Order = order::table.find(order_id).get_result(conn)?;
+    Ok(Payment::belonging_to(&order)
+        .order_by(payment::id)
+        .load(conn)?)
 }
 
 pub fn max_order_number(conn: &SqliteConnection) -> anyhow::Result<i32> {
@@ -294,68 +1870,688 @@ pub fn max_order_number(conn: &SqliteConnection) -> anyhow::Result<i32> {
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
 
+    use super::OrderStatus;
+    use crate::schema::*;
+    use crate::test_support::TestDatabase;
+    use diesel::sqlite::Sqlite;
     use diesel::*;
     #[test]
     pub fn get_client_with_name() {
-        let conn = super::establish_connection(true);
-        let results =
-            super::customer_with_name(&conn, "%pie%").expect("Could not find customer with name");
-        assert!(results.len() > 0)
+        let db = TestDatabase::seeded();
+        let results = super::customer_with_name(&db.conn(), "pie")
+            .expect("Could not find customer with name");
+        assert!(!results.is_empty());
+        // Wildcards are searched for literally
+        let results = super::customer_with_name(&db.conn(), "p_et").unwrap();
+        assert!(results.is_empty())
     }
 
     #[test]
     pub fn order_for_customer() {
-        let conn = super::establish_connection(true);
-        let results = super::orders_for_customer(&conn, 1)
+        let db = TestDatabase::seeded();
+        let results = super::orders_for_customer(&db.conn(), 1)
             .expect("Could not find orders for customer with this id");
-        assert!(results.len() > 0)
+        assert!(!results.is_empty())
     }
 
     #[test]
     pub fn pending_orders() {
-        let conn = super::establish_connection(true);
+        let db = TestDatabase::seeded();
         let pending_orders =
-            super::all_pending_orders(&conn).expect("Could not retreive pending orders");
-        assert!(pending_orders.len() > 0);
+            super::all_pending_orders(&db.conn()).expect("Could not retreive pending orders");
+        assert_eq!(pending_orders.len(), 2);
+    }
+
+    #[test]
+    pub fn empty_database() {
+        let db = TestDatabase::empty();
+        let conn = db.conn();
+        assert!(super::all_pending_orders(&conn).unwrap().is_empty());
+        assert_eq!(super::max_order_number(&conn).unwrap(), 0);
+    }
+
+    #[test]
+    pub fn separate_databases() {
+        let first = TestDatabase::seeded();
+        let second = TestDatabase::seeded();
+        super::update_order_retrieved(&first.conn(), 1, None).unwrap();
+
+        // The second database is not affected by changes in the first
+        assert_eq!(super::all_pending_orders(&first.conn()).unwrap().len(), 1);
+        assert_eq!(super::all_pending_orders(&second.conn()).unwrap().len(), 2);
     }
 
     #[test]
     pub fn updating_order() {
-        let conn = super::establish_connection(true);
-
-        assert_eq!(super::max_order_number(&conn).unwrap(), 1);
-        // Change to new
-        assert!(super::update_order_new(&conn, 1).expect("Could not update order to be new") > 0);
-        // Set to retrieved
-        assert!(
-            super::update_order_retrieved(&conn, 1).expect("Could not update order to retrieved")
-                > 0
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+
+        let result: Result<(), super::StatusError> = (|| {
+            assert_eq!(super::max_order_number(&conn).unwrap(), 1);
+            // An order in transit cannot be reset to new
+            assert!(matches!(
+                super::update_order_new(&conn, 1, None),
+                Err(super::StatusError::InvalidTransition {
+                    from: OrderStatus::InTransit,
+                    to: OrderStatus::New,
+                    ..
+                })
+            ));
+            // Set to retrieved
+            let order = super::update_order_retrieved(&conn, 1, Some("Tim"))?;
+            assert_eq!(order.status, OrderStatus::PickedUp);
+            assert_eq!(order.order_number, None);
+
+            let pending_orders =
+                super::all_pending_orders(&conn).expect("Could not retreive pending orders");
+            assert_eq!(pending_orders.len(), 1);
+
+            // Cannot be picked up twice
+            assert!(super::update_order_retrieved(&conn, 1, None).is_err());
+
+            // Reopen and dispatch again
+            super::update_order_new(&conn, 1, None)?;
+            let order = super::update_order_in_transit(&conn, 1, 2, None)?;
+            assert_eq!(order.status, OrderStatus::InTransit);
+            assert_eq!(super::max_order_number(&conn).unwrap(), 2);
+
+            // Ready keeps the order number
+            let order = super::update_order_ready(&conn, 1, None)?;
+            assert_eq!(order.status, OrderStatus::Ready);
+            assert_eq!(order.order_number, Some(2));
+
+            // Every successful change has been recorded
+            let history = super::order_history(&conn, 1).expect("Could not retrieve history");
+            let changes: Vec<_> = history
+                .iter()
+                .map(|e| (e.previous_status, e.new_status))
+                .collect();
+            assert_eq!(
+                changes,
+                vec![
+                    (Some(OrderStatus::InTransit), OrderStatus::PickedUp),
+                    (Some(OrderStatus::PickedUp), OrderStatus::New),
+                    (Some(OrderStatus::New), OrderStatus::InTransit),
+                    (Some(OrderStatus::InTransit), OrderStatus::Ready),
+                ]
+            );
+            assert_eq!(history[0].operator.as_deref(), Some("Tim"));
+            assert_eq!(history[1].operator, None);
+            Ok(())
+        })();
+        result.expect("Could not update order");
+    }
+
+    #[test]
+    pub fn missing_order() {
+        let db = TestDatabase::seeded();
+        assert!(matches!(
+            super::update_order_in_transit(&db.conn(), 9999, 1, None),
+            Err(super::StatusError::NotFound(9999))
+        ));
+    }
+
+    #[test]
+    pub fn status_transitions() {
+        use OrderStatus::*;
+        assert!(New.can_transition_to(InTransit));
+        assert!(InTransit.can_transition_to(Ready));
+        assert!(InTransit.can_transition_to(PickedUp));
+        assert!(Ready.can_transition_to(PickedUp));
+        assert!(Ready.can_transition_to(Cancelled));
+        assert!(Cancelled.can_transition_to(New));
+        assert!(PickedUp.can_transition_to(New));
+
+        assert!(!New.can_transition_to(PickedUp));
+        assert!(!InTransit.can_transition_to(InTransit));
+        assert!(!PickedUp.can_transition_to(Cancelled));
+        assert!(!Cancelled.can_transition_to(InTransit));
+
+        for status in &[New, InTransit, Ready, PickedUp, Cancelled] {
+            assert_eq!(status.as_str().parse::<OrderStatus>().unwrap(), *status);
+        }
+    }
+
+    #[test]
+    pub fn customers() {
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+
+        let customer = super::create_customer(
+            &conn,
+            super::NewCustomer {
+                name: " Anna de Vries ",
+                email: Some("anna@devries.nl"),
+                section_id: None,
+            },
+        )
+        .expect("Could not create customer");
+        assert_eq!(customer.id, 3);
+        assert_eq!(customer.name, "Anna de Vries");
+
+        // Names and emails are compared regardless of case
+        let duplicate = super::NewCustomer {
+            name: "anna DE vries",
+            email: None,
+            section_id: None,
+        };
+        assert!(matches!(
+            super::create_customer(&conn, duplicate),
+            Err(super::CustomerError::Duplicate(_))
+        ));
+        let duplicate = super::NewCustomer {
+            name: "Anna",
+            email: Some("PETER@peter.nl"),
+            section_id: None,
+        };
+        assert!(matches!(
+            super::update_customer(&conn, 3, duplicate),
+            Err(super::CustomerError::Duplicate(_))
+        ));
+        // Also when the case of letters with accents differs
+        let emile = super::NewCustomer {
+            name: "Émile",
+            email: Some("émile@devries.nl"),
+            section_id: None,
+        };
+        super::create_customer(&conn, emile).expect("Could not create customer");
+        for (name, email) in &[("émile", None), ("Emiel", Some("ÉMILE@devries.nl"))] {
+            let duplicate = super::NewCustomer {
+                name,
+                email: *email,
+                section_id: None,
+            };
+            assert!(matches!(
+                super::create_customer(&conn, duplicate),
+                Err(super::CustomerError::Duplicate(_))
+            ));
+        }
+        super::delete_customer(&conn, 4).expect("Could not delete customer");
+        assert!(matches!(
+            super::create_customer(
+                &conn,
+                super::NewCustomer {
+                    name: "  ",
+                    email: None,
+                    section_id: None,
+                }
+            ),
+            Err(super::CustomerError::Invalid(_))
+        ));
+
+        // Keeping your own name is fine
+        let customer = super::update_customer(
+            &conn,
+            3,
+            super::NewCustomer {
+                name: "Anna de Vries",
+                email: None,
+                section_id: None,
+            },
+        )
+        .expect("Could not update customer");
+        assert_eq!(customer.email, None);
+
+        let names: Vec<_> = super::all_customers(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        assert_eq!(
+            names,
+            vec!["Anna de Vries", "Peter Bergmans", "Piet Pokerface"]
         );
 
-        let pending_orders =
-            super::all_pending_orders(&conn).expect("Could not retreive pending orders");
-        println!("{:?}", pending_orders);
-        assert_eq!(pending_orders.len(), 1);
+        super::delete_customer(&conn, 3).expect("Could not delete customer");
+        assert!(matches!(
+            super::get_customer(&conn, 3),
+            Err(super::CustomerError::NotFound(3))
+        ));
+    }
+
+    #[test]
+    pub fn delete_customer_with_orders() {
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+
+        // The order of customer 1 is still in transit
+        assert!(matches!(
+            super::delete_customer(&conn, 1),
+            Err(super::CustomerError::OpenOrders {
+                customer_id: 1,
+                orders: 1
+            })
+        ));
+
+        // Picked up orders are kept, and with them the customer
+        super::update_order_retrieved(&conn, 1, None).unwrap();
+        assert!(matches!(
+            super::delete_customer(&conn, 1),
+            Err(super::CustomerError::ClosedOrders {
+                customer_id: 1,
+                orders: 1
+            })
+        ));
+        assert!(!super::order_history(&conn, 1).unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn editing_orders() {
+        use super::{OrderError, RowChange};
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+        let rows = [
+            RowChange {
+                vlaai_id: 2,
+                amount: 2,
+            },
+            RowChange {
+                vlaai_id: 5,
+                amount: 1,
+            },
+        ];
 
-        // Set to status as in seed and return
-        let order = super::update_order_in_transit(&conn, 1, 2);
-        assert!(order.is_ok());
+        let order = super::create_order(&conn, 2, &rows, Some("Tim")).unwrap();
+        assert_eq!(order.status, OrderStatus::New);
+        let pending = super::to_pending(&conn, order).unwrap();
+        assert_eq!(pending.customer_name, "Piet Pokerface");
+        assert_eq!(pending.rows.len(), 2);
+        assert_eq!(pending.rows[0].vlaai, "HalfHalf");
+        assert_eq!(pending.rows[0].amount, 2);
+        let history = super::order_history(&conn, order.id).unwrap();
+        assert_eq!(history[0].previous_status, None);
+        assert_eq!(history[0].operator.as_deref(), Some("Tim"));
 
-        if let Ok(order) = order {
-            assert_eq!(order.picked_up, false);
-            assert_eq!(order.in_transit, true);
+        assert!(matches!(
+            super::create_order(&conn, 99, &rows, None),
+            Err(OrderError::CustomerNotFound(99))
+        ));
+        assert!(matches!(
+            super::create_order(&conn, 1, &[], None),
+            Err(OrderError::Invalid(_))
+        ));
+        assert!(matches!(
+            super::set_order_rows(&conn, order.id, &[rows[0], rows[0]]),
+            Err(OrderError::Invalid(_))
+        ));
+        let unknown = RowChange {
+            vlaai_id: 42,
+            amount: 1,
+        };
+        assert!(matches!(
+            super::set_order_rows(&conn, order.id, &[unknown]),
+            Err(OrderError::VlaaiNotFound(42))
+        ));
+
+        // A failed change leaves the rows alone
+        let pending = super::to_pending(&conn, order).unwrap();
+        assert_eq!(pending.rows.len(), 2);
+
+        super::set_order_rows(&conn, order.id, &rows[1..]).unwrap();
+        let pending = super::to_pending(&conn, order).unwrap();
+        assert_eq!(pending.rows.len(), 1);
+        assert_eq!(pending.rows[0].vlaai, "Kruimelpudding");
+
+        // Picked up orders cannot be changed
+        super::update_order_retrieved(&conn, 1, None).unwrap();
+        assert!(matches!(
+            super::set_order_rows(&conn, 1, &rows),
+            Err(OrderError::Closed { order_id: 1, .. })
+        ));
+        assert!(matches!(
+            super::delete_order(&conn, 1),
+            Err(OrderError::Closed { order_id: 1, .. })
+        ));
+
+        let deleted = super::delete_order(&conn, order.id).unwrap();
+        assert_eq!(deleted.id, order.id);
+        assert!(matches!(
+            super::delete_order(&conn, order.id),
+            Err(OrderError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    pub fn catalogue() {
+        use super::{CatalogueError, OrderError, RowChange, VlaaiChange};
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+
+        let change = VlaaiChange {
+            name: " Rijst ".to_string(),
+            price_cents: 1450,
+            active: true,
+            image: Some("".to_string()),
+            description: Some("Rijstevlaai".to_string()),
+        };
+        let vlaai = super::create_vlaai(&conn, &change).unwrap();
+        assert_eq!(vlaai.id, 6);
+        assert_eq!(vlaai.name, "Rijst");
+        assert_eq!(vlaai.image, None);
+        assert!(matches!(
+            super::create_vlaai(
+                &conn,
+                &VlaaiChange {
+                    name: "kers".to_string(),
+                    ..change.clone()
+                }
+            ),
+            Err(CatalogueError::Duplicate(_))
+        ));
+        assert!(matches!(
+            super::update_vlaai(
+                &conn,
+                6,
+                &VlaaiChange {
+                    price_cents: -1,
+                    ..change.clone()
+                }
+            ),
+            Err(CatalogueError::Invalid(_))
+        ));
+
+        // Kers is no longer sold, but stays on the orders that have it
+        let vlaai = super::update_vlaai(
+            &conn,
+            3,
+            &VlaaiChange {
+                name: "Kers".to_string(),
+                active: false,
+                ..change
+            },
+        )
+        .unwrap();
+        assert!(!vlaai.active);
+        assert_eq!(vlaai.description.as_deref(), Some("Rijstevlaai"));
+        assert_eq!(super::all_vlaaien(&conn, false).unwrap().len(), 6);
+        assert_eq!(super::all_vlaaien(&conn, true).unwrap().len(), 5);
+
+        let kers = [RowChange {
+            vlaai_id: 3,
+            amount: 2,
+        }];
+        assert!(matches!(
+            super::create_order(&conn, 1, &kers, None),
+            Err(OrderError::Invalid(_))
+        ));
+        super::set_order_rows(&conn, 1, &kers).expect("Could not keep the vlaai on the order");
+    }
+
+    #[test]
+    pub fn sections() {
+        use super::{
+            CustomerError, NewCustomer, OrderQuery, PaymentMethod, RowChange, SectionError,
+        };
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+
+        let welpen = super::create_section(&conn, " Welpen ").unwrap();
+        assert_eq!(welpen.name, "Welpen");
+        assert!(matches!(
+            super::create_section(&conn, "WELPEN"),
+            Err(SectionError::Duplicate(_))
+        ));
+        let eclaireurs = super::create_section(&conn, "Éclaireurs").unwrap();
+        assert!(matches!(
+            super::create_section(&conn, "éCLAIREURS"),
+            Err(SectionError::Duplicate(_))
+        ));
+        let found = super::section_with_name(&conn, "ÉCLAIREURS").unwrap();
+        assert_eq!(found.map(|s| s.id), Some(eclaireurs.id));
+        diesel::delete(section::table.find(eclaireurs.id))
+            .execute(&conn)
+            .unwrap();
+        assert!(matches!(
+            super::create_section(&conn, " "),
+            Err(SectionError::Invalid(_))
+        ));
+        let scouts = super::create_section(&conn, "Scouts").unwrap();
+        let names: Vec<_> = super::all_sections(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec!["Scouts", "Welpen"]);
+
+        // Peter joins the welpen, and another Peter Bergmans is a scout
+        super::update_customer(
+            &conn,
+            1,
+            NewCustomer {
+                name: "Peter Bergmans",
+                email: Some("peter@peter.nl"),
+                section_id: Some(welpen.id),
+            },
+        )
+        .unwrap();
+        let scout = NewCustomer {
+            name: "Peter Bergmans",
+            email: None,
+            section_id: Some(scouts.id),
+        };
+        let scout = super::create_customer(&conn, scout).unwrap();
+        let duplicate = NewCustomer {
+            name: "peter bergmans",
+            email: None,
+            section_id: Some(welpen.id),
+        };
+        assert!(matches!(
+            super::create_customer(&conn, duplicate),
+            Err(CustomerError::Duplicate(_))
+        ));
+        let unknown = NewCustomer {
+            name: "Anna",
+            email: None,
+            section_id: Some(42),
+        };
+        assert!(matches!(
+            super::create_customer(&conn, unknown),
+            Err(CustomerError::Invalid(_))
+        ));
+        let welpen_customers: Vec<_> = super::customers_in_section(&conn, welpen.id)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(welpen_customers, vec![1]);
+
+        let rows = [RowChange {
+            vlaai_id: 2,
+            amount: 3,
+        }];
+        super::create_order(&conn, scout.id, &rows, None).unwrap();
+        let query = OrderQuery {
+            section_id: Some(scouts.id),
+            ..OrderQuery::default()
+        };
+        let page = super::list_orders(&conn, &query).unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].customer_name, "Peter Bergmans");
+        assert_eq!(page.items[0].id, 3);
+
+        diesel::update(vlaai::table.find(1))
+            .set(vlaai::price_cents.eq(1000))
+            .execute(&conn)
+            .unwrap();
+        super::add_payment(&conn, 1, 500, PaymentMethod::Cash, None).unwrap();
+        super::cancel_order(&conn, 2, None).unwrap();
+        let report = serde_json::to_value(super::section_report(&conn).unwrap()).unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!([
+                {
+                    "section": {"id": 2, "name": "Scouts"},
+                    "customers": 1,
+                    "orders": 1,
+                    "vlaaien": [{"vlaaiId": 2, "name": "HalfHalf", "amount": 3}],
+                    "totalCents": 0,
+                    "paidCents": 0
+                },
+                {
+                    "section": {"id": 1, "name": "Welpen"},
+                    "customers": 1,
+                    "orders": 1,
+                    "vlaaien": [
+                        {"vlaaiId": 1, "name": "Abrikoos", "amount": 1},
+                        {"vlaaiId": 3, "name": "Kers", "amount": 1}
+                    ],
+                    "totalCents": 1000,
+                    "paidCents": 500
+                },
+                // Piet has no section and his order is cancelled
+                {
+                    "section": null,
+                    "customers": 1,
+                    "orders": 0,
+                    "vlaaien": [],
+                    "totalCents": 0,
+                    "paidCents": 0
+                }
+            ])
+        );
+    }
+
+    #[test]
+    pub fn payments() {
+        use super::{OrderError, PaymentMethod, VlaaiChange};
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+        for (id, name, price_cents) in &[(1, "Abrikoos", 1250), (3, "Kers", 1400)] {
+            let change = VlaaiChange {
+                name: name.to_string(),
+                price_cents: *price_cents,
+                active: true,
+                image: None,
+                description: None,
+            };
+            super::update_vlaai(&conn, *id, &change).unwrap();
         }
 
-        assert_eq!(super::max_order_number(&conn).unwrap(), 2);
+        let pending_order = |id| {
+            let order = order::table.find(id).get_result(&conn).unwrap();
+            super::to_pending(&conn, order).unwrap()
+        };
+        let pending = pending_order(1);
+        assert_eq!(pending.total_cents, 2650);
+        assert_eq!(pending.paid_cents, 0);
+        assert!(!pending.is_paid());
+
+        super::add_payment(&conn, 1, 2000, PaymentMethod::Cash, Some("Tim")).unwrap();
+        let payment = super::add_payment(&conn, 1, 650, PaymentMethod::BankTransfer, None).unwrap();
+        assert_eq!(payment.method, PaymentMethod::BankTransfer);
+        let pending = pending_order(1);
+        assert_eq!(pending.paid_cents, 2650);
+        assert_eq!(pending.outstanding_cents, 0);
+        assert!(pending.is_paid());
+        assert_eq!(super::order_payments(&conn, 1).unwrap().len(), 2);
+
+        // The payments of one order do not count for the other
+        let all = super::all_pending_orders(&conn).unwrap();
+        assert_eq!(all[0].paid_cents, 2650);
+        assert_eq!(all[1].paid_cents, 0);
+        assert_eq!(all[1].outstanding_cents, 2650);
+
+        assert!(matches!(
+            super::add_payment(&conn, 1, 0, PaymentMethod::Cash, None),
+            Err(OrderError::Invalid(_))
+        ));
+        assert!(matches!(
+            super::add_payment(&conn, 99, 100, PaymentMethod::Cash, None),
+            Err(OrderError::NotFound(99))
+        ));
+
+        // An order that has been paid for is cancelled instead of removed
+        assert!(matches!(
+            super::delete_order(&conn, 1),
+            Err(OrderError::Invalid(_))
+        ));
+        assert_eq!(payment::table.count().get_result::<i64>(&conn).unwrap(), 2);
+        super::delete_order(&conn, 2).unwrap();
+    }
+
+    #[test]
+    pub fn listing_orders() {
+        use super::{OrderQuery, OrderSort};
+        let db = misaligned_database();
+        let conn = db.conn();
+        let ids = |query: &OrderQuery| -> Vec<u32> {
+            super::list_orders(&conn, query)
+                .unwrap()
+                .items
+                .iter()
+                .map(|o| o.id)
+                .collect()
+        };
+
+        assert_eq!(ids(&OrderQuery::default()), vec![1, 2, 3, 4, 5]);
+        let query = OrderQuery {
+            status: Some(OrderStatus::InTransit),
+            ..Default::default()
+        };
+        assert_eq!(ids(&query), vec![1, 2, 4]);
+        let query = OrderQuery {
+            customer_id: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(ids(&query), vec![3, 5]);
+        let query = OrderQuery {
+            min_order_number: Some(2),
+            max_order_number: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(ids(&query), vec![2, 3]);
+        let query = OrderQuery {
+            vlaai_id: Some(2),
+            ..Default::default()
+        };
+        assert!(ids(&query).is_empty());
+
+        // Walk through the pages sorted by customer name
+        let mut query = OrderQuery {
+            sort: OrderSort::Customer,
+            limit: Some(2),
+            ..Default::default()
+        };
+        let mut pages = Vec::new();
+        loop {
+            let page = super::list_orders(&conn, &query).unwrap();
+            pages.push(page.items.iter().map(|o| o.id).collect::<Vec<_>>());
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(pages, vec![vec![1, 2], vec![4, 3], vec![5]]);
+        let page = super::list_orders(
+            &conn,
+            &OrderQuery {
+                sort: OrderSort::OrderNumber,
+                limit: Some(4),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(page.items.len(), 4);
+        assert_eq!(page.next_cursor.as_deref(), Some("4:4"));
 
-        super::update_order_in_transit(&conn, 1, 1).unwrap();
+        assert!(matches!(
+            super::list_orders(
+                &conn,
+                &OrderQuery {
+                    cursor: Some("nonsense".to_string()),
+                    ..Default::default()
+                }
+            ),
+            Err(super::OrderError::Invalid(_))
+        ));
     }
 
     #[test]
     pub fn pending() {
-        let conn = super::establish_connection(true);
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
         assert!(super::to_pending(
             &conn,
             super::order::table
@@ -365,4 +2561,215 @@ mod test {
         )
         .is_ok())
     }
+
+    /// Wraps a connection and counts the statements sent to the database
+    struct CountingConnection {
+        inner: SqliteConnection,
+        queries: std::cell::Cell<usize>,
+    }
+
+    impl CountingConnection {
+        fn count(&self) {
+            self.queries.set(self.queries.get() + 1);
+        }
+    }
+
+    impl connection::SimpleConnection for CountingConnection {
+        fn batch_execute(&self, query: &str) -> QueryResult<()> {
+            self.count();
+            self.inner.batch_execute(query)
+        }
+    }
+
+    impl Connection for CountingConnection {
+        type Backend = Sqlite;
+        type TransactionManager = connection::AnsiTransactionManager;
+
+        fn establish(database_url: &str) -> ConnectionResult<Self> {
+            Ok(CountingConnection {
+                inner: SqliteConnection::establish(database_url)?,
+                queries: Default::default(),
+            })
+        }
+
+        fn execute(&self, query: &str) -> QueryResult<usize> {
+            self.count();
+            self.inner.execute(query)
+        }
+
+        fn query_by_index<T, U>(&self, source: T) -> QueryResult<Vec<U>>
+        where
+            T: query_builder::AsQuery,
+            T::Query: query_builder::QueryFragment<Sqlite> + query_builder::QueryId,
+            Sqlite: sql_types::HasSqlType<T::SqlType>,
+            U: Queryable<T::SqlType, Sqlite>,
+        {
+            self.count();
+            self.inner.query_by_index(source)
+        }
+
+        fn query_by_name<T, U>(&self, source: &T) -> QueryResult<Vec<U>>
+        where
+            T: query_builder::QueryFragment<Sqlite> + query_builder::QueryId,
+            U: deserialize::QueryableByName<Sqlite>,
+        {
+            self.count();
+            self.inner.query_by_name(source)
+        }
+
+        fn execute_returning_count<T>(&self, source: &T) -> QueryResult<usize>
+        where
+            T: query_builder::QueryFragment<Sqlite> + query_builder::QueryId,
+        {
+            self.count();
+            self.inner.execute_returning_count(source)
+        }
+
+        fn transaction_manager(&self) -> &Self::TransactionManager {
+            self.inner.transaction_manager()
+        }
+    }
+
+    /// Fill an in-memory database with `orders` orders in transit
+    fn generated_database(orders: i32) -> CountingConnection {
+        let conn = CountingConnection::establish(":memory:").unwrap();
+        super::run_migrations(&conn.inner).expect("Could not run migrations");
+
+        conn.inner
+            .transaction::<_, diesel::result::Error, _>(|| {
+                for name in &["Abrikoos", "Kers", "Appel"] {
+                    diesel::insert_into(vlaai::table)
+                        .values(super::NewVlaai { name })
+                        .execute(&conn.inner)?;
+                }
+                for id in 1..=orders {
+                    // Let the customer ids run out of step with the order ids
+                    let customer_id = id + 1000;
+                    diesel::insert_into(customer::table)
+                        .values((
+                            customer::id.eq(customer_id),
+                            customer::name.eq(format!("Klant {}", id)),
+                        ))
+                        .execute(&conn.inner)?;
+                    diesel::insert_into(order::table)
+                        .values(super::NewOrder {
+                            customer_id,
+                            order_number: Some(id),
+                            status: OrderStatus::InTransit,
+                        })
+                        .execute(&conn.inner)?;
+                    for vlaai_id in 1..=(id % 3 + 1) {
+                        diesel::insert_into(vlaai_to_order::table)
+                            .values(super::NewVlaaiToOrder {
+                                order_id: id,
+                                vlaai_id,
+                                amount: vlaai_id,
+                            })
+                            .execute(&conn.inner)?;
+                    }
+                }
+                Ok(())
+            })
+            .unwrap();
+        conn
+    }
+
+    #[test]
+    pub fn pending_orders_query_count() {
+        let mut query_counts = Vec::new();
+        for &orders in &[10, 3000] {
+            let conn = generated_database(orders);
+
+            conn.queries.set(0);
+            let pending_orders =
+                super::all_pending_orders(&conn).expect("Could not retreive pending orders");
+            assert_eq!(pending_orders.len(), orders as usize);
+            let last = pending_orders.last().unwrap();
+            assert_eq!(last.customer_name, format!("Klant {}", orders));
+            assert_eq!(last.rows.len(), (orders % 3 + 1) as usize);
+            assert_eq!(last.rows[0].vlaai, "Abrikoos");
+            query_counts.push(conn.queries.get());
+
+            conn.queries.set(0);
+            let order = order::table.find(orders).get_result(&conn.inner).unwrap();
+            let pending = super::to_pending(&conn, order).unwrap();
+            assert_eq!(&pending, last);
+            assert_eq!(conn.queries.get(), 1);
+        }
+        assert_eq!(query_counts, vec![1, 1]);
+    }
+
+    /// Database where the customer ids run out of step with the order ids, like after
+    /// an import with multiple orders per customer. Orders 1 to 4 are on the screen
+    pub(crate) fn misaligned_database() -> TestDatabase {
+        TestDatabase::with_seed(misaligned_seed)
+    }
+
+    fn misaligned_seed(conn: &SqliteConnection) -> QueryResult<()> {
+        diesel::insert_into(vlaai::table)
+            .values(super::NewVlaai { name: "Kers" })
+            .execute(conn)?;
+        for (id, name) in &[
+            (7, "Anna de Vries"),
+            (3, "Peter Bergmans"),
+            (1, "Piet Pokerface"),
+        ] {
+            diesel::insert_into(customer::table)
+                .values((customer::id.eq(id), customer::name.eq(name)))
+                .execute(conn)?;
+        }
+        for (id, customer_id, status) in &[
+            (1, 7, OrderStatus::InTransit),
+            (2, 7, OrderStatus::InTransit),
+            (3, 1, OrderStatus::Ready),
+            (4, 3, OrderStatus::InTransit),
+            (5, 1, OrderStatus::New),
+        ] {
+            diesel::insert_into(order::table)
+                .values((
+                    order::id.eq(id),
+                    order::customer_id.eq(customer_id),
+                    order::order_number.eq(id),
+                    order::status.eq(status),
+                ))
+                .execute(conn)?;
+            diesel::insert_into(vlaai_to_order::table)
+                .values(super::NewVlaaiToOrder {
+                    order_id: *id,
+                    vlaai_id: 1,
+                    amount: 1,
+                })
+                .execute(conn)?;
+        }
+        Ok(())
+    }
+
+    /// The expected customer names of the orders in transit in the misaligned database
+    pub(crate) const MISALIGNED_NAMES: [(u32, &str); 4] = [
+        (1, "Anna de Vries"),
+        (2, "Anna de Vries"),
+        (3, "Piet Pokerface"),
+        (4, "Peter Bergmans"),
+    ];
+
+    #[test]
+    pub fn pending_customer_names() {
+        let db = misaligned_database();
+        let conn = db.conn();
+        let pending_orders = super::all_pending_orders(&conn).unwrap();
+        let names: Vec<_> = pending_orders
+            .iter()
+            .map(|p| (p.id, p.customer_name.as_str()))
+            .collect();
+        assert_eq!(names, MISALIGNED_NAMES.to_vec());
+
+        // Converting the orders one by one should give the same names
+        for pending_order in pending_orders {
+            let order = order::table
+                .find(pending_order.id as i32)
+                .get_result(&conn)
+                .unwrap();
+            assert_eq!(super::to_pending(&conn, order).unwrap(), pending_order);
+        }
+    }
 }
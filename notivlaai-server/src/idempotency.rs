@@ -0,0 +1,141 @@
+//! Remembers the responses to requests with an `Idempotency-Key` header, so a client that
+//! retries a request after losing the connection does not change an order twice
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a key is remembered
+pub const KEY_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A response that can be replayed for a retried request
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub body: serde_json::Value,
+}
+
+/// What to do with a request that has an idempotency key
+#[derive(Debug, PartialEq)]
+pub enum Begin {
+    /// The key is new, process the request and call [`IdempotencyKeys::finish`]
+    New,
+    /// The request was processed before, reply with the same response
+    Done(StoredResponse),
+    /// The first request with this key has not finished yet
+    InProgress,
+    /// The key was used before for a different request
+    Mismatch,
+}
+
+struct Entry {
+    /// Identifies the request the key was first used for
+    request: String,
+    response: Option<StoredResponse>,
+    created: Instant,
+}
+
+/// Idempotency keys that have been seen, shared by all requests
+#[derive(Clone)]
+pub struct IdempotencyKeys {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+    lifetime: Duration,
+}
+
+impl Default for IdempotencyKeys {
+    fn default() -> Self {
+        IdempotencyKeys::with_lifetime(KEY_LIFETIME)
+    }
+}
+
+impl IdempotencyKeys {
+    /// Remember keys for `lifetime`
+    pub fn with_lifetime(lifetime: Duration) -> IdempotencyKeys {
+        IdempotencyKeys {
+            entries: Default::default(),
+            lifetime,
+        }
+    }
+
+    /// Register that `key` is used for `request`, which should describe the request well
+    /// enough to notice when a key is reused for something else
+    pub fn begin(&self, key: &str, request: &str) -> Begin {
+        let mut entries = self.entries.lock().expect("Idempotency keys poisoned");
+        let lifetime = self.lifetime;
+        entries.retain(|_, entry| entry.created.elapsed() < lifetime);
+
+        match entries.get(key) {
+            Some(entry) if entry.request != request => Begin::Mismatch,
+            Some(Entry {
+                response: Some(response),
+                ..
+            }) => Begin::Done(response.clone()),
+            Some(_) => Begin::InProgress,
+            None => {
+                entries.insert(
+                    key.to_string(),
+                    Entry {
+                        request: request.to_string(),
+                        response: None,
+                        created: Instant::now(),
+                    },
+                );
+                Begin::New
+            }
+        }
+    }
+
+    /// Store the response for `key`. Server errors are not stored, so the request can be
+    /// retried with the same key
+    pub fn finish(&self, key: &str, response: StoredResponse) {
+        let mut entries = self.entries.lock().expect("Idempotency keys poisoned");
+        if response.status >= 500 {
+            entries.remove(key);
+        } else if let Some(entry) = entries.get_mut(key) {
+            entry.response = Some(response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Begin, IdempotencyKeys, StoredResponse};
+    use std::time::Duration;
+
+    fn response(status: u16) -> StoredResponse {
+        StoredResponse {
+            status,
+            body: serde_json::json!({ "id": 1 }),
+        }
+    }
+
+    #[test]
+    fn replays_responses() {
+        let keys = IdempotencyKeys::default();
+        assert_eq!(keys.begin("key", "PATCH 1 inTransit"), Begin::New);
+        assert_eq!(keys.begin("key", "PATCH 1 inTransit"), Begin::InProgress);
+
+        keys.finish("key", response(200));
+        assert_eq!(
+            keys.begin("key", "PATCH 1 inTransit"),
+            Begin::Done(response(200))
+        );
+        assert_eq!(keys.begin("key", "PATCH 2 inTransit"), Begin::Mismatch);
+        assert_eq!(keys.begin("other", "PATCH 2 inTransit"), Begin::New);
+    }
+
+    #[test]
+    fn server_errors_can_be_retried() {
+        let keys = IdempotencyKeys::default();
+        assert_eq!(keys.begin("key", "PATCH 1 inTransit"), Begin::New);
+        keys.finish("key", response(500));
+        assert_eq!(keys.begin("key", "PATCH 1 inTransit"), Begin::New);
+    }
+
+    #[test]
+    fn keys_expire() {
+        let keys = IdempotencyKeys::with_lifetime(Duration::from_millis(0));
+        assert_eq!(keys.begin("key", "PATCH 1 inTransit"), Begin::New);
+        keys.finish("key", response(200));
+        assert_eq!(keys.begin("key", "PATCH 1 inTransit"), Begin::New);
+    }
+}
@@ -0,0 +1,1860 @@
+//! Reading the order forms into customers and their vlaaien. The columns are found by their
+//! header: vlaai columns are matched against the catalogue, optionally through an alias file,
+//! and the other columns against the customer fields we know
+use crate::db::{self, Customer, NewCustomer, RowChange, Vlaai};
+use crate::search::normalize;
+use anyhow::{anyhow, bail, Context};
+use calamine::Reader;
+use diesel::{Connection, SqliteConnection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+/// The alias file that is used when `--aliases` is not given, if it exists
+pub const DEFAULT_ALIASES_FILE: &str = "vlaai-aliases.toml";
+
+/// A column with something we know about the customer
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CustomerField {
+    Name,
+    Email,
+    Speltak,
+}
+
+impl CustomerField {
+    /// Headers that are recognized without an alias
+    fn headers(self) -> &'static [&'static str] {
+        match self {
+            CustomerField::Name => &["naam", "name", "klant"],
+            CustomerField::Email => &["email", "emailadres", "mail"],
+            CustomerField::Speltak => &["speltak", "section"],
+        }
+    }
+}
+
+impl fmt::Display for CustomerField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CustomerField::Name => "name",
+            CustomerField::Email => "email",
+            CustomerField::Speltak => "speltak",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Headers of this year's form that do not match the catalogue or the customer fields by
+/// themselves, read from a TOML file like:
+///
+/// ```toml
+/// [vlaaien]
+/// "½ kers / ½ abrikoos" = "HalfHalf"
+///
+/// [customer]
+/// "Besteld door" = "name"
+/// ```
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Aliases {
+    /// Header to the name of a vlaai in the catalogue
+    pub vlaaien: HashMap<String, String>,
+    /// Header to a customer field
+    pub customer: HashMap<String, CustomerField>,
+}
+
+impl Aliases {
+    /// Read the aliases from a TOML file
+    pub fn from_file(path: &Path) -> anyhow::Result<Aliases> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Could not parse {}", path.display()))
+    }
+}
+
+/// Headers are compared without case, accents, spaces and punctuation, so "Kruimel- pudding"
+/// matches "Kruimelpudding"
+fn header_key(header: &str) -> String {
+    normalize(header).replace([' ', '.'], "")
+}
+
+/// What is in a column of the form
+#[derive(Clone, Debug, PartialEq)]
+pub enum Column {
+    /// The amount of a vlaai from the catalogue
+    Vlaai {
+        vlaai_id: i32,
+        name: String,
+    },
+    Customer(CustomerField),
+    /// Not recognized, the contents are not imported
+    Unmatched,
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Column::Vlaai { name, .. } => write!(f, "vlaai {}", name),
+            Column::Customer(field) => write!(f, "customer {}", field),
+            Column::Unmatched => f.write_str("not imported"),
+        }
+    }
+}
+
+/// The meaning of every column of a form, found from its header row
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layout {
+    headers: Vec<String>,
+    columns: Vec<Column>,
+}
+
+impl Layout {
+    /// Match the `headers` against the `catalogue` and the customer fields. Empty headers are
+    /// left unmatched, but an alias to a vlaai that is not in the catalogue, two columns for
+    /// the same thing or no name column at all are errors
+    pub fn new(
+        headers: &[String],
+        catalogue: &[Vlaai],
+        aliases: &Aliases,
+    ) -> anyhow::Result<Layout> {
+        let vlaai_aliases: HashMap<String, &str> = aliases
+            .vlaaien
+            .iter()
+            .map(|(header, vlaai)| (header_key(header), vlaai.as_str()))
+            .collect();
+        let customer_aliases: HashMap<String, CustomerField> = aliases
+            .customer
+            .iter()
+            .map(|(header, field)| (header_key(header), *field))
+            .collect();
+        let find_vlaai = |name: &str| {
+            let key = header_key(name);
+            catalogue
+                .iter()
+                .find(|vlaai| header_key(&vlaai.name) == key)
+        };
+        let known_field = |key: &str| {
+            [
+                CustomerField::Name,
+                CustomerField::Email,
+                CustomerField::Speltak,
+            ]
+            .iter()
+            .copied()
+            .find(|field| field.headers().contains(&key))
+        };
+
+        let mut columns = Vec::with_capacity(headers.len());
+        for header in headers {
+            let key = header_key(header);
+            let column = if key.is_empty() {
+                Column::Unmatched
+            } else if let Some(target) = vlaai_aliases.get(&key) {
+                let vlaai = find_vlaai(target).ok_or_else(|| {
+                    anyhow!(
+                        "Column '{}' is mapped to vlaai '{}', which is not in the catalogue",
+                        header,
+                        target
+                    )
+                })?;
+                Column::Vlaai {
+                    vlaai_id: vlaai.id,
+                    name: vlaai.name.clone(),
+                }
+            } else if let Some(field) = customer_aliases.get(&key) {
+                Column::Customer(*field)
+            } else if let Some(vlaai) = find_vlaai(header) {
+                Column::Vlaai {
+                    vlaai_id: vlaai.id,
+                    name: vlaai.name.clone(),
+                }
+            } else if let Some(field) = known_field(&key) {
+                Column::Customer(field)
+            } else {
+                Column::Unmatched
+            };
+
+            if column != Column::Unmatched {
+                if let Some(i) = columns.iter().position(|other| *other == column) {
+                    bail!(
+                        "Columns '{}' and '{}' are both read as the same {}",
+                        headers[i],
+                        header,
+                        match column {
+                            Column::Customer(_) => "customer field",
+                            _ => "vlaai",
+                        }
+                    );
+                }
+            }
+            columns.push(column);
+        }
+
+        if !columns.contains(&Column::Customer(CustomerField::Name)) {
+            bail!(
+                "None of the columns {:?} holds the name of the customer, add it to the [customer] \
+                 section of the alias file",
+                headers
+            );
+        }
+        if !columns
+            .iter()
+            .any(|column| matches!(column, Column::Vlaai { .. }))
+        {
+            bail!(
+                "None of the columns {:?} matches a vlaai in the catalogue, add the vlaaien with \
+                 POST /vlaaien or map the columns in the [vlaaien] section of the alias file",
+                headers
+            );
+        }
+
+        Ok(Layout {
+            headers: headers.to_vec(),
+            columns,
+        })
+    }
+
+    /// The headers and what was found in them
+    pub fn columns(&self) -> impl Iterator<Item = (&str, &Column)> {
+        self.headers
+            .iter()
+            .map(String::as_str)
+            .zip(self.columns.iter())
+    }
+
+    /// Headers of the columns that are not imported
+    pub fn unmatched(&self) -> Vec<&str> {
+        self.columns()
+            .filter(|(header, column)| **column == Column::Unmatched && !header.trim().is_empty())
+            .map(|(header, _)| header)
+            .collect()
+    }
+
+    /// Read a row of the form, rows without a name and the totals at the bottom are skipped.
+    /// A row with problems is not read at all, all its problems are returned instead
+    pub fn record(
+        &self,
+        line: u64,
+        fields: &[String],
+    ) -> Result<Option<ImportRecord>, Vec<Problem>> {
+        let mut record = ImportRecord {
+            line,
+            name: String::new(),
+            email: None,
+            speltak: None,
+            rows: Vec::new(),
+        };
+        let mut problems = Vec::new();
+        let mut problem = |header: &str, kind, message| {
+            problems.push(Problem {
+                line,
+                column: Some(header.to_string()),
+                kind,
+                message,
+            })
+        };
+        for ((header, column), field) in self.columns().zip(fields) {
+            let field = field.trim();
+            if field.is_empty() {
+                continue;
+            }
+            match column {
+                Column::Customer(CustomerField::Name) => record.name = field.to_string(),
+                Column::Customer(CustomerField::Email) => {
+                    if !db::is_email_address(field) {
+                        problem(
+                            header,
+                            ProblemKind::Email,
+                            format!("'{}' is not an email address", field),
+                        );
+                    }
+                    record.email = Some(field.to_string())
+                }
+                Column::Customer(CustomerField::Speltak) => {
+                    record.speltak = Some(field.to_string())
+                }
+                Column::Vlaai { vlaai_id, name } => match field.parse::<i32>() {
+                    Ok(amount) if amount <= 0 || amount > MAX_AMOUNT => problem(
+                        header,
+                        ProblemKind::Amount,
+                        format!(
+                            "{} {} is not an amount that can be ordered, expected 1 to {}",
+                            amount, name, MAX_AMOUNT
+                        ),
+                    ),
+                    Ok(amount) => record.rows.push(RowChange {
+                        vlaai_id: *vlaai_id,
+                        amount,
+                    }),
+                    Err(_) => problem(
+                        header,
+                        ProblemKind::Parse,
+                        format!("'{}' is not an amount", field),
+                    ),
+                },
+                Column::Unmatched => {}
+            }
+        }
+
+        if record.name.starts_with("Totaal") {
+            return Ok(None);
+        }
+        if record.name.is_empty() {
+            if !record.rows.is_empty() {
+                problems.push(Problem {
+                    line,
+                    column: None,
+                    kind: ProblemKind::MissingName,
+                    message: "Vlaaien are ordered without the name of a customer".to_string(),
+                });
+            }
+            if problems.is_empty() {
+                return Ok(None);
+            }
+        }
+        if problems.is_empty() {
+            Ok(Some(record))
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+/// More vlaaien of one kind on a single order is most likely a mistake
+pub const MAX_AMOUNT: i32 = 100;
+
+/// What is wrong with a row of the form
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ProblemKind {
+    /// The row or a field could not be read
+    Parse,
+    /// An amount that is zero, negative or more than [`MAX_AMOUNT`]
+    Amount,
+    /// The customer is on an earlier row already
+    DuplicateName,
+    /// There are customers with the name in more than one section and the row has no speltak
+    AmbiguousName,
+    Email,
+    MissingName,
+}
+
+/// A problem with a row of the form, the row is not imported
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Problem {
+    pub line: u64,
+    pub column: Option<String>,
+    pub kind: ProblemKind,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.column {
+            Some(column) => write!(
+                f,
+                "Line {}, column '{}': {}",
+                self.line, column, self.message
+            ),
+            None => write!(f, "Line {}: {}", self.line, self.message),
+        }
+    }
+}
+
+/// A customer and what they ordered, from one row of the form
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportRecord {
+    /// Line in the file, for messages
+    pub line: u64,
+    pub name: String,
+    pub email: Option<String>,
+    pub speltak: Option<String>,
+    pub rows: Vec<RowChange>,
+}
+
+/// A column of the form that is not imported
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UnmatchedColumn {
+    pub header: String,
+    /// Lines with a number in the column, which may be amounts of a vlaai that is not in the
+    /// catalogue
+    pub number_lines: Vec<u64>,
+}
+
+impl fmt::Display for UnmatchedColumn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Column '{}' is not imported", self.header)?;
+        if let Some(first) = self.number_lines.first() {
+            write!(
+                f,
+                ", but has numbers on {} rows starting at line {}: is it a vlaai that is not in \
+                 the catalogue?",
+                self.number_lines.len(),
+                first
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A form that has been read
+#[derive(Clone, Debug, PartialEq)]
+pub struct Import {
+    pub layout: Layout,
+    /// The rows without problems
+    pub records: Vec<ImportRecord>,
+    /// Problems with the other rows, by line
+    pub problems: Vec<Problem>,
+    pub unmatched_columns: Vec<UnmatchedColumn>,
+}
+
+impl Import {
+    /// What went wrong while reading the form
+    pub fn report(&self) -> Report<'_> {
+        Report {
+            records: self.records.len(),
+            unmatched_columns: &self.unmatched_columns,
+            problems: &self.problems,
+        }
+    }
+
+    /// Move the rows that cannot be matched with the customers in the database to the problems,
+    /// like a customer whose email is on two rows with different names
+    pub fn check(&mut self, conn: &SqliteConnection) -> anyhow::Result<()> {
+        let plan = plan(conn, &self.records, &self.problems)?;
+        let lines: Vec<u64> = plan.problems.iter().map(|problem| problem.line).collect();
+        self.records.retain(|record| !lines.contains(&record.line));
+        self.problems.extend(plan.problems);
+        self.problems.sort_by_key(|problem| problem.line);
+        Ok(())
+    }
+}
+
+/// The result of reading a form, to show before importing it
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Report<'a> {
+    /// Number of rows that can be imported
+    pub records: usize,
+    pub unmatched_columns: &'a [UnmatchedColumn],
+    pub problems: &'a [Problem],
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for column in self.unmatched_columns {
+            writeln!(f, "{}", column)?;
+        }
+        for problem in self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        writeln!(
+            f,
+            "{} rows can be imported, {} problems",
+            self.records,
+            self.problems.len()
+        )
+    }
+}
+
+/// Read the `rows` of a form, the first one is the header. Every row comes with its line number,
+/// or is a problem if it could not be read
+pub fn read_rows<I>(rows: I, catalogue: &[Vlaai], aliases: &Aliases) -> anyhow::Result<Import>
+where
+    I: IntoIterator<Item = Result<(u64, Vec<String>), Problem>>,
+{
+    let mut rows = rows.into_iter();
+    let (_, headers) = rows
+        .next()
+        .ok_or_else(|| anyhow!("The form is empty, expected a header row"))?
+        .map_err(|problem| anyhow!("Could not read the header: {}", problem))?;
+    let layout = Layout::new(&headers, catalogue, aliases)?;
+
+    let mut records: Vec<ImportRecord> = Vec::new();
+    let mut problems = Vec::new();
+    let mut unmatched_columns: Vec<_> = layout
+        .unmatched()
+        .into_iter()
+        .map(|header| UnmatchedColumn {
+            header: header.to_string(),
+            number_lines: Vec::new(),
+        })
+        .collect();
+    for row in rows {
+        let record = match row {
+            Ok((line, fields)) => {
+                let record = layout.record(line, &fields);
+                // The totals at the bottom have numbers in every column
+                if record != Ok(None) {
+                    for ((header, column), field) in layout.columns().zip(&fields) {
+                        if *column != Column::Unmatched || field.trim().parse::<i32>().is_err() {
+                            continue;
+                        }
+                        if let Some(unmatched) =
+                            unmatched_columns.iter_mut().find(|c| c.header == header)
+                        {
+                            unmatched.number_lines.push(line);
+                        }
+                    }
+                }
+                record
+            }
+            Err(problem) => Err(vec![problem]),
+        };
+        match record {
+            Ok(Some(record)) => {
+                let earlier = records.iter().find(|other| {
+                    normalize(&other.name) == normalize(&record.name)
+                        && other.speltak.as_deref().map(normalize)
+                            == record.speltak.as_deref().map(normalize)
+                });
+                match earlier {
+                    Some(earlier) => problems.push(Problem {
+                        line: record.line,
+                        column: None,
+                        kind: ProblemKind::DuplicateName,
+                        message: format!("{} is on line {} already", record.name, earlier.line),
+                    }),
+                    None => records.push(record),
+                }
+            }
+            Ok(None) => {}
+            Err(mut row_problems) => problems.append(&mut row_problems),
+        }
+    }
+    Ok(Import {
+        layout,
+        records,
+        problems,
+        unmatched_columns,
+    })
+}
+
+/// Read a CSV form
+pub fn read_csv<R: std::io::Read>(
+    reader: R,
+    catalogue: &[Vlaai],
+    aliases: &Aliases,
+) -> anyhow::Result<Import> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(reader);
+    let rows = reader.records().map(|result| match result {
+        Ok(record) => {
+            let line = record.position().map_or(0, |position| position.line());
+            Ok((line, record.iter().map(str::to_string).collect()))
+        }
+        Err(e) => Err(Problem {
+            line: e.position().map_or(0, |position| position.line()),
+            column: None,
+            kind: ProblemKind::Parse,
+            message: e.to_string(),
+        }),
+    });
+    read_rows(rows, catalogue, aliases)
+}
+
+/// Read a sheet of an Excel or LibreOffice spreadsheet, the first one if no `sheet` is given
+pub fn read_spreadsheet(
+    path: &Path,
+    sheet: Option<&str>,
+    catalogue: &[Vlaai],
+    aliases: &Aliases,
+) -> anyhow::Result<Import> {
+    let mut workbook = calamine::open_workbook_auto(path)
+        .with_context(|| format!("Could not open {}", path.display()))?;
+    let names = workbook.sheet_names();
+    let name = match sheet {
+        Some(sheet) => names.iter().find(|name| *name == sheet).ok_or_else(|| {
+            anyhow!(
+                "{} has no sheet '{}', the sheets are {:?}",
+                path.display(),
+                sheet,
+                names
+            )
+        })?,
+        None => names
+            .first()
+            .ok_or_else(|| anyhow!("{} has no sheets", path.display()))?,
+    };
+    let range = workbook
+        .worksheet_range(name)
+        .with_context(|| format!("Could not read sheet '{}' of {}", name, path.display()))?;
+
+    // The range starts at the first cell that is used
+    let first_line = range.start().map_or(1, |(row, _)| u64::from(row) + 1);
+    let rows = range.rows().enumerate().map(|(i, cells)| {
+        let fields = cells.iter().map(|cell| cell.to_string()).collect();
+        Ok((first_line + i as u64, fields))
+    });
+    read_rows(rows, catalogue, aliases)
+}
+
+/// Read a form from a `.csv`, `.xlsx`, `.xls` or `.ods` file. Only spreadsheets have a `sheet`
+pub fn read_file(
+    path: &Path,
+    sheet: Option<&str>,
+    catalogue: &[Vlaai],
+    aliases: &Aliases,
+) -> anyhow::Result<Import> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase);
+    match extension.as_deref() {
+        Some("csv") => {
+            if sheet.is_some() {
+                bail!("A CSV file has no sheets, leave out the sheet");
+            }
+            let file = std::fs::File::open(path)
+                .with_context(|| format!("Could not open {}", path.display()))?;
+            read_csv(file, catalogue, aliases)
+        }
+        Some("xlsx") | Some("xlsm") | Some("xls") | Some("ods") => {
+            read_spreadsheet(path, sheet, catalogue, aliases)
+        }
+        _ => bail!(
+            "Cannot read {}, use a .csv, .xlsx, .xls or .ods file",
+            path.display()
+        ),
+    }
+}
+
+/// A customer of the form, either one that is in the database already or one the plan adds
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CustomerRef {
+    Existing(i32),
+    /// Index of the [`CustomerChange::Add`] in [`Plan::customers`]
+    New(usize),
+}
+
+/// What an import does to a customer
+#[derive(Clone, Debug, PartialEq)]
+pub enum CustomerChange {
+    Add {
+        line: u64,
+        name: String,
+        email: Option<String>,
+        section: Option<String>,
+    },
+    Change {
+        line: u64,
+        before: Customer,
+        name: String,
+        email: Option<String>,
+        section: Option<String>,
+    },
+    /// No longer on the form, and all their orders came from an earlier import
+    Remove(Customer),
+}
+
+/// What an import does to an order
+#[derive(Clone, Debug, PartialEq)]
+pub enum OrderChange {
+    Add {
+        line: u64,
+        customer: CustomerRef,
+        customer_name: String,
+        rows: Vec<RowChange>,
+    },
+    Change {
+        line: u64,
+        order_id: i32,
+        customer_name: String,
+        before: Vec<RowChange>,
+        rows: Vec<RowChange>,
+    },
+    Remove {
+        order_id: i32,
+        customer_name: String,
+        rows: Vec<RowChange>,
+    },
+}
+
+/// The differences between the form and the database, which can be shown before applying them
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plan {
+    /// Sections of the form that are not in the database yet
+    pub sections: Vec<String>,
+    pub customers: Vec<CustomerChange>,
+    pub orders: Vec<OrderChange>,
+    /// Imported orders that differ from the form but are picked up, cancelled or paid for
+    /// already, and so cannot be removed
+    pub kept: Vec<String>,
+    /// Rows that cannot be matched with the customers in the database, they are left out
+    pub problems: Vec<Problem>,
+    vlaaien: HashMap<i32, String>,
+    section_names: HashMap<i32, String>,
+}
+
+/// Rows in a fixed order, so forms and orders can be compared
+fn sorted(rows: &[RowChange]) -> Vec<RowChange> {
+    let mut rows = rows.to_vec();
+    rows.sort_by_key(|row| row.vlaai_id);
+    rows
+}
+
+/// Names and sections are the same when they are [`normalize`]d, like the duplicates on the form
+fn same_name(a: &str, b: &str) -> bool {
+    normalize(a) == normalize(b)
+}
+
+fn same_email(a: &str, b: &str) -> bool {
+    a.trim().to_lowercase() == b.trim().to_lowercase()
+}
+
+fn same_section(a: Option<&str>, b: Option<&str>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => same_name(a, b),
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+/// Work out what importing the `records` changes. Customers are found by their email, or by
+/// their name and section when the form has no email for them. Every customer gets one imported
+/// order, which is changed or removed when the form changes as long as it has not been picked up.
+/// The rows with `problems` are not in the `records`, the customers on them may be missing, so
+/// nothing is removed as long as there are any
+pub fn plan(
+    conn: &SqliteConnection,
+    records: &[ImportRecord],
+    problems: &[Problem],
+) -> anyhow::Result<Plan> {
+    let customers = db::all_customers(conn)?;
+    let sections: HashMap<i32, String> = db::all_sections(conn)?
+        .into_iter()
+        .map(|section| (section.id, section.name))
+        .collect();
+    let section_of = |customer: &Customer| {
+        customer
+            .section_id
+            .and_then(|id| sections.get(&id))
+            .map(String::as_str)
+    };
+    let mut imported = db::imported_orders(conn)?;
+    let mut plan = Plan {
+        sections: Vec::new(),
+        customers: Vec::new(),
+        orders: Vec::new(),
+        kept: Vec::new(),
+        problems: Vec::new(),
+        vlaaien: db::all_vlaaien(conn, false)?
+            .into_iter()
+            .map(|vlaai| (vlaai.id, vlaai.name))
+            .collect(),
+        section_names: sections.clone(),
+    };
+
+    // The line that claimed every customer, to find customers that are on the form twice
+    let mut claimed: HashMap<CustomerRef, u64> = HashMap::new();
+    for record in records {
+        // The section as it is called in the database, or where the form first names it
+        let speltak = match &record.speltak {
+            Some(speltak) => {
+                let known = sections
+                    .values()
+                    .chain(plan.sections.iter())
+                    .find(|name| same_name(name, speltak))
+                    .cloned();
+                Some(known.unwrap_or_else(|| {
+                    plan.sections.push(speltak.clone());
+                    speltak.clone()
+                }))
+            }
+            None => None,
+        };
+
+        let named = |c: &&Customer| same_name(&c.name, &record.name);
+        let unclaimed =
+            |c: &&Customer| named(c) && !claimed.contains_key(&CustomerRef::Existing(c.id));
+        let mut existing = record
+            .email
+            .as_deref()
+            .and_then(|email| {
+                customers.iter().find(|c| {
+                    c.email
+                        .as_deref()
+                        .is_some_and(|other| same_email(email, other))
+                })
+            })
+            .or_else(|| {
+                customers
+                    .iter()
+                    .find(|c| named(c) && same_section(section_of(c), speltak.as_deref()))
+            });
+        if existing.is_none() {
+            // A customer from before they had a section, or in a section when the form has
+            // none, as long as no one else on the form is them already
+            let mut namesakes = customers
+                .iter()
+                .filter(unclaimed)
+                .filter(|c| speltak.is_none() || c.section_id.is_none());
+            existing = namesakes.next();
+            if speltak.is_none() && namesakes.next().is_some() {
+                plan.problems.push(Problem {
+                    line: record.line,
+                    column: None,
+                    kind: ProblemKind::AmbiguousName,
+                    message: format!(
+                        "there are customers named {} in more than one section, add the \
+                         speltak to the form",
+                        record.name
+                    ),
+                });
+                continue;
+            }
+        }
+
+        // Found by an email that is on the form twice, or under two names that are the same
+        let twice = |line: &u64| Problem {
+            line: record.line,
+            column: None,
+            kind: ProblemKind::DuplicateName,
+            message: format!("{} is on line {} of the form already", record.name, line),
+        };
+        let customer = match existing {
+            Some(customer) => {
+                if let Some(line) = claimed.get(&CustomerRef::Existing(customer.id)) {
+                    plan.problems.push(twice(line));
+                    continue;
+                }
+                // Only differences that matter are changed, not the case or accents
+                let name = if same_name(&customer.name, &record.name) {
+                    customer.name.clone()
+                } else {
+                    record.name.clone()
+                };
+                let email = match (&record.email, &customer.email) {
+                    (Some(email), Some(before)) if same_email(email, before) => {
+                        Some(before.clone())
+                    }
+                    (Some(email), _) => Some(email.clone()),
+                    (None, before) => before.clone(),
+                };
+                let section = speltak
+                    .clone()
+                    .or_else(|| section_of(customer).map(str::to_string));
+                if customer.name != name
+                    || customer.email != email
+                    || section.as_deref() != section_of(customer)
+                {
+                    plan.customers.push(CustomerChange::Change {
+                        line: record.line,
+                        before: customer.clone(),
+                        name,
+                        email,
+                        section,
+                    });
+                }
+                CustomerRef::Existing(customer.id)
+            }
+            None => {
+                let added = plan.customers.iter().enumerate().find(|(_, c)| match c {
+                    CustomerChange::Add {
+                        name,
+                        email,
+                        section,
+                        ..
+                    } => {
+                        same_name(name, &record.name)
+                            && same_section(section.as_deref(), speltak.as_deref())
+                            || email
+                                .as_deref()
+                                .zip(record.email.as_deref())
+                                .is_some_and(|(a, b)| same_email(a, b))
+                    }
+                    _ => false,
+                });
+                match added {
+                    Some((i, _)) => {
+                        plan.problems.push(twice(&claimed[&CustomerRef::New(i)]));
+                        continue;
+                    }
+                    None => {
+                        plan.customers.push(CustomerChange::Add {
+                            line: record.line,
+                            name: record.name.clone(),
+                            email: record.email.clone(),
+                            section: speltak.clone(),
+                        });
+                        CustomerRef::New(plan.customers.len() - 1)
+                    }
+                }
+            }
+        };
+        claimed.insert(customer, record.line);
+
+        let order = match customer {
+            CustomerRef::Existing(id) => imported
+                .iter()
+                .position(|(order, _)| order.customer_id == id)
+                .map(|i| imported.remove(i)),
+            CustomerRef::New(_) => None,
+        };
+        match order {
+            Some((order, before)) if sorted(&before) != sorted(&record.rows) => {
+                if !order.status.is_open() {
+                    plan.kept.push(format!(
+                        "Line {}: order {} of {} has status '{}' and is not changed",
+                        record.line, order.id, record.name, order.status
+                    ));
+                } else if record.rows.is_empty() && db::has_payments(conn, order.id)? {
+                    plan.kept.push(format!(
+                        "Line {}: order {} of {} has payments and is not removed",
+                        record.line, order.id, record.name
+                    ));
+                } else if record.rows.is_empty() {
+                    plan.orders.push(OrderChange::Remove {
+                        order_id: order.id,
+                        customer_name: record.name.clone(),
+                        rows: before,
+                    });
+                } else {
+                    plan.orders.push(OrderChange::Change {
+                        line: record.line,
+                        order_id: order.id,
+                        customer_name: record.name.clone(),
+                        before,
+                        rows: record.rows.clone(),
+                    });
+                }
+            }
+            Some(_) => {}
+            None if record.rows.is_empty() => {}
+            None => plan.orders.push(OrderChange::Add {
+                line: record.line,
+                customer,
+                customer_name: record.name.clone(),
+                rows: record.rows.clone(),
+            }),
+        }
+    }
+
+    // Imported orders of customers that are no longer on the form
+    let mut removed: HashMap<i32, usize> = HashMap::new();
+    for (order, rows) in imported {
+        let customer = customers
+            .iter()
+            .find(|c| c.id == order.customer_id)
+            .ok_or_else(|| anyhow!("Order {} has no customer", order.id))?;
+        if !order.status.is_open() || claimed.contains_key(&CustomerRef::Existing(customer.id)) {
+            continue;
+        }
+        if !problems.is_empty() || !plan.problems.is_empty() {
+            plan.kept.push(format!(
+                "Order {} of {} is not on the form, but is not removed because the form has \
+                 problems",
+                order.id, customer.name
+            ));
+            continue;
+        }
+        if db::has_payments(conn, order.id)? {
+            plan.kept.push(format!(
+                "Order {} of {} is no longer on the form but has payments and is not removed",
+                order.id, customer.name
+            ));
+            continue;
+        }
+        plan.orders.push(OrderChange::Remove {
+            order_id: order.id,
+            customer_name: customer.name.clone(),
+            rows,
+        });
+        *removed.entry(customer.id).or_default() += 1;
+    }
+    for customer in &customers {
+        if let Some(count) = removed.get(&customer.id) {
+            if db::orders_for_customer(conn, customer.id)?.len() == *count {
+                plan.customers
+                    .push(CustomerChange::Remove(customer.clone()));
+            }
+        }
+    }
+    Ok(plan)
+}
+
+impl Plan {
+    /// Whether the database matches the form already
+    pub fn is_empty(&self) -> bool {
+        self.sections.is_empty() && self.customers.is_empty() && self.orders.is_empty()
+    }
+
+    /// Make the changes, on an error the changes that were made already are not rolled back
+    /// unless this runs in a transaction, like [`import`] does
+    pub fn apply(&self, conn: &SqliteConnection) -> anyhow::Result<()> {
+        // The plan names sections as they are called in the database, or on the form if it adds
+        // them
+        let mut section_ids: HashMap<&str, i32> = self
+            .section_names
+            .iter()
+            .map(|(id, name)| (name.as_str(), *id))
+            .collect();
+        for name in &self.sections {
+            let section = db::create_section(conn, name)?;
+            section_ids.insert(name, section.id);
+        }
+        let section_id = |name: &Option<String>| -> anyhow::Result<Option<i32>> {
+            match name {
+                Some(name) => {
+                    Ok(Some(*section_ids.get(name.as_str()).ok_or_else(|| {
+                        anyhow!("Section '{}' does not exist", name)
+                    })?))
+                }
+                None => Ok(None),
+            }
+        };
+
+        let mut added = HashMap::new();
+        for (i, change) in self.customers.iter().enumerate() {
+            match change {
+                CustomerChange::Add {
+                    line,
+                    name,
+                    email,
+                    section,
+                } => {
+                    let customer = db::create_customer(
+                        conn,
+                        NewCustomer {
+                            name,
+                            email: email.as_deref(),
+                            section_id: section_id(section)?,
+                        },
+                    )
+                    .with_context(|| format!("Line {}: could not add {}", line, name))?;
+                    added.insert(i, customer.id);
+                }
+                CustomerChange::Change {
+                    line,
+                    before,
+                    name,
+                    email,
+                    section,
+                } => {
+                    db::update_customer(
+                        conn,
+                        before.id,
+                        NewCustomer {
+                            name,
+                            email: email.as_deref(),
+                            section_id: section_id(section)?,
+                        },
+                    )
+                    .with_context(|| format!("Line {}: could not change {}", line, before.name))?;
+                }
+                CustomerChange::Remove(_) => {}
+            }
+        }
+
+        for change in &self.orders {
+            match change {
+                OrderChange::Add {
+                    line,
+                    customer,
+                    customer_name,
+                    rows,
+                } => {
+                    let customer_id = match customer {
+                        CustomerRef::Existing(id) => *id,
+                        CustomerRef::New(i) => added[i],
+                    };
+                    db::create_imported_order(conn, customer_id, rows).with_context(|| {
+                        format!(
+                            "Line {}: could not add the order of {}",
+                            line, customer_name
+                        )
+                    })?;
+                }
+                OrderChange::Change {
+                    line,
+                    order_id,
+                    customer_name,
+                    rows,
+                    ..
+                } => {
+                    db::set_order_rows(conn, *order_id, rows).with_context(|| {
+                        format!(
+                            "Line {}: could not change the order of {}",
+                            line, customer_name
+                        )
+                    })?;
+                }
+                OrderChange::Remove { order_id, .. } => {
+                    db::delete_order(conn, *order_id)?;
+                }
+            }
+        }
+
+        for change in &self.customers {
+            if let CustomerChange::Remove(customer) = change {
+                db::delete_customer(conn, customer.id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rows(&self, rows: &[RowChange]) -> String {
+        if rows.is_empty() {
+            return "nothing".to_string();
+        }
+        rows.iter()
+            .map(|row| match self.vlaaien.get(&row.vlaai_id) {
+                Some(name) => format!("{} {}", row.amount, name),
+                None => format!("{} of vlaai {}", row.amount, row.vlaai_id),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn who(name: &str, email: Option<&str>, section: Option<&str>) -> String {
+    let mut who = name.to_string();
+    if let Some(email) = email {
+        who = format!("{} <{}>", who, email);
+    }
+    if let Some(section) = section {
+        who = format!("{} [{}]", who, section);
+    }
+    who
+}
+
+/// A diff with a line per change, starting with `+` for additions, `~` for changes and `-` for
+/// removals
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let section_of = |customer: &Customer| {
+            customer
+                .section_id
+                .and_then(|id| self.section_names.get(&id))
+                .map(String::as_str)
+        };
+        for name in &self.sections {
+            writeln!(f, "+ section {}", name)?;
+        }
+        for change in &self.customers {
+            match change {
+                CustomerChange::Add {
+                    line,
+                    name,
+                    email,
+                    section,
+                } => writeln!(
+                    f,
+                    "+ customer {} (line {})",
+                    who(name, email.as_deref(), section.as_deref()),
+                    line
+                )?,
+                CustomerChange::Change {
+                    line,
+                    before,
+                    name,
+                    email,
+                    section,
+                } => writeln!(
+                    f,
+                    "~ customer {} -> {} (line {})",
+                    who(&before.name, before.email.as_deref(), section_of(before)),
+                    who(name, email.as_deref(), section.as_deref()),
+                    line
+                )?,
+                CustomerChange::Remove(customer) => writeln!(
+                    f,
+                    "- customer {}",
+                    who(
+                        &customer.name,
+                        customer.email.as_deref(),
+                        section_of(customer)
+                    )
+                )?,
+            }
+        }
+        for change in &self.orders {
+            match change {
+                OrderChange::Add {
+                    line,
+                    customer_name,
+                    rows,
+                    ..
+                } => writeln!(
+                    f,
+                    "+ order of {}: {} (line {})",
+                    customer_name,
+                    self.rows(rows),
+                    line
+                )?,
+                OrderChange::Change {
+                    line,
+                    order_id,
+                    customer_name,
+                    before,
+                    rows,
+                } => writeln!(
+                    f,
+                    "~ order {} of {}: {} -> {} (line {})",
+                    order_id,
+                    customer_name,
+                    self.rows(before),
+                    self.rows(rows),
+                    line
+                )?,
+                OrderChange::Remove {
+                    order_id,
+                    customer_name,
+                    rows,
+                } => writeln!(
+                    f,
+                    "- order {} of {}: {}",
+                    order_id,
+                    customer_name,
+                    self.rows(rows)
+                )?,
+            }
+        }
+        for message in &self.kept {
+            writeln!(f, "! {}", message)?;
+        }
+        if self.is_empty() {
+            writeln!(f, "Nothing to change")?;
+        }
+        Ok(())
+    }
+}
+
+/// Bring the database in line with the `records` in a single transaction, so a failure leaves
+/// it as it was. With `dry_run` only the plan is made. Rows the plan cannot match are an error,
+/// [`Import::check`] moves them to the problems first
+pub fn import(
+    conn: &SqliteConnection,
+    records: &[ImportRecord],
+    problems: &[Problem],
+    dry_run: bool,
+) -> anyhow::Result<Plan> {
+    conn.transaction(|| {
+        let plan = plan(conn, records, problems)?;
+        if let Some(problem) = plan.problems.first() {
+            bail!("{}", problem);
+        }
+        if !dry_run {
+            plan.apply(conn)?;
+        }
+        Ok(plan)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        import, read_csv, read_file, Aliases, Column, CustomerChange, CustomerField, ImportRecord,
+        OrderChange, ProblemKind,
+    };
+    use crate::db::{self, OrderStatus, RowChange};
+    use crate::test_support::TestDatabase;
+
+    const FORM: &str = "\
+Naam,Abrikoos,Kers,½ kers / ½ abrikoos,Kruimel- pudding,Rijst,E-mail,Speltak,Opmerking
+Anna de Vries,1,,2,,,anna@devries.nl,Welpen,
+Jan Janssen,,3,,1,,,,Graag bezorgen
+,,,,,,,,
+Totaal,1,3,2,1,,,,
+";
+
+    fn aliases() -> Aliases {
+        toml::from_str(
+            r#"
+            [vlaaien]
+            "½ kers / ½ abrikoos" = "HalfHalf"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn reading_a_form() {
+        let db = TestDatabase::seeded();
+        let catalogue = db::all_vlaaien(&db.conn(), false).unwrap();
+        let import = read_csv(FORM.as_bytes(), &catalogue, &aliases()).unwrap();
+
+        let columns: Vec<_> = import.layout.columns().map(|(_, c)| c.clone()).collect();
+        assert_eq!(columns[0], Column::Customer(CustomerField::Name));
+        assert_eq!(
+            columns[3],
+            Column::Vlaai {
+                vlaai_id: 2,
+                name: "HalfHalf".to_string()
+            }
+        );
+        // Matched without an alias because spaces and punctuation are ignored
+        assert_eq!(
+            columns[4],
+            Column::Vlaai {
+                vlaai_id: 5,
+                name: "Kruimelpudding".to_string()
+            }
+        );
+        assert_eq!(columns[6], Column::Customer(CustomerField::Email));
+        // Rijst is not in the seeded catalogue
+        assert_eq!(import.layout.unmatched(), vec!["Rijst", "Opmerking"]);
+
+        assert_eq!(
+            import.records,
+            vec![
+                ImportRecord {
+                    line: 2,
+                    name: "Anna de Vries".to_string(),
+                    email: Some("anna@devries.nl".to_string()),
+                    speltak: Some("Welpen".to_string()),
+                    rows: vec![
+                        RowChange {
+                            vlaai_id: 1,
+                            amount: 1
+                        },
+                        RowChange {
+                            vlaai_id: 2,
+                            amount: 2
+                        },
+                    ],
+                },
+                ImportRecord {
+                    line: 3,
+                    name: "Jan Janssen".to_string(),
+                    email: None,
+                    speltak: None,
+                    rows: vec![
+                        RowChange {
+                            vlaai_id: 3,
+                            amount: 3
+                        },
+                        RowChange {
+                            vlaai_id: 5,
+                            amount: 1
+                        },
+                    ],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_forms() {
+        let db = TestDatabase::seeded();
+        let catalogue = db::all_vlaaien(&db.conn(), false).unwrap();
+        let read = |form: &str, aliases: &Aliases| {
+            read_csv(form.as_bytes(), &catalogue, aliases)
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert!(read("", &Aliases::default()).contains("empty"));
+        assert!(read("Kers,Appel\n1,2\n", &Aliases::default()).contains("name of the customer"));
+        assert!(read("Naam,Rijst\nAnna,1\n", &Aliases::default()).contains("matches a vlaai"));
+        assert!(read("Naam,Kers,kers\nAnna,1,1\n", &Aliases::default()).contains("same vlaai"));
+
+        let aliases: Aliases = toml::from_str("[vlaaien]\nRijstevlaai = \"Rijst\"").unwrap();
+        assert!(read("Naam,Kers,Rijstevlaai\n", &aliases).contains("not in the catalogue"));
+        assert!(toml::from_str::<Aliases>("[customer]\nTelefoon = \"phone\"").is_err());
+    }
+
+    #[test]
+    fn problems() {
+        let db = TestDatabase::seeded();
+        let catalogue = db::all_vlaaien(&db.conn(), false).unwrap();
+        let form = "\
+Naam,Kers,Appel,Rijst,Email
+Anna,veel,-1,,anna@devries
+Jan,1,,,
+Piet,1,,2,piet@piet.nl
+,1,,,
+JAN,,2,,
+Klaas,1,1000,,
+Totaal,3,1001,2,
+";
+        let form = [form.as_bytes(), b"Kees,\xff,,,\n"].concat();
+        let import = read_csv(form.as_slice(), &catalogue, &Aliases::default()).unwrap();
+        let problems: Vec<_> = import.problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            problems[..6],
+            [
+                "Line 2, column 'Kers': 'veel' is not an amount",
+                "Line 2, column 'Appel': -1 Appel is not an amount that can be ordered, expected 1 to 100",
+                "Line 2, column 'Email': 'anna@devries' is not an email address",
+                "Line 5: Vlaaien are ordered without the name of a customer",
+                "Line 6: JAN is on line 3 already",
+                "Line 7, column 'Appel': 1000 Appel is not an amount that can be ordered, expected 1 to 100",
+            ]
+        );
+        // The last row is not UTF-8
+        assert!(problems[6].starts_with("Line 9: CSV parse error"));
+        assert_eq!(import.problems[0].kind, ProblemKind::Parse);
+        assert_eq!(import.problems[4].kind, ProblemKind::DuplicateName);
+        // Only the rows without problems are read, a number in a column that is not imported is
+        // only a warning
+        let names: Vec<_> = import.records.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["Jan", "Piet"]);
+
+        let report = serde_json::to_value(import.report()).unwrap();
+        assert_eq!(report["records"], 2);
+        assert_eq!(
+            report["unmatchedColumns"],
+            serde_json::json!([{"header": "Rijst", "numberLines": [4]}])
+        );
+        assert_eq!(
+            report["problems"][2],
+            serde_json::json!({
+                "line": 2,
+                "column": "Email",
+                "kind": "email",
+                "message": "'anna@devries' is not an email address"
+            })
+        );
+        let report = import.report().to_string();
+        assert!(report.starts_with(
+            "Column 'Rijst' is not imported, but has numbers on 1 rows starting at line 4: is it \
+             a vlaai that is not in the catalogue?\n"
+        ));
+        assert!(report.ends_with("2 rows can be imported, 7 problems\n"));
+
+        assert!(!db::is_email_address("anna@.nl"));
+        assert!(!db::is_email_address("@devries.nl"));
+        assert!(!db::is_email_address("anna de vries@devries.nl"));
+        assert!(db::is_email_address("anna.de.vries@devries.nl"));
+    }
+
+    #[test]
+    fn customer_aliases() {
+        let db = TestDatabase::seeded();
+        let catalogue = db::all_vlaaien(&db.conn(), false).unwrap();
+        let aliases: Aliases = toml::from_str("[customer]\n\"Besteld door\" = \"name\"").unwrap();
+        let import = read_csv(
+            "Besteld door,Appel\nPiet,1\n".as_bytes(),
+            &catalogue,
+            &aliases,
+        )
+        .unwrap();
+        assert_eq!(import.records[0].name, "Piet");
+        assert!(import.layout.unmatched().is_empty());
+    }
+
+    fn record(line: u64, name: &str, email: Option<&str>, rows: &[(i32, i32)]) -> ImportRecord {
+        ImportRecord {
+            line,
+            name: name.to_string(),
+            email: email.map(str::to_string),
+            speltak: None,
+            rows: rows
+                .iter()
+                .map(|(vlaai_id, amount)| RowChange {
+                    vlaai_id: *vlaai_id,
+                    amount: *amount,
+                })
+                .collect(),
+        }
+    }
+
+    impl ImportRecord {
+        fn with_speltak(self, speltak: &str) -> ImportRecord {
+            ImportRecord {
+                speltak: Some(speltak.to_string()),
+                ..self
+            }
+        }
+    }
+
+    #[test]
+    fn importing_again() {
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+        let form = vec![
+            record(2, "Peter Bergmans", Some("PETER@peter.nl"), &[(3, 2)]),
+            record(3, "Anna de Vries", None, &[(1, 1), (2, 1)]),
+        ];
+
+        // The seeded order of Peter was not imported, like an order taken at the door, so the
+        // form adds one for him
+        let plan = import(&conn, &form, &[], true).unwrap();
+        assert_eq!(
+            plan.to_string(),
+            "+ customer Anna de Vries (line 3)\n\
+             + order of Peter Bergmans: 2 Kers (line 2)\n\
+             + order of Anna de Vries: 1 Abrikoos, 1 HalfHalf (line 3)\n"
+        );
+        // A dry run changes nothing
+        assert_eq!(db::all_customers(&conn).unwrap().len(), 2);
+        assert_eq!(import(&conn, &form, &[], true).unwrap(), plan);
+
+        import(&conn, &form, &[], false).unwrap();
+        assert_eq!(db::all_customers(&conn).unwrap().len(), 3);
+        assert_eq!(db::imported_orders(&conn).unwrap().len(), 2);
+        // Importing the same form again does not duplicate anything
+        let again = import(&conn, &form, &[], false).unwrap();
+        assert!(again.is_empty(), "{}", again);
+        assert_eq!(again.to_string(), "Nothing to change\n");
+
+        // Anna changes her order and gives her email, Peter is no longer on the form
+        let form = vec![record(
+            3,
+            "Anna de Vries",
+            Some("anna@devries.nl"),
+            &[(2, 1), (1, 3)],
+        )];
+        let plan = import(&conn, &form, &[], false).unwrap();
+        assert_eq!(
+            plan.to_string(),
+            "~ customer Anna de Vries -> Anna de Vries <anna@devries.nl> (line 3)\n\
+             ~ order 4 of Anna de Vries: 1 Abrikoos, 1 HalfHalf -> 1 HalfHalf, 3 Abrikoos (line 3)\n\
+             - order 3 of Peter Bergmans: 2 Kers\n"
+        );
+        // Peter keeps his other order, so he stays a customer
+        assert_eq!(db::all_customers(&conn).unwrap().len(), 3);
+
+        // Once picked up, an imported order is no longer changed or removed
+        db::update_order_in_transit(&conn, 4, 1, None).unwrap();
+        db::update_order_ready(&conn, 4, None).unwrap();
+        db::update_order_retrieved(&conn, 4, None).unwrap();
+        let plan = import(
+            &conn,
+            &[record(3, "Anna de Vries", None, &[(1, 1)])],
+            &[],
+            false,
+        )
+        .unwrap();
+        assert!(plan.is_empty());
+        assert_eq!(
+            plan.kept,
+            vec!["Line 3: order 4 of Anna de Vries has status 'picked_up' and is not changed"]
+        );
+        let plan = import(&conn, &[], &[], false).unwrap();
+        assert!(plan.is_empty(), "{}", plan);
+    }
+
+    #[test]
+    fn importing_after_upgrading() {
+        use diesel::connection::SimpleConnection;
+        // The seed stands in for a database that was loaded before imported orders were marked
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+        conn.batch_execute(include_str!(
+            "../migrations/2026-10-18-130000_imported_orders/down.sql"
+        ))
+        .unwrap();
+        conn.batch_execute(include_str!(
+            "../migrations/2026-10-18-130000_imported_orders/up.sql"
+        ))
+        .unwrap();
+
+        let form = [
+            record(2, "Peter Bergmans", None, &[(1, 1), (3, 1)]),
+            record(3, "Piet Pokerface", None, &[(1, 1), (3, 2)]),
+        ];
+        let plan = import(&conn, &form, &[], false).unwrap();
+        assert_eq!(
+            plan.to_string(),
+            "~ order 2 of Piet Pokerface: 1 Abrikoos, 1 Kers -> 1 Abrikoos, 2 Kers (line 3)\n"
+        );
+        assert_eq!(db::orders_for_customer(&conn, 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn removing_imported_customers() {
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+        let form = vec![
+            record(2, "Anna", None, &[(1, 1)]),
+            record(3, "Bob", None, &[(1, 1)]),
+        ];
+        import(&conn, &form, &[], false).unwrap();
+        // Bob has paid already, his order and with it Bob stay
+        db::add_payment(&conn, 4, 1000, db::PaymentMethod::Cash, None).unwrap();
+        let plan = import(&conn, &[record(2, "Jan", None, &[])], &[], false).unwrap();
+        assert!(matches!(
+            plan.customers.as_slice(),
+            [CustomerChange::Add { .. }, CustomerChange::Remove(anna)] if anna.name == "Anna"
+        ));
+        assert_eq!(
+            plan.kept,
+            vec!["Order 4 of Bob is no longer on the form but has payments and is not removed"]
+        );
+        let names: Vec<_> = db::all_customers(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        assert_eq!(
+            names,
+            vec!["Bob", "Jan", "Peter Bergmans", "Piet Pokerface"]
+        );
+        assert_eq!(db::imported_orders(&conn).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reimporting_rows_with_problems() {
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+        let catalogue = db::all_vlaaien(&conn, false).unwrap();
+        let form = "Naam,Kers,Email\nAnna,1,anna@devries.nl\nJan,2,\n";
+        let form = read_csv(form.as_bytes(), &catalogue, &Aliases::default()).unwrap();
+        import(&conn, &form.records, &form.problems, false).unwrap();
+
+        // Anna's row is left out now, which must not make her look like she left the form
+        let form = "Naam,Kers,Email\nAnna,1,anna@devries\nJan,3,\n";
+        let form = read_csv(form.as_bytes(), &catalogue, &Aliases::default()).unwrap();
+        assert_eq!(form.problems.len(), 1);
+        let plan = import(&conn, &form.records, &form.problems, false).unwrap();
+        assert!(plan.customers.is_empty());
+        assert!(matches!(
+            plan.orders.as_slice(),
+            [OrderChange::Change { .. }]
+        ));
+        assert_eq!(plan.kept.len(), 1);
+        assert!(plan.kept[0].ends_with(
+            "of Anna is not on the form, but is not removed because the form has problems"
+        ));
+        assert_eq!(db::imported_orders(&conn).unwrap().len(), 2);
+        assert!(db::all_customers(&conn)
+            .unwrap()
+            .iter()
+            .any(|c| c.name == "Anna"));
+    }
+
+    #[test]
+    fn checking_against_the_database() {
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+        let catalogue = db::all_vlaaien(&conn, false).unwrap();
+        // Both rows are Peter Bergmans by his email, which only the database can tell
+        let form = "Naam,Kers,Email\nPeter,1,peter@peter.nl\nPeter Bergmans,2,PETER@peter.nl\n";
+        let mut form = read_csv(form.as_bytes(), &catalogue, &Aliases::default()).unwrap();
+        assert!(form.problems.is_empty());
+        form.check(&conn).unwrap();
+        let names: Vec<_> = form.records.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["Peter"]);
+        let report = serde_json::to_value(form.report()).unwrap();
+        assert_eq!(
+            report["problems"],
+            serde_json::json!([{
+                "line": 3,
+                "column": null,
+                "kind": "duplicateName",
+                "message": "Peter Bergmans is on line 2 of the form already"
+            }])
+        );
+    }
+
+    #[test]
+    fn failed_imports_change_nothing() {
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+        let form = vec![
+            record(2, "Anna", None, &[(1, 1)]),
+            record(3, "Jan", Some("not an email"), &[(1, 1)]),
+        ];
+        let error = import(&conn, &form, &[], false).unwrap_err();
+        assert_eq!(error.to_string(), "Line 3: could not add Jan");
+        assert_eq!(db::all_customers(&conn).unwrap().len(), 2);
+        assert!(db::imported_orders(&conn).unwrap().is_empty());
+
+        let form = vec![
+            record(2, "Anna", None, &[(1, 1)]),
+            record(5, "anna", None, &[(3, 1)]),
+        ];
+        let error = import(&conn, &form, &[], false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Line 5: anna is on line 2 of the form already"
+        );
+        assert_eq!(
+            db::pending_order(&conn, 1).unwrap().status,
+            OrderStatus::InTransit
+        );
+    }
+
+    #[test]
+    fn spreadsheets() {
+        let db = TestDatabase::seeded();
+        let catalogue = db::all_vlaaien(&db.conn(), false).unwrap();
+        let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        for file in &["orders.xlsx", "orders.ods"] {
+            let path = fixtures.join(file);
+            let import = read_file(&path, Some("Bestellingen"), &catalogue, &aliases()).unwrap();
+            assert!(import.problems.is_empty(), "{}", import.report());
+            assert_eq!(
+                import.records,
+                vec![
+                    record(
+                        2,
+                        "Anna de Vries",
+                        Some("anna@devries.nl"),
+                        &[(1, 1), (2, 2)]
+                    )
+                    .with_speltak("Welpen"),
+                    record(3, "Jan Janssen", None, &[(3, 3)]),
+                ],
+                "{}",
+                file
+            );
+
+            // The first sheet is not an order form
+            let error = read_file(&path, None, &catalogue, &aliases()).unwrap_err();
+            assert!(
+                error.to_string().contains("name of the customer"),
+                "{}",
+                error
+            );
+            let error = read_file(&path, Some("Orders"), &catalogue, &aliases()).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!(
+                    "{} has no sheet 'Orders', the sheets are [\"Info\", \"Bestellingen\"]",
+                    path.display()
+                )
+            );
+        }
+
+        let error = read_file(&fixtures.join("orders.txt"), None, &catalogue, &aliases());
+        assert!(error.unwrap_err().to_string().starts_with("Cannot read"));
+        let error = read_file(
+            &fixtures.join("orders.csv"),
+            Some("Bestellingen"),
+            &catalogue,
+            &aliases(),
+        );
+        assert!(error.unwrap_err().to_string().contains("has no sheets"));
+    }
+
+    #[test]
+    fn importing_sections() {
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+        let form = vec![
+            record(2, "Peter Bergmans", None, &[(1, 1)]).with_speltak("Welpen"),
+            record(3, "Jan", None, &[(1, 2)]).with_speltak("Welpen"),
+            record(4, "Jan", None, &[(3, 1)]).with_speltak("scouts"),
+        ];
+        let plan = import(&conn, &form, &[], false).unwrap();
+        assert_eq!(
+            plan.to_string(),
+            "+ section Welpen\n\
+             + section scouts\n\
+             ~ customer Peter Bergmans <peter@peter.nl> -> Peter Bergmans <peter@peter.nl> [Welpen] (line 2)\n\
+             + customer Jan [Welpen] (line 3)\n\
+             + customer Jan [scouts] (line 4)\n\
+             + order of Peter Bergmans: 1 Abrikoos (line 2)\n\
+             + order of Jan: 2 Abrikoos (line 3)\n\
+             + order of Jan: 1 Kers (line 4)\n"
+        );
+        let sections = db::all_sections(&conn).unwrap();
+        assert_eq!(sections.len(), 2);
+        let welpen: Vec<_> = db::customers_in_section(&conn, sections[1].id)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        assert_eq!(welpen, vec!["Jan", "Peter Bergmans"]);
+
+        // Sections are compared without case, the Jans are told apart by their section
+        let form = vec![
+            record(2, "Peter Bergmans", None, &[(1, 1)]).with_speltak("welpen"),
+            record(3, "Jan", None, &[(1, 2)]).with_speltak("WELPEN"),
+            record(4, "Jan", None, &[(3, 2)]).with_speltak("Scouts"),
+        ];
+        let plan = import(&conn, &form, &[], false).unwrap();
+        assert_eq!(
+            plan.to_string(),
+            "~ order 5 of Jan: 1 Kers -> 2 Kers (line 4)\n"
+        );
+
+        // Also when a new section starts with a capital with an accent
+        let mut form = form;
+        form.push(record(5, "Émile", None, &[(1, 1)]).with_speltak("Éclaireurs"));
+        let plan = import(&conn, &form, &[], false).unwrap();
+        assert_eq!(
+            plan.to_string(),
+            "+ section Éclaireurs\n\
+             + customer Émile [Éclaireurs] (line 5)\n\
+             + order of Émile: 1 Abrikoos (line 5)\n"
+        );
+        form[3] = record(5, "Émile", None, &[(1, 1)]).with_speltak("ÉCLAIREURS");
+        assert!(import(&conn, &form, &[], false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn importing_namesakes() {
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+        let customer = db::NewCustomer {
+            name: "Kees",
+            email: None,
+            section_id: None,
+        };
+        db::create_customer(&conn, customer).unwrap();
+
+        // Kees from before the sections is the first Kees, not the second as well
+        let form = vec![
+            record(2, "Jan", None, &[(1, 1)]).with_speltak("Welpen"),
+            record(3, "Jan", None, &[(1, 2)]).with_speltak("Scouts"),
+            record(4, "Kees", None, &[(3, 1)]).with_speltak("Welpen"),
+            record(5, "Kees", None, &[(3, 2)]).with_speltak("Scouts"),
+        ];
+        let plan = import(&conn, &form, &[], false).unwrap();
+        assert_eq!(
+            plan.to_string(),
+            "+ section Welpen\n\
+             + section Scouts\n\
+             + customer Jan [Welpen] (line 2)\n\
+             + customer Jan [Scouts] (line 3)\n\
+             ~ customer Kees -> Kees [Welpen] (line 4)\n\
+             + customer Kees [Scouts] (line 5)\n\
+             + order of Jan: 1 Abrikoos (line 2)\n\
+             + order of Jan: 2 Abrikoos (line 3)\n\
+             + order of Kees: 1 Kers (line 4)\n\
+             + order of Kees: 2 Kers (line 5)\n"
+        );
+        assert_eq!(db::all_customers(&conn).unwrap().len(), 6);
+
+        // Without the speltak the Jans cannot be told apart, nor merged
+        let form = vec![record(2, "Jan", None, &[(1, 1)])];
+        let error = import(&conn, &form, &[], false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Line 2: there are customers named Jan in more than one section, add the speltak \
+             to the form"
+        );
+        assert_eq!(db::imported_orders(&conn).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn importing_accents() {
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+        let welpen = db::create_section(&conn, "Wélpen").unwrap();
+        let customer = db::NewCustomer {
+            name: "José Berg",
+            email: None,
+            section_id: Some(welpen.id),
+        };
+        db::create_customer(&conn, customer).unwrap();
+
+        // The form is typed without accents, José is found like a duplicate on the form would be
+        let form = vec![
+            record(2, "Jose  Berg", None, &[(1, 1)]).with_speltak("welpen"),
+            record(3, "Anna", None, &[(3, 1)]).with_speltak("Welpen"),
+        ];
+        let plan = import(&conn, &form, &[], false).unwrap();
+        assert_eq!(
+            plan.to_string(),
+            "+ customer Anna [Wélpen] (line 3)\n\
+             + order of Jose  Berg: 1 Abrikoos (line 2)\n\
+             + order of Anna: 1 Kers (line 3)\n"
+        );
+        let names: Vec<_> = db::customers_in_section(&conn, welpen.id)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        assert_eq!(names, vec!["Anna", "José Berg"]);
+        assert_eq!(db::all_sections(&conn).unwrap().len(), 1);
+    }
+}
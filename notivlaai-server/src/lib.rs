@@ -1,7 +1,17 @@
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 
+pub mod api_error;
+pub mod config;
 pub mod db;
+pub mod idempotency;
+pub mod import;
 pub mod schema;
+pub mod search;
+pub mod shutdown;
 pub mod status_updater;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 pub mod ws_updater;
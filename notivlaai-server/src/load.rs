@@ -1,158 +1,124 @@
-#[macro_use]
-extern crate diesel_migrations;
-
-use diesel::prelude::*;
+use anyhow::Context;
+use diesel::connection::{Connection, TransactionManager};
 use diesel::SqliteConnection;
-use notivlaai_lib::db::{NewCustomer, NewOrder, NewVlaai, NewVlaaiToOrder};
-
-use notivlaai_lib::schema;
-use serde::Deserialize;
-
-#[derive(Debug, Deserialize)]
-struct CSVRecord {
-    #[serde(rename = "Naam")]
-    naam: String,
-    #[serde(rename = "Abrikoos")]
-    abrikoos: Option<i32>,
-    #[serde(rename = "Kers")]
-    kers: Option<i32>,
-    #[serde(rename = "½ kers / ½ abrikoos")]
-    halfhalf: Option<i32>,
-    #[serde(rename = "Rijst")]
-    rijst: Option<i32>,
-    #[serde(rename = "Kruimel- pudding")]
-    kruimelpudding: Option<i32>,
-    #[serde(rename = "Appel")]
-    appel: Option<i32>,
-    email: Option<String>,
-    #[serde(rename = "Speltak")]
-    speltak: Option<String>,
+use notivlaai_lib::config::{Args, Config, Settings};
+use notivlaai_lib::db;
+use notivlaai_lib::import::{self, Aliases};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use structopt::StructOpt;
+
+/// How the report of the form is printed
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    /// The report as JSON on stdout, everything else is printed on stderr
+    Json,
 }
 
-embed_migrations!();
+impl FromStr for Format {
+    type Err = String;
 
-/// Insert a vlaai into the database
-fn insert_vlaai(conn: &SqliteConnection, name: &str) {
-    let vlaai = NewVlaai { name };
-    diesel::insert_into(schema::vlaai::table)
-        .values(vlaai)
-        .execute(conn)
-        .expect("Could not insert vlaai");
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            other => Err(format!("'{}' is not a format, use 'text' or 'json'", other)),
+        }
+    }
 }
 
-/// Insert a customer
-fn insert_customer(conn: &SqliteConnection, name: &str, email: &str) {
-    let customer = NewCustomer { name, email };
-    diesel::insert_into(schema::customer::table)
-        .values(customer)
-        .execute(conn)
-        .expect("Could not insert vlaai");
+/// Command-line arguments of the importer
+#[derive(StructOpt, Debug)]
+#[structopt(name = "load")]
+struct LoadArgs {
+    /// The order form, a `.csv`, `.xlsx`, `.xls` or `.ods` file
+    #[structopt(parse(from_os_str), default_value = "orders.csv")]
+    input: PathBuf,
+    /// Sheet of the spreadsheet with the orders, the first sheet is used if not given
+    #[structopt(long)]
+    sheet: Option<String>,
+    /// TOML file that maps the headers of the form to vlaaien and customer fields,
+    /// `vlaai-aliases.toml` is used if it exists
+    #[structopt(long, parse(from_os_str))]
+    aliases: Option<PathBuf>,
+    /// Only show what would be added, changed and removed
+    #[structopt(long)]
+    dry_run: bool,
+    /// Import nothing if any row has a problem, instead of leaving out those rows
+    #[structopt(long)]
+    strict: bool,
+    /// Print the report of the form as `text` or `json`
+    #[structopt(long, default_value = "text")]
+    format: Format,
+    /// TOML configuration file of the server, `notivlaai.toml` is used if it exists
+    #[structopt(short, long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Path of the SQLite database, instead of the one of the server
+    #[structopt(long)]
+    database_url: Option<String>,
 }
 
-/// Insert an order
-fn insert_order(
-    conn: &SqliteConnection,
-    in_transit: bool,
-    order_number: Option<i32>,
-    name: &str,
-    vlaaien: &[(&str, i32)],
-) {
-    let client = schema::customer::table
-        .filter(schema::customer::name.eq(name))
-        .first::<notivlaai_lib::db::Customer>(conn)
-        .expect("Could not find customer");
-
-    diesel::insert_into(schema::order::table)
-        .values(NewOrder {
-            customer_id: client.id,
-            in_transit,
-            picked_up: false,
-            order_number,
-        })
-        .execute(conn)
-        .expect("Could not insert order");
-
-    let order_id: i32 = schema::order::table
-        .filter(schema::order::customer_id.eq(client.id))
-        .select(schema::order::id)
-        .first(conn)
-        .expect("Could not find order");
-
-    for (vlaai, amount) in vlaaien {
-        let vlaai_id: i32 = schema::vlaai::table
-            .filter(schema::vlaai::name.eq(vlaai))
-            .select(schema::vlaai::id)
-            .first(conn)
-            .expect("Could not get vlaai");
-
-        diesel::insert_into(schema::vlaai_to_order::table)
-            .values(NewVlaaiToOrder {
-                order_id,
-                vlaai_id,
-                amount: *amount,
-            })
-            .execute(conn)
-            .expect("Could not insert vlaai -> order");
+fn main() -> anyhow::Result<()> {
+    let args = LoadArgs::from_args();
+    let mut out: Box<dyn Write> = match args.format {
+        Format::Text => Box::new(std::io::stdout()),
+        Format::Json => Box::new(std::io::stderr()),
+    };
+    let server = Args {
+        config: args.config.clone(),
+        settings: Settings {
+            database_url: args.database_url.clone(),
+            ..Settings::default()
+        },
+    };
+    let config = Config::load(&server)?;
+    let conn = db::Database::from_config(&config)?.conn()?;
+    if !args.dry_run {
+        return load(&args, &conn, &mut out);
     }
-}
-
-/// Create a vlaaien record from the CSVRecord
-fn record_to_vlaai(record: &CSVRecord) -> Vec<(&str, i32)> {
-    let mut slice = Vec::new();
 
-    if let Some(amount) = record.abrikoos {
-        slice.push(("Abrikoos", amount));
-    }
-    if let Some(amount) = record.halfhalf {
-        slice.push(("HalfHalf", amount));
-    }
-    if let Some(amount) = record.kers {
-        slice.push(("Kers", amount));
-    }
-    if let Some(amount) = record.appel {
-        slice.push(("Appel", amount));
-    }
-    if let Some(amount) = record.kruimelpudding {
-        slice.push(("Kruimelpudding", amount));
-    }
-    if let Some(amount) = record.rijst {
-        slice.push(("Rijst", amount));
-    }
-
-    slice
+    // The migrations are rolled back with everything else, a dry run leaves the database as it was
+    let transactions = conn.transaction_manager();
+    transactions.begin_transaction(&*conn)?;
+    let result = load(&args, &conn, &mut out);
+    transactions.rollback_transaction(&*conn)?;
+    result?;
+    writeln!(out, "Dry run, the database has not been changed")?;
+    Ok(())
 }
 
-fn main() {
-    let conn = notivlaai_lib::db::establish_connection(false);
-    embedded_migrations::run_with_output(&conn, &mut std::io::stdout())
-        .expect("Could not run migrations");
-
-    // Insert vlaaien
-    insert_vlaai(&conn, "Abrikoos");
-    insert_vlaai(&conn, "HalfHalf");
-    insert_vlaai(&conn, "Kers");
-    insert_vlaai(&conn, "Appel");
-    insert_vlaai(&conn, "Rijst");
-    insert_vlaai(&conn, "Kruimelpudding");
-
-    let mut rdr = csv::Reader::from_path("./orders.csv").expect("Could not open reader");
-
-    for result in rdr.deserialize() {
-        let record: CSVRecord = result.expect("could not decode result");
+/// Bring the database up to date and import the form into it
+fn load(args: &LoadArgs, conn: &SqliteConnection, out: &mut dyn Write) -> anyhow::Result<()> {
+    db::run_migrations(conn).context("Could not run migrations")?;
 
-        // Skip these because they are not orders
-        if record.naam.is_empty() || record.naam.starts_with("Totaal") {
-            continue;
+    let aliases = match &args.aliases {
+        Some(path) => Aliases::from_file(path)?,
+        None if Path::new(import::DEFAULT_ALIASES_FILE).exists() => {
+            Aliases::from_file(Path::new(import::DEFAULT_ALIASES_FILE))?
         }
-
-        insert_customer(
-            &conn,
-            &record.naam,
-            &record.email.clone().unwrap_or_default(),
+        None => Aliases::default(),
+    };
+    let catalogue = db::all_vlaaien(conn, false)?;
+    let mut form = import::read_file(&args.input, args.sheet.as_deref(), &catalogue, &aliases)?;
+    form.check(conn)?;
+
+    for (header, column) in form.layout.columns() {
+        writeln!(out, "Column '{}': {}", header, column)?;
+    }
+    match args.format {
+        Format::Text => write!(out, "{}", form.report())?,
+        Format::Json => println!("{}", serde_json::to_string_pretty(&form.report())?),
+    }
+    if args.strict && !form.problems.is_empty() {
+        anyhow::bail!(
+            "The form has {} problems, nothing has been imported",
+            form.problems.len()
         );
-
-        insert_order(&conn, false, None, &record.naam, &record_to_vlaai(&record));
-
-        println!("Inserted {:?}", record);
     }
+
+    let plan = import::import(conn, &form.records, &form.problems, args.dry_run)?;
+    write!(out, "{}", plan)?;
+    Ok(())
 }
@@ -1,159 +1,877 @@
+use notivlaai_lib::config::{Args, Config};
 use notivlaai_lib::db;
 use notivlaai_lib::{
-    status_updater::{DBBackend, OrderStatusUpdater, UpdateOrder},
+    api_error::{self, ApiError},
+    idempotency::{Begin, IdempotencyKeys, StoredResponse},
+    search,
+    shutdown::{self, Shutdown, TaskGuard},
+    status_updater::{
+        DBBackend, OrderPublish, OrderStatusUpdater, OrderSubscriber, UpdateError, UpdateOrder,
+    },
     ws_updater,
 };
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
+use std::time::Duration;
+use structopt::StructOpt;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
 use warp::http::StatusCode;
 use warp::Filter;
 
-/// Use the environment variable for static files, otherwise assume it is the project dir
-pub fn static_file_location() -> String {
-    dotenv::var("STATIC_FILES")
-        .unwrap_or_else(|_| concat!(env!("CARGO_MANIFEST_DIR"), "/static").to_string())
-}
-
 /// Couples a sender to add to a filter
 fn with_sender(
     sender: Sender<UpdateOrder>,
 ) -> impl Filter<Extract = (Sender<UpdateOrder>,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || sender.clone())
 }
-/// Couples a sender to add to a filter
+/// Couples a connection from the database to add to a filter
 fn with_conn(
-) -> impl Filter<Extract = (db::PooledConnection,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || db::establish_connection(false))
+    database: db::Database,
+) -> impl Filter<Extract = (db::PooledConnection,), Error = warp::Rejection> + Clone {
+    warp::any().and_then(move || {
+        let conn = database
+            .conn()
+            .map_err(|e| warp::reject::custom(ApiError::from(e)));
+        async move { conn }
+    })
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-enum UpdateResponse {
-    OK,
+fn stored_reply(response: StoredResponse) -> warp::reply::WithStatus<warp::reply::Json> {
+    let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    warp::reply::with_status(warp::reply::json(&response.body), status)
+}
+
+/// Send an update to the status updater and wait until it has been processed
+async fn run_update(
+    mut sender: Sender<UpdateOrder>,
+    update: UpdateOrder,
+    response: oneshot::Receiver<Result<db::PendingOrder, UpdateError>>,
+) -> Result<db::PendingOrder, ApiError> {
+    if sender.send(update).await.is_err() {
+        return Err(ApiError::Internal(
+            "Order updates are not being processed".to_string(),
+        ));
+    }
+    match response.await {
+        Ok(result) => result.map_err(ApiError::from),
+        // The runner dropped the update without answering
+        Err(_) => Err(ApiError::Internal(
+            "The order update was not processed".to_string(),
+        )),
+    }
+}
+
+/// Send an update to the status updater and wait until it has been processed, the response
+/// contains the updated order or the reason it failed
+async fn process_update(
+    sender: Sender<UpdateOrder>,
+    update: UpdateOrder,
+    response: oneshot::Receiver<Result<db::PendingOrder, UpdateError>>,
+) -> StoredResponse {
+    match run_update(sender, update, response).await {
+        Ok(order) => StoredResponse {
+            status: StatusCode::OK.as_u16(),
+            body: serde_json::to_value(&order).expect("Could not convert order to json"),
+        },
+        Err(e) => StoredResponse::from(&e),
+    }
+}
+
+/// Send an update and reply with the outcome
+async fn send_update(
+    sender: Sender<UpdateOrder>,
+    update: UpdateOrder,
+    response: oneshot::Receiver<Result<db::PendingOrder, UpdateError>>,
+) -> Result<warp::reply::WithStatus<warp::reply::Json>, std::convert::Infallible> {
+    Ok(stored_reply(process_update(sender, update, response).await))
+}
+
+/// Body of PATCH /orders/:order_id
+#[derive(Deserialize)]
+struct StatusChange {
+    status: db::OrderStatus,
+}
+
+/// Give an order another status. A request with an idempotency key is processed only once,
+/// retries get the response of the first request
+async fn change_status(
+    id: u32,
+    change: StatusChange,
+    operator: Option<String>,
+    key: Option<String>,
+    sender: Sender<UpdateOrder>,
+    keys: IdempotencyKeys,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    log::info!("PATCH order {} to {}", id, change.status);
+
+    if let Some(key) = &key {
+        match keys.begin(key, &format!("PATCH /orders/{} {}", id, change.status)) {
+            Begin::New => {}
+            Begin::Done(response) => return Ok(stored_reply(response)),
+            Begin::InProgress => {
+                return Ok(stored_reply(StoredResponse::from(&ApiError::Conflict(
+                    "A request with this idempotency key is still being processed".to_string(),
+                ))))
+            }
+            Begin::Mismatch => {
+                return Ok(stored_reply(StoredResponse::from(&ApiError::Request(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "This idempotency key was used for another request".to_string(),
+                ))))
+            }
+        }
+    }
+
+    let (reply, response) = oneshot::channel();
+    let update = UpdateOrder::ChangeStatus {
+        id,
+        status: change.status,
+        operator,
+        reply: Some(reply),
+    };
+    // Keep going when the client disconnects, so a retry finds the outcome
+    let outcome = tokio::spawn(async move {
+        let outcome = process_update(sender, update, response).await;
+        if let Some(key) = &key {
+            keys.finish(key, outcome.clone());
+        }
+        outcome
+    })
+    .await
+    .unwrap_or_else(|e| StoredResponse::from(&ApiError::Internal(e.to_string())));
+    Ok(stored_reply(outcome))
 }
 
 /// Updating an order
 async fn order_retrieved(
     id: u32,
-    mut sender: Sender<UpdateOrder>,
+    operator: Option<String>,
+    sender: Sender<UpdateOrder>,
 ) -> Result<impl warp::Reply, std::convert::Infallible> {
     log::info!("GET order_retrieved");
 
-    // Try to send a message to the status updater that the order has been retrieved
-    if sender.send(UpdateOrder::OrderRetrieved(id)).await.is_err() {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&"".to_string()),
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ))
-    } else {
-        // The request has been completed succesfully
-        Ok(warp::reply::with_status(
-            warp::reply::json(&"".to_string()),
-            StatusCode::OK,
-        ))
-    }
+    // Tell the status updater that the order has been retrieved and wait for it
+    let (reply, response) = oneshot::channel();
+    let update = UpdateOrder::OrderRetrieved {
+        id,
+        operator,
+        reply: Some(reply),
+    };
+    send_update(sender, update, response).await
 }
 
 /// Updating an order
 async fn order_in_transit(
     id: u32,
-    mut sender: Sender<UpdateOrder>,
+    operator: Option<String>,
+    sender: Sender<UpdateOrder>,
 ) -> Result<impl warp::Reply, std::convert::Infallible> {
     log::info!("GET order_in_transit");
 
-    // Try to send a message to the status updater that the order has been retrieved
-    if sender.send(UpdateOrder::OrderInTransit(id)).await.is_err() {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&"".to_string()),
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ))
-    } else {
-        // The request has been completed succesfully
-        Ok(warp::reply::with_status(
-            warp::reply::json(&"".to_string()),
-            StatusCode::OK,
-        ))
-    }
+    // Tell the status updater that the order is in transit and wait for it
+    let (reply, response) = oneshot::channel();
+    let update = UpdateOrder::OrderInTransit {
+        id,
+        operator,
+        reply: Some(reply),
+    };
+    send_update(sender, update, response).await
 }
 
-fn find_client(name: String, conn: db::PooledConnection) -> impl warp::Reply {
-    // Find the cutomer with like function, trim the string and replace the
-    // %20 space escaped
-    let customer = db::customer_with_name(
-        &conn,
-        format!("%{}%", name.trim_start().trim_end().replace("%20", " ")),
-    )
-    .expect("Could not retrieve customer");
-    let names: Vec<(i32, String)> = customer.iter().map(|c| (c.id, c.name.clone())).collect();
+/// The ids and names of all customers matching the name, best matches first. This is the
+/// response of the deprecated GET /customer/find/:name, see [`search_customers`] for a page
+async fn find_client(
+    name: String,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // The name is still percent encoded when it comes from the path
+    let name = percent_encoding::percent_decode_str(&name).decode_utf8_lossy();
+    let customers = search::customers(&conn, &name, search::SearchFields::default())
+        .map_err(|e| ApiError::from_anyhow(e, "Customer"))?;
+    let names: Vec<(i32, String)> = customers.into_iter().map(|(_, c)| (c.id, c.name)).collect();
     Ok(warp::reply::json(&names))
 }
 
-fn find_order(id: u32, conn: db::PooledConnection) -> impl warp::Reply {
-    let orders =
-        db::orders_for_customer(&conn, id as i32).expect("Could not retrieve orders for customer");
-    let orders: Vec<db::PendingOrder> = orders
+/// Query of GET /customers/search
+#[derive(Deserialize)]
+struct CustomerSearchQuery {
+    q: String,
+    /// Search the email addresses as well
+    email: Option<bool>,
+    /// Search the names of the sections as well
+    speltak: Option<bool>,
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+async fn search_customers(
+    query: CustomerSearchQuery,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let fields = search::SearchFields {
+        email: query.email.unwrap_or(false),
+        speltak: query.speltak.unwrap_or(false),
+    };
+    let customers = search::customers(&conn, &query.q, fields)
+        .map_err(|e| ApiError::from_anyhow(e, "Customer"))?;
+    let names: Vec<_> = customers
         .into_iter()
-        .filter_map(|o| db::to_pending(&conn, o).ok())
+        .map(|(key, c)| (key, (c.id, c.name)))
         .collect();
+    let page = db::PageQuery {
+        cursor: query.cursor,
+        limit: query.limit,
+    };
+    let names = search::page_after(names, &page)
+        .ok_or_else(|| ApiError::Validation("Invalid cursor".to_string()))?;
+    Ok(warp::reply::json(&names))
+}
+
+/// Query of GET /orders/search
+#[derive(Deserialize)]
+struct OrderSearchQuery {
+    q: String,
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+async fn search_orders(
+    query: OrderSearchQuery,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let page = db::PageQuery {
+        cursor: query.cursor,
+        limit: query.limit,
+    };
+    let found = search::orders(&conn, &query.q, &page)
+        .map_err(|e| ApiError::from_anyhow(e, "Order"))?
+        .ok_or_else(|| ApiError::Validation("Invalid cursor".to_string()))?;
+    Ok(warp::reply::json(&found))
+}
+
+async fn list_orders(
+    query: db::OrderQuery,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let orders = db::list_orders(&conn, &query).map_err(ApiError::from)?;
     Ok(warp::reply::json(&orders))
 }
 
-/// GET /client/find/:name
-fn find_client_filter() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
-{
+async fn find_order(
+    id: u32,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let orders = db::orders_for_customer(&conn, id as i32)
+        .map_err(|e| ApiError::from_anyhow(e, format!("Customer {}", id)))?;
+    let orders = orders
+        .into_iter()
+        .map(|o| db::to_pending(&conn, o))
+        .collect::<anyhow::Result<Vec<db::PendingOrder>>>()
+        .map_err(|e| ApiError::from_anyhow(e, "Order"))?;
+    Ok(warp::reply::json(&orders))
+}
+
+async fn order_history(
+    id: u32,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let history = db::order_history(&conn, id as i32)
+        .map_err(|e| ApiError::from_anyhow(e, format!("Order {}", id)))?;
+    Ok(warp::reply::json(&history))
+}
+
+/// Body of POST /customers and PUT /customers/:customer_id
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CustomerChange {
+    name: String,
+    email: Option<String>,
+    section_id: Option<i32>,
+}
+
+impl CustomerChange {
+    fn as_new(&self) -> db::NewCustomer<'_> {
+        db::NewCustomer {
+            name: &self.name,
+            email: self.email.as_deref(),
+            section_id: self.section_id,
+        }
+    }
+}
+
+/// Query of GET /customers
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CustomerQuery {
+    section_id: Option<i32>,
+}
+
+async fn list_customers(
+    query: CustomerQuery,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let customers = match query.section_id {
+        Some(section_id) => {
+            db::get_section(&conn, section_id).map_err(ApiError::from)?;
+            db::customers_in_section(&conn, section_id)
+        }
+        None => db::all_customers(&conn),
+    }
+    .map_err(|e| ApiError::from_anyhow(e, "Customer"))?;
+    Ok(warp::reply::json(&customers))
+}
+
+async fn get_customer(
+    id: u32,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let customer = db::get_customer(&conn, id as i32).map_err(ApiError::from)?;
+    Ok(warp::reply::json(&customer))
+}
+
+async fn create_customer(
+    change: CustomerChange,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let customer = db::create_customer(&conn, change.as_new()).map_err(ApiError::from)?;
+    log::info!("Created customer {}", customer.id);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&customer),
+        StatusCode::CREATED,
+    ))
+}
+
+async fn update_customer(
+    id: u32,
+    change: CustomerChange,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let customer =
+        db::update_customer(&conn, id as i32, change.as_new()).map_err(ApiError::from)?;
+    Ok(warp::reply::json(&customer))
+}
+
+async fn delete_customer(
+    id: u32,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    db::delete_customer(&conn, id as i32).map_err(ApiError::from)?;
+    log::info!("Deleted customer {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Query of GET /vlaaien
+#[derive(Deserialize)]
+struct CatalogueQuery {
+    active: Option<bool>,
+}
+
+async fn list_vlaaien(
+    query: CatalogueQuery,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let vlaaien = db::all_vlaaien(&conn, query.active.unwrap_or(false))
+        .map_err(|e| ApiError::from_anyhow(e, "Vlaai"))?;
+    Ok(warp::reply::json(&vlaaien))
+}
+
+async fn get_vlaai(
+    id: u32,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let vlaai = db::get_vlaai(&conn, id as i32).map_err(ApiError::from)?;
+    Ok(warp::reply::json(&vlaai))
+}
+
+async fn create_vlaai(
+    change: db::VlaaiChange,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let vlaai = db::create_vlaai(&conn, &change).map_err(ApiError::from)?;
+    log::info!("Added vlaai {} to the catalogue", vlaai.name);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&vlaai),
+        StatusCode::CREATED,
+    ))
+}
+
+async fn update_vlaai(
+    id: u32,
+    change: db::VlaaiChange,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let vlaai = db::update_vlaai(&conn, id as i32, &change).map_err(ApiError::from)?;
+    Ok(warp::reply::json(&vlaai))
+}
+
+/// Body of POST /sections
+#[derive(Deserialize)]
+struct SectionChange {
+    name: String,
+}
+
+async fn list_sections(conn: db::PooledConnection) -> Result<impl warp::Reply, warp::Rejection> {
+    let sections = db::all_sections(&conn).map_err(|e| ApiError::from_anyhow(e, "Section"))?;
+    Ok(warp::reply::json(&sections))
+}
+
+async fn create_section(
+    change: SectionChange,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let section = db::create_section(&conn, &change.name).map_err(ApiError::from)?;
+    log::info!("Added section {}", section.name);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&section),
+        StatusCode::CREATED,
+    ))
+}
+
+async fn section_report(conn: db::PooledConnection) -> Result<impl warp::Reply, warp::Rejection> {
+    let report = db::section_report(&conn).map_err(|e| ApiError::from_anyhow(e, "Section"))?;
+    Ok(warp::reply::json(&report))
+}
+
+/// Body of POST /orders
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewOrder {
+    customer_id: u32,
+    rows: Vec<db::RowChange>,
+}
+
+async fn create_order(
+    order: NewOrder,
+    operator: Option<String>,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let order = db::create_order(
+        &conn,
+        order.customer_id as i32,
+        &order.rows,
+        operator.as_deref(),
+    )
+    .map_err(ApiError::from)?;
+    log::info!("Created order {}", order.id);
+    let pending = db::to_pending(&conn, order).map_err(|e| ApiError::from_anyhow(e, "Order"))?;
+    // New orders are not on the screen yet, so there is nothing to publish
+    Ok(warp::reply::with_status(
+        warp::reply::json(&pending),
+        StatusCode::CREATED,
+    ))
+}
+
+async fn change_rows(
+    id: u32,
+    rows: Vec<db::RowChange>,
+    conn: db::PooledConnection,
+    subscriber: OrderSubscriber,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let order = db::set_order_rows(&conn, id as i32, &rows).map_err(ApiError::from)?;
+    let pending = db::to_pending(&conn, order).map_err(|e| ApiError::from_anyhow(e, "Order"))?;
+    // Show the new vlaaien if the order is on the screen
+    if pending.status.is_pending() {
+        subscriber.publish(OrderPublish::AddOrder(pending.clone()));
+    }
+    Ok(warp::reply::json(&pending))
+}
+
+async fn delete_order(
+    id: u32,
+    conn: db::PooledConnection,
+    subscriber: OrderSubscriber,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let order = db::delete_order(&conn, id as i32).map_err(ApiError::from)?;
+    log::info!("Deleted order {}", id);
+    if order.status.is_pending() {
+        subscriber.publish(OrderPublish::RemoveOrder(id));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Body of POST /orders/:order_id/payments
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewPayment {
+    amount_cents: i32,
+    method: db::PaymentMethod,
+}
+
+async fn add_payment(
+    id: u32,
+    payment: NewPayment,
+    operator: Option<String>,
+    conn: db::PooledConnection,
+    subscriber: OrderSubscriber,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let payment = db::add_payment(
+        &conn,
+        id as i32,
+        payment.amount_cents,
+        payment.method,
+        operator.as_deref(),
+    )
+    .map_err(ApiError::from)?;
+    log::info!(
+        "Received {} cents for order {} by {}",
+        payment.amount_cents,
+        id,
+        payment.method
+    );
+    // Show the new balance if the order is on the screen
+    let pending = db::pending_order(&conn, id as i32)
+        .map_err(|e| ApiError::from_anyhow(e, format!("Order {}", id)))?;
+    if pending.status.is_pending() {
+        subscriber.publish(OrderPublish::AddOrder(pending));
+    }
+    Ok(warp::reply::with_status(
+        warp::reply::json(&payment),
+        StatusCode::CREATED,
+    ))
+}
+
+async fn order_payments(
+    id: u32,
+    conn: db::PooledConnection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let payments = db::order_payments(&conn, id as i32)
+        .map_err(|e| ApiError::from_anyhow(e, format!("Order {}", id)))?;
+    Ok(warp::reply::json(&payments))
+}
+
+/// Couples the order subscriber to add to a filter, used to publish changes to orders
+fn with_subscriber(
+    subscriber: OrderSubscriber,
+) -> impl Filter<Extract = (OrderSubscriber,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || subscriber.clone())
+}
+
+/// Couples the idempotency keys to add to a filter
+fn with_keys(
+    keys: IdempotencyKeys,
+) -> impl Filter<Extract = (IdempotencyKeys,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || keys.clone())
+}
+
+/// The `Idempotency-Key` header, if there is one
+fn with_idempotency_key(
+) -> impl Filter<Extract = (Option<String>,), Error = std::convert::Infallible> + Clone {
+    warp::header::optional::<String>("idempotency-key")
+        .or(warp::any().map(|| None))
+        .unify()
+}
+
+/// Name of the person making a change, recorded in the order history
+fn with_operator(
+) -> impl Filter<Extract = (Option<String>,), Error = std::convert::Infallible> + Clone {
+    warp::header::optional::<String>("x-operator")
+        .or(warp::any().map(|| None))
+        .unify()
+}
+
+/// GET /customer/find/:name
+fn find_client_filter(
+    database: db::Database,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("customer" / "find" / String)
-        .and(with_conn())
-        .map(find_client)
+        .and(with_conn(database))
+        .and_then(find_client)
+}
+
+/// GET /customers/search?q=, paged like the other lists
+fn search_customers_filter(
+    database: db::Database,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("customers" / "search"))
+        .and(warp::query())
+        .and(with_conn(database))
+        .and_then(search_customers)
+}
+
+/// GET, POST, PUT and DELETE on /customers and /customers/:customer_id
+fn customers_filter(
+    database: db::Database,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path!("customers"))
+        .and(warp::query())
+        .and(with_conn(database.clone()))
+        .and_then(list_customers);
+    let get = warp::get()
+        .and(warp::path!("customers" / u32))
+        .and(with_conn(database.clone()))
+        .and_then(get_customer);
+    let create = warp::post()
+        .and(warp::path!("customers"))
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_conn(database.clone()))
+        .and_then(create_customer);
+    let update = warp::put()
+        .and(warp::path!("customers" / u32))
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_conn(database.clone()))
+        .and_then(update_customer);
+    let delete = warp::delete()
+        .and(warp::path!("customers" / u32))
+        .and(with_conn(database))
+        .and_then(delete_customer);
+    list.or(get).or(create).or(update).or(delete)
+}
+
+/// GET and POST on /sections, GET on /sections/report
+fn sections_filter(
+    database: db::Database,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path!("sections"))
+        .and(with_conn(database.clone()))
+        .and_then(list_sections);
+    let create = warp::post()
+        .and(warp::path!("sections"))
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_conn(database.clone()))
+        .and_then(create_section);
+    let report = warp::get()
+        .and(warp::path!("sections" / "report"))
+        .and(with_conn(database))
+        .and_then(section_report);
+    list.or(create).or(report)
+}
+
+/// GET and POST on /vlaaien, GET and PUT on /vlaaien/:vlaai_id
+fn vlaaien_filter(
+    database: db::Database,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path!("vlaaien"))
+        .and(warp::query())
+        .and(with_conn(database.clone()))
+        .and_then(list_vlaaien);
+    let get = warp::get()
+        .and(warp::path!("vlaaien" / u32))
+        .and(with_conn(database.clone()))
+        .and_then(get_vlaai);
+    let create = warp::post()
+        .and(warp::path!("vlaaien"))
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::json())
+        .and(with_conn(database.clone()))
+        .and_then(create_vlaai);
+    let update = warp::put()
+        .and(warp::path!("vlaaien" / u32))
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::json())
+        .and(with_conn(database))
+        .and_then(update_vlaai);
+    list.or(get).or(create).or(update)
+}
+
+/// POST and GET on /orders/:order_id/payments
+fn payments_filter(
+    database: db::Database,
+    subscriber: OrderSubscriber,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let add = warp::post()
+        .and(warp::path!("orders" / u32 / "payments"))
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_operator())
+        .and(with_conn(database.clone()))
+        .and(with_subscriber(subscriber))
+        .and_then(add_payment);
+    let list = warp::get()
+        .and(warp::path!("orders" / u32 / "payments"))
+        .and(with_conn(database))
+        .and_then(order_payments);
+    add.or(list)
+}
+
+/// GET /orders/search?q=, finds orders by order number, order id, email, name or vlaai.
+/// `/search` itself is the search page of the client
+fn search_filter(
+    database: db::Database,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("orders" / "search"))
+        .and(warp::query())
+        .and(with_conn(database))
+        .and_then(search_orders)
+}
+
+/// GET /orders, filtered, sorted and paged with the query, e.g.
+/// `?status=inTransit&sort=customer&limit=20`
+fn list_orders_filter(
+    database: db::Database,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("orders"))
+        .and(warp::query())
+        .and(with_conn(database))
+        .and_then(list_orders)
+}
+
+/// POST /orders, PUT /orders/:order_id/rows and DELETE /orders/:order_id
+fn edit_orders_filter(
+    database: db::Database,
+    subscriber: OrderSubscriber,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let create = warp::post()
+        .and(warp::path!("orders"))
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::json())
+        .and(with_operator())
+        .and(with_conn(database.clone()))
+        .and_then(create_order);
+    let rows = warp::put()
+        .and(warp::path!("orders" / u32 / "rows"))
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::json())
+        .and(with_conn(database.clone()))
+        .and(with_subscriber(subscriber.clone()))
+        .and_then(change_rows);
+    let delete = warp::delete()
+        .and(warp::path!("orders" / u32))
+        .and(with_conn(database))
+        .and(with_subscriber(subscriber))
+        .and_then(delete_order);
+    create.or(rows).or(delete)
 }
 
 /// GET /order/find/:customer_id
-fn find_order_filter() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+fn find_order_filter(
+    database: db::Database,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("order" / "find" / u32)
-        .and(with_conn())
-        .map(find_order)
+        .and(with_conn(database))
+        .and_then(find_order)
 }
 
-/// GET /order/retrieved/:order_id
+/// GET /order/retrieved/:order_id, deprecated in favour of PATCH /orders/:order_id
 fn update_filter(
     sender: Sender<UpdateOrder>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("order" / "retrieved" / u32)
+        .and(with_operator())
         .and(with_sender(sender))
         .and_then(order_retrieved)
 }
 
-/// GET /order/in_transit/:order_id
+/// GET /order/in_transit/:order_id, deprecated in favour of PATCH /orders/:order_id
 fn in_transit_filter(
     sender: Sender<UpdateOrder>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("order" / "in_transit" / u32)
+        .and(with_operator())
         .and(with_sender(sender))
         .and_then(order_in_transit)
 }
 
-async fn warp_main(sender: Sender<UpdateOrder>) {
-    let static_files = warp::fs::dir("static");
+/// PATCH /orders/:order_id, with a body like `{"status": "inTransit"}`
+fn change_status_filter(
+    sender: Sender<UpdateOrder>,
+    keys: IdempotencyKeys,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::patch()
+        .and(warp::path!("orders" / u32))
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_operator())
+        .and(with_idempotency_key())
+        .and(with_sender(sender))
+        .and(with_keys(keys))
+        .and_then(change_status)
+}
+
+/// The deprecated GET routes, which change orders on a GET that browsers and proxies may
+/// repeat, and the customer search that answers with all results instead of a page. These
+/// are only served when `enabled`
+fn legacy_filter(
+    enabled: bool,
+    sender: Sender<UpdateOrder>,
+    database: db::Database,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+        .and(
+            update_filter(sender.clone())
+                .or(in_transit_filter(sender))
+                .or(find_client_filter(database)),
+        )
+        .map(|reply| warp::reply::with_header(reply, "deprecation", "true"))
+}
 
-    let addr = if dotenv::var("MODE").expect("Could not find MODE in .env file") == "dev" {
-        ([127, 0, 0, 1], 3030)
-    } else {
-        ([0, 0, 0, 0], 3030)
-    };
-    warp::serve(
-        update_filter(sender.clone())
-            .or(find_client_filter())
-            .or(find_order_filter())
-            .or(in_transit_filter(sender))
-            .or(warp::path("search").and(warp::fs::file("./static/index.html")))
-            .or(static_files),
-    )
-    .run(addr)
-    .await;
+/// GET /order/:order_id/history
+fn order_history_filter(
+    database: db::Database,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("order" / u32 / "history")
+        .and(with_conn(database))
+        .and_then(order_history)
 }
 
-fn main() {
-    // Load environment file
-    dotenv::dotenv().ok();
+/// How long websocket clients get to receive their close frame when stopping
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve the HTTP server until `shutdown` is triggered and all requests have been handled
+async fn warp_main(
+    config: Config,
+    sender: Sender<UpdateOrder>,
+    subscriber: OrderSubscriber,
+    database: db::Database,
+    mut shutdown: Shutdown,
+    closing: Shutdown,
+    tasks: TaskGuard,
+) {
+    let static_files = warp::fs::dir(config.static_files.clone());
+    let index = warp::fs::file(config.static_files.join("index.html"));
+
+    if config.legacy_get_routes {
+        log::warn!("Serving the deprecated GET routes that change orders");
+    }
+    let routes = change_status_filter(sender.clone(), IdempotencyKeys::default())
+        .or(legacy_filter(
+            config.legacy_get_routes,
+            sender,
+            database.clone(),
+        ))
+        .or(list_orders_filter(database.clone()))
+        .or(search_filter(database.clone()))
+        .or(search_customers_filter(database.clone()))
+        .or(edit_orders_filter(database.clone(), subscriber.clone()))
+        .or(payments_filter(database.clone(), subscriber.clone()))
+        .or(customers_filter(database.clone()))
+        .or(vlaaien_filter(database.clone()))
+        .or(sections_filter(database.clone()))
+        .or(find_order_filter(database.clone()))
+        .or(order_history_filter(database.clone()))
+        .or(ws_updater::orders_filter(
+            subscriber, database, closing, tasks,
+        ))
+        .or(warp::path("search").and(index))
+        .or(static_files)
+        .recover(api_error::recover);
+
+    let (addr, server) = warp::serve(routes)
+        .bind_with_graceful_shutdown(config.http_addr(), async move { shutdown.wait().await });
+    log::info!("Listening on: {}", addr);
+    server.await;
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::from_args();
+    // Load environment file and configuration
+    let config = Config::load(&args)?;
 
     // Set info logging as standard if it is not there
     if std::env::var_os("RUST_LOG").is_none() {
@@ -163,41 +881,113 @@ fn main() {
     // Initialize the logger
     pretty_env_logger::init();
 
+    // The database is shared by all parts of the server
+    let database = db::Database::from_config(&config)?;
+    // Bring the database up to date, the committed one has none of the newer tables
+    db::run_migrations(&*database.conn()?)?;
+
     let mut runtime = tokio::runtime::Runtime::new().expect("Could not construct runtime");
-    let (sender, receiver) = tokio::sync::mpsc::channel(100);
+    let (sender, receiver) = tokio::sync::mpsc::channel(config.update_queue_capacity);
 
     // This handles the updating of orders when this is requested by the clien
-    let order_status_updater = OrderStatusUpdater::<DBBackend>::new(receiver);
-    let handler = ws_updater::WsUpdater::new(9001);
+    let order_status_updater = OrderStatusUpdater::with_capacity(
+        receiver,
+        DBBackend::new(&database)?,
+        config.broadcast_capacity,
+    );
 
     // Tokio runtime
     runtime.block_on(async {
         let (subscriber, runner) = order_status_updater.order_mutator();
-        // Run the web-client
-        tokio::spawn(async { warp_main(sender).await });
+        let (trigger, shutdown) = shutdown::channel();
+        let (close_clients, closing) = shutdown::channel();
+        let (tasks, clients_closed) = shutdown::tasks();
+
+        // Wait for new updates, with a fresh connection if processing one crashes
+        let restart_database = database.clone();
+        let runner = tokio::spawn(async move {
+            runner
+                .supervise(|| Ok(DBBackend::new(&restart_database)?))
+                .await
+        });
+
+        // Run the websocket handler on its own port as well, if requested
+        if config.standalone_ws {
+            let handler = ws_updater::WsUpdater::new(config.ws_addr(), database.clone());
+            tokio::spawn(handler.start(
+                subscriber.clone(),
+                shutdown.clone(),
+                closing.clone(),
+                tasks.clone(),
+            ));
+        }
+
+        tokio::spawn(async move {
+            shutdown::signal().await;
+            log::info!("Shutting down, no longer accepting connections");
+            trigger.shutdown();
+        });
+
+        // Run the web-client until we are asked to stop
+        warp_main(
+            config, sender, subscriber, database, shutdown, closing, tasks,
+        )
+        .await;
 
-        // Run the websocket handler
-        handler.start(subscriber, runner).await
+        // Nobody can send updates anymore, process the ones that are still queued
+        match runner.await {
+            Ok(()) => log::info!("Processed all queued order updates"),
+            Err(e) => log::error!("Order updates stopped unexpectedly: {}", e),
+        }
+
+        // Every change has been published, tell the websocket clients we are going away
+        close_clients.shutdown();
+        if tokio::time::timeout(CLOSE_TIMEOUT, clients_closed.wait())
+            .await
+            .is_err()
+        {
+            log::warn!("Not all websocket clients were closed in time");
+        }
     });
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use notivlaai_lib::status_updater::{OrderPublish, OrderStatusUpdater, TestBackend};
+    use notivlaai_lib::api_error;
+    use notivlaai_lib::db;
+    use notivlaai_lib::idempotency::IdempotencyKeys;
+    use notivlaai_lib::status_updater::{
+        OrderPublish, OrderStatusUpdater, TestBackend, UpdateOrder,
+    };
+    use notivlaai_lib::test_support::TestDatabase;
     use warp::http::StatusCode;
     use warp::test::request;
+    use warp::Filter;
 
     #[tokio::test]
     async fn test_api() {
-        let (sender, receiver) = tokio::sync::mpsc::channel(100);
+        let (mut sender, receiver) = tokio::sync::mpsc::channel(100);
         let order_status_updater = OrderStatusUpdater::<TestBackend>::new(receiver);
-        let update = super::update_filter(sender);
+        let update = super::update_filter(sender.clone());
 
         // Get a subscriber and a runner
         let (subscriber, runner) = order_status_updater.order_mutator();
 
         // Process order updates
         tokio::spawn(async { runner.run().await });
+
+        // Only orders that have been sent can be retrieved
+        let (reply, response) = tokio::sync::oneshot::channel();
+        sender
+            .send(UpdateOrder::OrderInTransit {
+                id: 1,
+                operator: None,
+                reply: Some(reply),
+            })
+            .await
+            .unwrap();
+        response.await.unwrap().unwrap();
         let mut sub = subscriber.subscribe();
 
         // We should be able to send a request
@@ -207,8 +997,11 @@ mod tests {
             .reply(&update)
             .await;
 
-        // The request should return an OK
+        // The request should return an OK, with the updated order
         assert_eq!(resp.status(), StatusCode::OK);
+        let order: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(order["id"], 1);
+        assert_eq!(order["status"], "pickedUp");
 
         // And the subscriber should receieve the updated message
         let message = sub.recv().await;
@@ -216,6 +1009,167 @@ mod tests {
         assert_eq!(message.unwrap(), OrderPublish::RemoveOrder(1));
     }
 
+    #[tokio::test]
+    async fn test_api_failed_updates() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(100);
+        let (_subscriber, runner) =
+            OrderStatusUpdater::<TestBackend>::new(receiver).order_mutator();
+        tokio::spawn(async { runner.run().await });
+        let update = super::update_filter(sender.clone()).or(super::in_transit_filter(sender));
+
+        // There is no such order
+        let resp = request()
+            .method("GET")
+            .path("/order/in_transit/42")
+            .reply(&update)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let error: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(error["error"], "Order 42 does not exist");
+
+        // The order has not been sent yet, so it cannot be retrieved
+        let resp = request()
+            .method("GET")
+            .path("/order/retrieved/1")
+            .reply(&update)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let error: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert!(error["error"].is_string());
+    }
+
+    /// Request to change the status of order 1
+    fn patch_order(status: &str) -> warp::test::RequestBuilder {
+        request()
+            .method("PATCH")
+            .path("/orders/1")
+            .header("content-type", "application/json")
+            .body(format!("{{\"status\": \"{}\"}}", status))
+    }
+
+    #[tokio::test]
+    async fn test_change_status() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(100);
+        let (subscriber, runner) = OrderStatusUpdater::<TestBackend>::new(receiver).order_mutator();
+        tokio::spawn(async { runner.run().await });
+        let mut sub = subscriber.subscribe();
+        let change = super::change_status_filter(sender, IdempotencyKeys::default());
+
+        let resp = patch_order("inTransit").reply(&change).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let order: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(order["status"], "inTransit");
+        assert!(matches!(
+            sub.recv().await.unwrap(),
+            OrderPublish::AddOrder(_)
+        ));
+
+        let resp = patch_order("ready").reply(&change).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(
+            matches!(sub.recv().await.unwrap(), OrderPublish::AddOrder(o) if o.status == db::OrderStatus::Ready)
+        );
+
+        let resp = patch_order("pickedUp").reply(&change).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(sub.recv().await.unwrap(), OrderPublish::RemoveOrder(1));
+
+        // Not a status
+        let resp = patch_order("lost").reply(&change).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Only PATCH changes orders
+        let resp = request()
+            .method("GET")
+            .path("/orders/1")
+            .reply(&change)
+            .await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_change_status_idempotency_key() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(100);
+        let (_subscriber, runner) =
+            OrderStatusUpdater::<TestBackend>::new(receiver).order_mutator();
+        tokio::spawn(async { runner.run().await });
+        let change = super::change_status_filter(sender, IdempotencyKeys::default());
+
+        let first = patch_order("inTransit")
+            .header("idempotency-key", "dispatch-1")
+            .reply(&change)
+            .await;
+        assert_eq!(first.status(), StatusCode::OK);
+
+        // Sending the order again would be a conflict, the retry gets the first response
+        let retry = patch_order("inTransit")
+            .header("idempotency-key", "dispatch-1")
+            .reply(&change)
+            .await;
+        assert_eq!(retry.status(), StatusCode::OK);
+        assert_eq!(retry.body(), first.body());
+
+        let again = patch_order("inTransit").reply(&change).await;
+        assert_eq!(again.status(), StatusCode::CONFLICT);
+
+        // The key belongs to the first request
+        let other = patch_order("pickedUp")
+            .header("idempotency-key", "dispatch-1")
+            .reply(&change)
+            .await;
+        assert_eq!(other.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_legacy_get_routes() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(100);
+        let (_subscriber, runner) =
+            OrderStatusUpdater::<TestBackend>::new(receiver).order_mutator();
+        tokio::spawn(async { runner.run().await });
+
+        let db = TestDatabase::seeded();
+        let disabled = super::legacy_filter(false, sender.clone(), db.database());
+        let resp = request()
+            .method("GET")
+            .path("/order/in_transit/1")
+            .reply(&disabled)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let enabled = super::legacy_filter(true, sender, db.database());
+        let resp = request()
+            .method("GET")
+            .path("/order/in_transit/1")
+            .reply(&enabled)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["deprecation"], "true");
+
+        // The customer search answers with all customers that match, as it used to
+        let resp = request()
+            .method("GET")
+            .path("/customer/find/P%C3%AFet%20pokrface")
+            .reply(&enabled)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let names: Vec<(i32, String)> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(names, vec![(2, "Piet Pokerface".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn test_api_runner_stopped() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(100);
+        drop(receiver);
+        let update = super::in_transit_filter(sender);
+
+        let resp = request()
+            .method("GET")
+            .path("/order/in_transit/1")
+            .reply(&update)
+            .await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
     #[tokio::test]
     async fn test_api_in_transit() {
         let (sender, receiver) = tokio::sync::mpsc::channel(100);
@@ -245,20 +1199,489 @@ mod tests {
         assert!(matches!(message.unwrap(), OrderPublish::AddOrder(_)));
     }
     #[tokio::test]
-    async fn test_get_client() {
-        let client = super::find_client_filter();
+    async fn test_search_customers() {
+        let db = TestDatabase::seeded();
+        let client = super::search_customers_filter(db.database()).recover(api_error::recover);
+
+        let resp = request()
+            .method("GET")
+            .path("/customers/search?q=e")
+            .reply(&client)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(page["items"][0][1], "Peter Bergmans");
+        assert_eq!(page["nextCursor"], serde_json::Value::Null);
+
+        let resp = request()
+            .method("GET")
+            .path("/customers/search?q=e&limit=1")
+            .reply(&client)
+            .await;
+        let page: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+        let cursor = page["nextCursor"].as_str().unwrap().to_string();
+
+        let resp = request()
+            .method("GET")
+            .path(&format!(
+                "/customers/search?q=e&limit=1&cursor={}",
+                cursor.replace(' ', "%20")
+            ))
+            .reply(&client)
+            .await;
+        let page: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(page["items"][0][1], "Piet Pokerface");
+
+        // Accents, case and a typo do not matter
+        let resp = request()
+            .method("GET")
+            .path("/customers/search?q=P%C3%AFet%20pokrface")
+            .reply(&client)
+            .await;
+        let page: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(page["items"][0][1], "Piet Pokerface");
 
         let resp = request()
             .method("GET")
-            .path("/customer/find/pie")
+            .path("/customers/search?q=pokeren&email=true")
             .reply(&client)
             .await;
+        let page: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(page["items"][0][1], "Piet Pokerface");
+    }
+
+    #[tokio::test]
+    async fn test_search_orders() {
+        let db = TestDatabase::seeded();
+        let search = super::search_filter(db.database()).recover(api_error::recover);
+
+        let resp = request()
+            .method("GET")
+            .path("/orders/search?q=peter%40peter.nl")
+            .reply(&search)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(page["items"][0]["id"], 1);
+        assert_eq!(page["items"][0]["customerName"], "Peter Bergmans");
+        assert_eq!(page["items"][0]["matchReason"], "email");
+
+        let resp = request()
+            .method("GET")
+            .path("/orders/search?q=abrikoos&limit=1")
+            .reply(&search)
+            .await;
+        let page: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(page["items"][0]["matchReason"], "vlaai");
+        assert_eq!(page["nextCursor"], "4:0:1:");
+
+        let resp = request()
+            .method("GET")
+            .path("/orders/search")
+            .reply(&search)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        // The search page of the client is not in the way
+        let resp = request().method("GET").path("/search").reply(&search).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_list_orders() {
+        let db = TestDatabase::seeded();
+        let orders = super::list_orders_filter(db.database()).recover(api_error::recover);
+
+        let resp = request()
+            .method("GET")
+            .path("/orders?status=inTransit&customerId=2")
+            .reply(&orders)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+        assert_eq!(page["items"][0]["customerName"], "Piet Pokerface");
+
+        let resp = request()
+            .method("GET")
+            .path("/orders?sort=customer&limit=1")
+            .reply(&orders)
+            .await;
+        let page: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(page["items"][0]["id"], 1);
+        assert!(page["nextCursor"].is_string());
+
+        let resp = request()
+            .method("GET")
+            .path("/orders?sort=price")
+            .reply(&orders)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_customers() {
+        let db = TestDatabase::seeded();
+        let customers = super::customers_filter(db.database()).recover(api_error::recover);
+
+        let resp = request()
+            .method("POST")
+            .path("/customers")
+            .json(&serde_json::json!({"name": "Anna de Vries", "email": "anna@devries.nl"}))
+            .reply(&customers)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let customer: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(customer["id"], 3);
+
+        // The same name twice is refused
+        let resp = request()
+            .method("POST")
+            .path("/customers")
+            .json(&serde_json::json!({"name": "anna de vries"}))
+            .reply(&customers)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let resp = request()
+            .method("PUT")
+            .path("/customers/3")
+            .json(&serde_json::json!({"name": "Anna Bergmans", "email": null}))
+            .reply(&customers)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = request()
+            .method("GET")
+            .path("/customers/3")
+            .reply(&customers)
+            .await;
+        let customer: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(customer["name"], "Anna Bergmans");
+        assert_eq!(customer["email"], serde_json::Value::Null);
+
+        let resp = request()
+            .method("PUT")
+            .path("/customers/3")
+            .json(&serde_json::json!({"name": ""}))
+            .reply(&customers)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Customer 1 still has an order in transit
+        let resp = request()
+            .method("DELETE")
+            .path("/customers/1")
+            .reply(&customers)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let resp = request()
+            .method("DELETE")
+            .path("/customers/3")
+            .reply(&customers)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let resp = request()
+            .method("GET")
+            .path("/customers")
+            .reply(&customers)
+            .await;
+        let all: Vec<serde_json::Value> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(all.len(), 2);
+
+        let resp = request()
+            .method("GET")
+            .path("/customers/3")
+            .reply(&customers)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_sections() {
+        let db = TestDatabase::seeded();
+        let sections = super::sections_filter(db.database()).recover(api_error::recover);
+        let customers = super::customers_filter(db.database()).recover(api_error::recover);
+
+        let resp = request()
+            .method("POST")
+            .path("/sections")
+            .json(&serde_json::json!({"name": "Welpen"}))
+            .reply(&sections)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = request()
+            .method("POST")
+            .path("/sections")
+            .json(&serde_json::json!({"name": "welpen"}))
+            .reply(&sections)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let resp = request()
+            .method("PUT")
+            .path("/customers/2")
+            .json(&serde_json::json!({"name": "Piet Pokerface", "sectionId": 1}))
+            .reply(&customers)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let customer: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(customer["sectionId"], 1);
+
+        let resp = request()
+            .method("GET")
+            .path("/customers?sectionId=1")
+            .reply(&customers)
+            .await;
+        let in_section: Vec<serde_json::Value> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(in_section.len(), 1);
+        assert_eq!(in_section[0]["name"], "Piet Pokerface");
+        let resp = request()
+            .method("GET")
+            .path("/customers?sectionId=7")
+            .reply(&customers)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = request()
+            .method("GET")
+            .path("/sections/report")
+            .reply(&sections)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let report: Vec<serde_json::Value> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0]["section"]["name"], "Welpen");
+        assert_eq!(report[0]["orders"], 1);
+        assert_eq!(report[1]["section"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_edit_orders() {
+        let db = TestDatabase::seeded();
+        let (_sender, receiver) = tokio::sync::mpsc::channel(1);
+        let (subscriber, _runner) =
+            OrderStatusUpdater::<TestBackend>::new(receiver).order_mutator();
+        let mut published = subscriber.subscribe();
+        let orders =
+            super::edit_orders_filter(db.database(), subscriber).recover(api_error::recover);
+
+        let resp = request()
+            .method("POST")
+            .path("/orders")
+            .header("x-operator", "Tim")
+            .json(&serde_json::json!({
+                "customerId": 1,
+                "rows": [{"vlaaiId": 4, "amount": 2}]
+            }))
+            .reply(&orders)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let order: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(order["id"], 3);
+        assert_eq!(order["status"], "new");
+        assert_eq!(order["rows"][0]["vlaai"], "Appel");
+
+        let resp = request()
+            .method("POST")
+            .path("/orders")
+            .json(&serde_json::json!({"customerId": 1, "rows": [{"vlaaiId": 42, "amount": 1}]}))
+            .reply(&orders)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Order 1 is in transit, so the screens get the new vlaaien
+        let resp = request()
+            .method("PUT")
+            .path("/orders/1/rows")
+            .json(&serde_json::json!([{"vlaaiId": 5, "amount": 3}]))
+            .reply(&orders)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        match published.recv().await.unwrap() {
+            OrderPublish::AddOrder(order) => {
+                assert_eq!(order.id, 1);
+                assert_eq!(
+                    order.rows,
+                    vec![db::OrderRow {
+                        vlaai: "Kruimelpudding".to_string(),
+                        amount: 3
+                    }]
+                );
+            }
+            other => panic!("Unexpected publish {:?}", other),
+        }
+
+        // Order 3 is not on the screen
+        let resp = request()
+            .method("DELETE")
+            .path("/orders/3")
+            .reply(&orders)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = request()
+            .method("DELETE")
+            .path("/orders/2")
+            .reply(&orders)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            published.recv().await.unwrap(),
+            OrderPublish::RemoveOrder(2)
+        );
+
+        let resp = request()
+            .method("DELETE")
+            .path("/orders/2")
+            .reply(&orders)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_vlaaien() {
+        let db = TestDatabase::seeded();
+        let vlaaien = super::vlaaien_filter(db.database()).recover(api_error::recover);
+
+        let resp = request()
+            .method("POST")
+            .path("/vlaaien")
+            .json(&serde_json::json!({"name": "Rijst", "priceCents": 1450}))
+            .reply(&vlaaien)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let vlaai: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(vlaai["id"], 6);
+        assert_eq!(vlaai["active"], true);
+
+        let resp = request()
+            .method("PUT")
+            .path("/vlaaien/1")
+            .json(&serde_json::json!({
+                "name": "Abrikoos",
+                "priceCents": 1250,
+                "active": false,
+                "description": "Met slagroom"
+            }))
+            .reply(&vlaaien)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = request()
+            .method("PUT")
+            .path("/vlaaien/1")
+            .json(&serde_json::json!({"name": "Kers", "priceCents": 1250}))
+            .reply(&vlaaien)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let resp = request()
+            .method("GET")
+            .path("/vlaaien?active=true")
+            .reply(&vlaaien)
+            .await;
+        let active: Vec<serde_json::Value> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(active.len(), 5);
+        assert_eq!(active[0]["name"], "HalfHalf");
+
+        let resp = request()
+            .method("GET")
+            .path("/vlaaien/1")
+            .reply(&vlaaien)
+            .await;
+        let vlaai: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(vlaai["priceCents"], 1250);
+        assert_eq!(vlaai["description"], "Met slagroom");
+
+        let resp = request()
+            .method("GET")
+            .path("/vlaaien/42")
+            .reply(&vlaaien)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_payments() {
+        let db = TestDatabase::seeded();
+        let (_sender, receiver) = tokio::sync::mpsc::channel(1);
+        let (subscriber, _runner) =
+            OrderStatusUpdater::<TestBackend>::new(receiver).order_mutator();
+        let mut published = subscriber.subscribe();
+        let payments =
+            super::payments_filter(db.database(), subscriber).recover(api_error::recover);
+
+        let resp = request()
+            .method("POST")
+            .path("/orders/1/payments")
+            .header("x-operator", "Tim")
+            .json(&serde_json::json!({"amountCents": 1250, "method": "bankTransfer"}))
+            .reply(&payments)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let payment: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(payment["method"], "bankTransfer");
+        assert_eq!(payment["operator"], "Tim");
+
+        // Order 1 is on the screen, so it is shown with the new balance
+        match published.recv().await.unwrap() {
+            OrderPublish::AddOrder(order) => assert_eq!(order.paid_cents, 1250),
+            other => panic!("Unexpected publish {:?}", other),
+        }
+
+        let resp = request()
+            .method("POST")
+            .path("/orders/1/payments")
+            .json(&serde_json::json!({"amountCents": 100, "method": "creditCard"}))
+            .reply(&payments)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = request()
+            .method("GET")
+            .path("/orders/1/payments")
+            .reply(&payments)
+            .await;
+        let all: Vec<serde_json::Value> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(all.len(), 1);
+
+        let resp = request()
+            .method("GET")
+            .path("/orders/99/payments")
+            .reply(&payments)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_order_history() {
+        let db = TestDatabase::seeded();
+        let history = super::order_history_filter(db.database()).recover(api_error::recover);
+
+        let resp = request()
+            .method("GET")
+            .path("/order/1/history")
+            .reply(&history)
+            .await;
         assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = request()
+            .method("GET")
+            .path("/order/9999/history")
+            .reply(&history)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let error: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(error["error"], "Order 9999 does not exist");
+        assert_eq!(error["code"], "notFound");
     }
 
     #[tokio::test]
     async fn test_get_order() {
-        let client = super::find_order_filter();
+        let db = TestDatabase::seeded();
+        let client = super::find_order_filter(db.database()).recover(api_error::recover);
 
         let resp = request()
             .method("GET")
@@ -266,5 +1689,15 @@ mod tests {
             .reply(&client)
             .await;
         assert_eq!(resp.status(), StatusCode::OK);
+
+        // A customer that does not exist is not a reason to drop the connection
+        let resp = request()
+            .method("GET")
+            .path("/order/find/9999")
+            .reply(&client)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let error: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(error["code"], "notFound");
     }
 }
@@ -3,6 +3,7 @@ table! {
         id -> Integer,
         name -> Text,
         email -> Nullable<Text>,
+        section_id -> Nullable<Integer>,
     }
 }
 
@@ -10,9 +11,38 @@ table! {
     order (id) {
         id -> Integer,
         customer_id -> Integer,
-        in_transit -> Bool,
-        picked_up -> Bool,
         order_number -> Nullable<Integer>,
+        status -> Text,
+        imported -> Bool,
+    }
+}
+
+table! {
+    order_event (id) {
+        id -> Integer,
+        order_id -> Integer,
+        previous_status -> Nullable<Text>,
+        new_status -> Text,
+        operator -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    payment (id) {
+        id -> Integer,
+        order_id -> Integer,
+        amount_cents -> Integer,
+        method -> Text,
+        operator -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    section (id) {
+        id -> Integer,
+        name -> Text,
     }
 }
 
@@ -20,6 +50,10 @@ table! {
     vlaai (id) {
         id -> Integer,
         name -> Text,
+        price_cents -> Integer,
+        active -> Bool,
+        image -> Nullable<Text>,
+        description -> Nullable<Text>,
     }
 }
 
@@ -32,9 +66,19 @@ table! {
     }
 }
 
+joinable!(customer -> section (section_id));
+joinable!(order -> customer (customer_id));
+joinable!(order_event -> order (order_id));
+joinable!(payment -> order (order_id));
+joinable!(vlaai_to_order -> order (order_id));
+joinable!(vlaai_to_order -> vlaai (vlaai_id));
+
 allow_tables_to_appear_in_same_query!(
     customer,
     order,
+    order_event,
+    payment,
+    section,
     vlaai,
     vlaai_to_order,
 );
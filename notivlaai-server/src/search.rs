@@ -0,0 +1,661 @@
+//! Finding customers by what someone types at the counter: diacritics and case are ignored,
+//! the words can come in any order and small typos are forgiven
+use crate::db::{self, Customer, Page, PageQuery, PendingOrder};
+use diesel::SqliteConnection;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Lower case ASCII with the diacritics removed and single spaces between the words,
+/// so "  José van den  Bérg" becomes "jose van den berg"
+pub fn normalize(text: &str) -> String {
+    deunicode::deunicode(text)
+        .to_lowercase()
+        .split(|c: char| !(c.is_alphanumeric() || c == '@' || c == '.'))
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Escape the wildcards of a `LIKE` pattern, to be used with `.escape('\\')`
+pub fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The edit distances from `a` to every prefix of `b`, the last one is to all of `b`
+fn distances(a: &str, b: &str) -> Vec<usize> {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitute.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous
+}
+
+/// The number of single character insertions, deletions or substitutions to go from `a` to `b`
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    *distances(a, b)
+        .last()
+        .expect("There is always a distance to the empty prefix")
+}
+
+/// How well a text matches the query, better matches sort first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rank {
+    /// The same text
+    Exact,
+    /// The text starts with the query
+    Prefix,
+    /// Every word of the query starts a word of the text, in any order
+    Token,
+    /// The query is somewhere in the text
+    Contains,
+    /// Every word of the query is close to a word of the text, with this many typos in total
+    Fuzzy(usize),
+}
+
+impl Rank {
+    /// The place of the rank in the sort order, for cursors
+    fn position(self) -> u32 {
+        match self {
+            Rank::Exact => 0,
+            Rank::Prefix => 1,
+            Rank::Token => 2,
+            Rank::Contains => 3,
+            Rank::Fuzzy(typos) => 4 + typos as u32,
+        }
+    }
+}
+
+/// The number of typos we forgive in a word of the query
+fn allowed_typos(word: &str) -> usize {
+    match word.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+/// The typos needed to match `word` with the start of the closest word of `words`, so that
+/// someone who is still typing finds the name as well
+fn typos(word: &str, words: &[&str]) -> Option<usize> {
+    words
+        .iter()
+        .filter_map(|candidate| distances(word, candidate).into_iter().min())
+        .min()
+        .filter(|&distance| distance <= allowed_typos(word))
+}
+
+/// Parts of a normalized query word of which at least one is unchanged in every text that
+/// matches the word: a word with `n` typos allowed is split in `n + 1` parts, and every typo
+/// changes at most one of them
+fn parts(word: &str) -> Vec<String> {
+    let chars: Vec<char> = word.chars().collect();
+    let count = allowed_typos(word) + 1;
+    (0..count)
+        .map(|i| {
+            chars[i * chars.len() / count..(i + 1) * chars.len() / count]
+                .iter()
+                .collect()
+        })
+        .collect()
+}
+
+/// Rank a normalized text against a normalized query, `None` if it does not match at all
+pub fn rank(query: &str, text: &str) -> Option<Rank> {
+    if query.is_empty() {
+        return None;
+    }
+    if text == query {
+        return Some(Rank::Exact);
+    }
+    if text.starts_with(query) {
+        return Some(Rank::Prefix);
+    }
+
+    let words: Vec<&str> = text.split(' ').collect();
+    let query_words: Vec<&str> = query.split(' ').collect();
+    if query_words
+        .iter()
+        .all(|q| words.iter().any(|w| w.starts_with(q)))
+    {
+        return Some(Rank::Token);
+    }
+    if text.contains(query) {
+        return Some(Rank::Contains);
+    }
+    query_words
+        .iter()
+        .map(|q| typos(q, &words))
+        .sum::<Option<usize>>()
+        .map(Rank::Fuzzy)
+}
+
+/// Which fields of a customer are searched besides the name
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SearchFields {
+    pub email: bool,
+    /// The name of the section of the customer
+    pub speltak: bool,
+}
+
+/// The best rank of a customer over the searched fields
+fn rank_customer(
+    query: &str,
+    customer: &Customer,
+    section: Option<&str>,
+    fields: SearchFields,
+) -> Option<Rank> {
+    let name = rank(query, &normalize(&customer.name));
+    let email = customer
+        .email
+        .as_deref()
+        .filter(|_| fields.email)
+        .and_then(|email| rank(query, &normalize(email)));
+    let section = section
+        .filter(|_| fields.speltak)
+        .and_then(|section| rank(query, &normalize(section)));
+    [name, email, section].iter().flatten().min().copied()
+}
+
+/// The place of a result in a search: by the reason it was found, then better ranks first and
+/// then sorted by name and id. The key of the last result of a page is the cursor of the next
+/// page
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SortKey {
+    /// The [`MatchReason`] of an order, always 0 for customers
+    reason: u32,
+    rank: u32,
+    name: String,
+    id: i32,
+}
+
+impl SortKey {
+    fn encode(&self) -> String {
+        format!("{}:{}:{}:{}", self.reason, self.rank, self.id, self.name)
+    }
+
+    fn decode(cursor: &str) -> Option<SortKey> {
+        let mut parts = cursor.splitn(4, ':');
+        let reason = parts.next()?.parse().ok()?;
+        let rank = parts.next()?.parse().ok()?;
+        let id = parts.next()?.parse().ok()?;
+        let name = parts.next()?.to_string();
+        Some(SortKey {
+            reason,
+            rank,
+            name,
+            id,
+        })
+    }
+}
+
+/// All customers matching the query with their place in the results, best matches first.
+/// Only the customers that have a part of every word of the query are loaded to be ranked
+pub fn customers(
+    conn: &SqliteConnection,
+    query: &str,
+    fields: SearchFields,
+) -> anyhow::Result<Vec<(SortKey, Customer)>> {
+    let query = normalize(query);
+    if query.is_empty() {
+        return Ok(Vec::new());
+    }
+    let query_parts: Vec<Vec<String>> = query.split(' ').map(parts).collect();
+    let candidates = db::customer_candidates(conn, &query_parts, fields)?;
+    let sections: HashMap<i32, String> = if fields.speltak {
+        db::all_sections(conn)?
+            .into_iter()
+            .map(|section| (section.id, section.name))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    let mut ranked: Vec<(SortKey, Customer)> = candidates
+        .into_iter()
+        .filter_map(|customer| {
+            let section = customer
+                .section_id
+                .and_then(|id| sections.get(&id))
+                .map(String::as_str);
+            let rank = rank_customer(&query, &customer, section, fields)?;
+            let key = SortKey {
+                reason: 0,
+                rank: rank.position(),
+                name: normalize(&customer.name),
+                id: customer.id,
+            };
+            Some((key, customer))
+        })
+        .collect();
+    ranked.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(ranked)
+}
+
+/// The page of the results that come after the cursor, which is the key of the last result of
+/// the previous page. Results that are added in between do not move the pages that follow.
+/// Returns `None` for a cursor we did not hand out
+pub fn page_after<T>(results: Vec<(SortKey, T)>, page: &PageQuery) -> Option<Page<T>> {
+    let after = match &page.cursor {
+        Some(cursor) => Some(SortKey::decode(cursor)?),
+        None => None,
+    };
+    let limit = db::page_size(page.limit) as usize;
+    let mut results: Vec<(SortKey, T)> = results
+        .into_iter()
+        .filter(|(key, _)| after.as_ref().is_none_or(|after| key > after))
+        .collect();
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    let next_cursor = if results.len() > limit {
+        results.truncate(limit);
+        results.last().map(|(key, _)| key.encode())
+    } else {
+        None
+    };
+    Some(Page {
+        items: results.into_iter().map(|(_, item)| item).collect(),
+        next_cursor,
+    })
+}
+
+/// Why an order was found, the reasons are sorted from most to least specific
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum MatchReason {
+    OrderId,
+    OrderNumber,
+    Email,
+    Name,
+    Vlaai,
+}
+
+/// An order that was found, shaped like a [`PendingOrder`] with the reason it matched
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderMatch {
+    #[serde(flatten)]
+    pub order: PendingOrder,
+    pub match_reason: MatchReason,
+}
+
+/// The vlaai a word of the query is about, "kersenvlaaien" is about "Kers"
+fn vlaai_in<'a>(word: &str, vlaaien: &'a [db::Vlaai]) -> Option<&'a db::Vlaai> {
+    vlaaien.iter().find(|vlaai| {
+        let name = normalize(&vlaai.name).replace(' ', "");
+        word.starts_with(&name) || (word.len() >= 3 && name.starts_with(word))
+    })
+}
+
+/// The ids of the orders with the vlaaien named in the query. A number in the query is the
+/// amount of each vlaai, so "2 kers" finds the orders with two kersenvlaaien. `None` if some
+/// words are not about vlaaien
+fn orders_with_vlaaien(conn: &SqliteConnection, query: &str) -> anyhow::Result<Option<Vec<i32>>> {
+    let vlaaien = db::all_vlaaien(conn, false)?;
+    let mut amount = None;
+    let mut wanted = Vec::new();
+    for word in query.split(' ') {
+        if let Ok(number) = word.parse::<i32>() {
+            amount = Some(number);
+        } else {
+            match vlaai_in(word, &vlaaien) {
+                Some(vlaai) => wanted.push(vlaai.id),
+                None => return Ok(None),
+            }
+        }
+    }
+    if wanted.is_empty() {
+        return Ok(None);
+    }
+
+    let mut rows: HashMap<i32, Vec<(i32, i32)>> = HashMap::new();
+    for (order_id, vlaai_id, row_amount) in db::rows_with_vlaaien(conn, &wanted)? {
+        rows.entry(order_id)
+            .or_default()
+            .push((vlaai_id, row_amount));
+    }
+    let mut ids: Vec<i32> = rows
+        .into_iter()
+        .filter(|(_, rows)| {
+            wanted.iter().all(|vlaai_id| {
+                rows.iter().any(|(id, row_amount)| {
+                    id == vlaai_id && amount.is_none_or(|a| *row_amount == a)
+                })
+            })
+        })
+        .map(|(order_id, _)| order_id)
+        .collect();
+    ids.sort_unstable();
+    Ok(Some(ids))
+}
+
+/// The orders of the customers found by a search, placed like their customers
+fn orders_of(
+    conn: &SqliteConnection,
+    reason: MatchReason,
+    customers: &[(SortKey, Customer)],
+) -> anyhow::Result<Vec<(SortKey, (i32, MatchReason))>> {
+    let ids: Vec<i32> = customers.iter().map(|(_, customer)| customer.id).collect();
+    let keys: HashMap<i32, &SortKey> = customers
+        .iter()
+        .map(|(key, customer)| (customer.id, key))
+        .collect();
+    Ok(db::order_ids_of_customers(conn, &ids)?
+        .into_iter()
+        .map(|(order_id, customer_id)| {
+            let key = SortKey {
+                reason: reason as u32,
+                id: order_id,
+                ..keys[&customer_id].clone()
+            };
+            (key, (order_id, reason))
+        })
+        .collect())
+}
+
+/// All orders matching the query with the reason they were found. An order is found once, with
+/// the most specific reason, and orders found for the same reason are sorted like the customers
+/// they belong to
+fn order_matches(
+    conn: &SqliteConnection,
+    query: &str,
+) -> anyhow::Result<Vec<(SortKey, (i32, MatchReason))>> {
+    let query = normalize(query.trim_start_matches('#'));
+    let by_id = |reason: MatchReason, order_id: i32| {
+        let key = SortKey {
+            reason: reason as u32,
+            rank: 0,
+            name: String::new(),
+            id: order_id,
+        };
+        (key, (order_id, reason))
+    };
+    let mut found = Vec::new();
+
+    if let Ok(number) = query.parse::<i32>() {
+        if db::pending_order(conn, number).is_ok() {
+            found.push(by_id(MatchReason::OrderId, number));
+        }
+        for order_id in db::order_ids_with_number(conn, number)? {
+            found.push(by_id(MatchReason::OrderNumber, order_id));
+        }
+    }
+
+    if query.contains('@') {
+        let fields = SearchFields {
+            email: true,
+            ..SearchFields::default()
+        };
+        let by_email: Vec<(SortKey, Customer)> =
+            db::customer_candidates(conn, &[vec![query.clone()]], fields)?
+                .into_iter()
+                .filter_map(|customer| {
+                    let rank = customer
+                        .email
+                        .as_deref()
+                        .and_then(|email| rank(&query, &normalize(email)))
+                        .filter(|rank| matches!(rank, Rank::Exact | Rank::Prefix))?;
+                    let key = SortKey {
+                        reason: 0,
+                        rank: rank.position(),
+                        name: normalize(&customer.name),
+                        id: customer.id,
+                    };
+                    Some((key, customer))
+                })
+                .collect();
+        found.extend(orders_of(conn, MatchReason::Email, &by_email)?);
+    }
+
+    let by_name = customers(conn, &query, SearchFields::default())?;
+    found.extend(orders_of(conn, MatchReason::Name, &by_name)?);
+
+    if let Some(order_ids) = orders_with_vlaaien(conn, &query)? {
+        for order_id in order_ids {
+            found.push(by_id(MatchReason::Vlaai, order_id));
+        }
+    }
+
+    // Keep the first, most specific, reason for every order
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut seen = std::collections::HashSet::new();
+    Ok(found
+        .into_iter()
+        .filter(|(_, (order_id, _))| seen.insert(*order_id))
+        .collect())
+}
+
+/// A page of the orders found by order number, order id, email, customer name or the vlaaien
+/// on it. Only the orders on the page are loaded. `None` for a cursor we did not hand out
+pub fn orders(
+    conn: &SqliteConnection,
+    query: &str,
+    page: &PageQuery,
+) -> anyhow::Result<Option<Page<OrderMatch>>> {
+    let page = match page_after(order_matches(conn, query)?, page) {
+        Some(page) => page,
+        None => return Ok(None),
+    };
+    let reasons: HashMap<i32, MatchReason> = page.items.iter().copied().collect();
+    let ids: Vec<i32> = page.items.iter().map(|(order_id, _)| *order_id).collect();
+    let items = db::pending_orders_by_id(conn, &ids)?
+        .into_iter()
+        .map(|order| OrderMatch {
+            match_reason: reasons[&(order.id as i32)],
+            order,
+        })
+        .collect();
+    Ok(Some(Page {
+        items,
+        next_cursor: page.next_cursor,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edit_distance, escape_like, normalize, parts, rank, Rank, SearchFields};
+    use crate::db::PageQuery;
+    use crate::test_support::TestDatabase;
+
+    #[test]
+    fn normalizing() {
+        assert_eq!(normalize("  José van den  Bérg"), "jose van den berg");
+        assert_eq!(normalize("Anne-Marie"), "anne marie");
+        assert_eq!(normalize("Ça Œuvre"), "ca oeuvre");
+        assert_eq!(escape_like("100%_\\"), "100\\%\\_\\\\");
+    }
+
+    #[test]
+    fn ranking() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(rank("jose", "jose"), Some(Rank::Exact));
+        assert_eq!(rank("jos", "jose van den berg"), Some(Rank::Prefix));
+        assert_eq!(rank("berg van", "jose van den berg"), Some(Rank::Token));
+        assert_eq!(rank("an den", "jose van den berg"), Some(Rank::Contains));
+        assert_eq!(rank("bregmans", "peter bergmans"), Some(Rank::Fuzzy(2)));
+        assert_eq!(rank("pokerfac", "piet pokerface"), Some(Rank::Token));
+        assert_eq!(rank("pkerf", "piet pokerface"), Some(Rank::Fuzzy(1)));
+        // Swapping two letters counts as two typos, too many for a short word
+        assert_eq!(rank("jsoe", "jose"), None);
+        assert_eq!(rank("jan", "jose van den berg"), None);
+        assert_eq!(rank("", "jose"), None);
+        assert!(Rank::Token < Rank::Fuzzy(0));
+        assert!(Rank::Fuzzy(1) < Rank::Fuzzy(2));
+        assert_eq!(parts("jan"), vec!["jan"]);
+        assert_eq!(parts("jose"), vec!["jo", "se"]);
+        assert_eq!(parts("bergmans"), vec!["be", "rgm", "ans"]);
+    }
+
+    #[test]
+    fn searching_orders() {
+        use super::MatchReason;
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+        let rows = [crate::db::RowChange {
+            vlaai_id: 3,
+            amount: 2,
+        }];
+        // Order 3 has two kersenvlaaien, the seeded orders one Abrikoos and one Kers
+        crate::db::create_order(&conn, 2, &rows, None).unwrap();
+        let found = |query: &str| -> Vec<(u32, MatchReason)> {
+            super::orders(&conn, query, &PageQuery::default())
+                .unwrap()
+                .unwrap()
+                .items
+                .into_iter()
+                .map(|m| (m.order.id, m.match_reason))
+                .collect()
+        };
+
+        // Both seeded orders have order number 1
+        assert_eq!(
+            found("#1"),
+            vec![(1, MatchReason::OrderId), (2, MatchReason::OrderNumber)]
+        );
+        assert_eq!(found("3"), vec![(3, MatchReason::OrderId)]);
+        assert_eq!(
+            found("POKEREN@pokerface.nl"),
+            vec![(2, MatchReason::Email), (3, MatchReason::Email)]
+        );
+        assert_eq!(found("bergmans"), vec![(1, MatchReason::Name)]);
+        assert_eq!(found("2 kersenvlaaien"), vec![(3, MatchReason::Vlaai)]);
+        assert_eq!(
+            found("kers"),
+            vec![
+                (1, MatchReason::Vlaai),
+                (2, MatchReason::Vlaai),
+                (3, MatchReason::Vlaai)
+            ]
+        );
+        assert!(found("appeltaart").is_empty());
+
+        // Pickup day, more orders than fit on the largest page are all found
+        for _ in 0..crate::db::MAX_PAGE_SIZE {
+            crate::db::create_order(&conn, 1, &rows, None).unwrap();
+        }
+        let mut page = PageQuery {
+            cursor: None,
+            limit: Some(crate::db::MAX_PAGE_SIZE),
+        };
+        let first = super::orders(&conn, "kers", &page).unwrap().unwrap();
+        assert_eq!(first.items.len() as i64, crate::db::MAX_PAGE_SIZE);
+        page.cursor = first.next_cursor;
+        let rest = super::orders(&conn, "kers", &page).unwrap().unwrap();
+        assert_eq!(rest.items.len(), 3);
+        assert_eq!(rest.items.last().unwrap().order.id, 203);
+        assert_eq!(rest.next_cursor, None);
+    }
+
+    #[test]
+    fn searching_customers() {
+        let db = TestDatabase::seeded();
+        let conn = db.conn();
+        let bevers = crate::db::create_section(&conn, "Bevers").unwrap();
+        for (name, section_id) in &[
+            ("José Bergmans", Some(bevers.id)),
+            ("Piet 100%", None),
+            ("Piet_Jansen", None),
+        ] {
+            let customer = crate::db::NewCustomer {
+                name,
+                email: None,
+                section_id: *section_id,
+            };
+            crate::db::create_customer(&conn, customer).unwrap();
+        }
+        let names = |query: &str, fields: SearchFields| -> Vec<String> {
+            super::customers(&conn, query, fields)
+                .unwrap()
+                .into_iter()
+                .map(|(_, c)| c.name)
+                .collect()
+        };
+
+        assert_eq!(
+            names("jose", SearchFields::default()),
+            vec!["José Bergmans"]
+        );
+        // Equally good matches are sorted by name
+        assert_eq!(
+            names("berg", SearchFields::default()),
+            vec!["José Bergmans", "Peter Bergmans"]
+        );
+        assert_eq!(
+            names("piet", SearchFields::default()),
+            // Peter is one typo away and comes last
+            vec![
+                "Piet 100%",
+                "Piet_Jansen",
+                "Piet Pokerface",
+                "Peter Bergmans"
+            ]
+        );
+        // Wildcards are just characters
+        assert_eq!(names("%", SearchFields::default()), Vec::<String>::new());
+        assert!(names("pokeren", SearchFields::default()).is_empty());
+        assert_eq!(
+            names(
+                "pokeren",
+                SearchFields {
+                    email: true,
+                    ..SearchFields::default()
+                }
+            ),
+            vec!["Piet Pokerface"]
+        );
+        assert!(names("bever", SearchFields::default()).is_empty());
+        assert_eq!(
+            names(
+                "bever",
+                SearchFields {
+                    speltak: true,
+                    ..SearchFields::default()
+                }
+            ),
+            vec!["José Bergmans"]
+        );
+
+        let page_names = |cursor: Option<String>, limit| {
+            let results = super::customers(&conn, "e", SearchFields::default()).unwrap();
+            let page = PageQuery {
+                cursor,
+                limit: Some(limit),
+            };
+            let page = super::page_after(results, &page).unwrap();
+            let names: Vec<String> = page.items.into_iter().map(|c| c.name).collect();
+            (names, page.next_cursor)
+        };
+        let (first, cursor) = page_names(None, 2);
+        assert_eq!(first, vec!["José Bergmans", "Peter Bergmans"]);
+        assert_eq!(cursor.as_deref(), Some("0:3:1:peter bergmans"));
+        // A customer that sorts before the cursor does not shift the next page
+        let customer = crate::db::NewCustomer {
+            name: "Eva",
+            email: None,
+            section_id: None,
+        };
+        crate::db::create_customer(&conn, customer).unwrap();
+        let (second, cursor) = page_names(cursor, 10);
+        assert_eq!(second, vec!["Piet 100%", "Piet_Jansen", "Piet Pokerface"]);
+        assert_eq!(cursor, None);
+        assert!(super::page_after(
+            Vec::<(super::SortKey, ())>::new(),
+            &PageQuery {
+                cursor: Some("2".to_string()),
+                limit: None,
+            }
+        )
+        .is_none());
+    }
+}
@@ -3,7 +3,8 @@ extern crate diesel;
 use diesel::prelude::*;
 use diesel::{QueryDsl, RunQueryDsl, SqliteConnection};
 
-use notivlaai_lib::db::{NewCustomer, NewOrder, NewVlaai, NewVlaaiToOrder};
+use notivlaai_lib::config::{Args, Config};
+use notivlaai_lib::db::{NewCustomer, NewOrder, NewVlaai, NewVlaaiToOrder, OrderStatus};
 use notivlaai_lib::schema;
 
 /// Insert a vlaai into the database
@@ -17,7 +18,11 @@ fn insert_vlaai(conn: &SqliteConnection, name: &str) {
 
 /// Insert a customer
 fn insert_customer(conn: &SqliteConnection, name: &str, email: &str) {
-    let customer = NewCustomer { name, email };
+    let customer = NewCustomer {
+        name,
+        email: Some(email),
+        section_id: None,
+    };
     diesel::insert_into(schema::customer::table)
         .values(customer)
         .execute(conn)
@@ -27,7 +32,7 @@ fn insert_customer(conn: &SqliteConnection, name: &str, email: &str) {
 /// Insert an order
 fn insert_order(
     conn: &SqliteConnection,
-    in_transit: bool,
+    status: OrderStatus,
     order_number: Option<i32>,
     name: &str,
     vlaaien: &[&str],
@@ -40,9 +45,8 @@ fn insert_order(
     diesel::insert_into(schema::order::table)
         .values(NewOrder {
             customer_id: client.id,
-            in_transit,
-            picked_up: false,
             order_number,
+            status,
         })
         .execute(conn)
         .expect("Could not insert order");
@@ -72,7 +76,10 @@ fn insert_order(
 }
 
 fn main() {
-    let conn = notivlaai_lib::db::establish_connection(false);
+    let config = Config::load(&Args::default()).expect("Could not load configuration");
+    let conn = notivlaai_lib::db::Database::from_config(&config)
+        .and_then(|database| database.conn())
+        .expect("Could not connect to the database");
 
     // Insert vlaaien
     insert_vlaai(&conn, "Abrikoos");
@@ -85,10 +92,16 @@ fn main() {
     insert_customer(&conn, "Peter Bergmans", "peter@peter.nl");
     insert_customer(&conn, "Piet Pokerface", "pokeren@pokerface.nl");
 
-    insert_order(&conn, false, None, "Peter Bergmans", &["Abrikoos", "Kers"]);
     insert_order(
         &conn,
-        true,
+        OrderStatus::New,
+        None,
+        "Peter Bergmans",
+        &["Abrikoos", "Kers"],
+    );
+    insert_order(
+        &conn,
+        OrderStatus::InTransit,
         Some(1),
         "Piet Pokerface",
         &["Abrikoos", "Kers"],
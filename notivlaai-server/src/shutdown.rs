@@ -0,0 +1,85 @@
+//! Stopping the server gracefully: the listeners stop accepting connections, the order
+//! updates that are still queued are processed and the websocket clients are closed
+use tokio::sync::{mpsc, watch};
+
+/// Completes when the process is asked to stop with SIGINT (Ctrl-C) or SIGTERM
+pub async fn signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Could not listen for SIGINT")
+    };
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+        tokio::select! {
+            _ = interrupt => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    interrupt.await;
+}
+
+/// Tells the listeners that they should stop accepting connections
+#[derive(Clone)]
+pub struct Shutdown {
+    receiver: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Wait until the shutdown is triggered, or the trigger has been dropped
+    pub async fn wait(&mut self) {
+        // The first value we receive is the current one
+        while let Some(stopping) = self.receiver.recv().await {
+            if stopping {
+                return;
+            }
+        }
+    }
+}
+
+/// Triggers all [`Shutdown`] handles that were created with it
+pub struct ShutdownTrigger {
+    sender: watch::Sender<bool>,
+}
+
+impl ShutdownTrigger {
+    pub fn shutdown(&self) {
+        // Nobody is listening anymore if this fails, which is fine as well
+        let _ = self.sender.broadcast(true);
+    }
+}
+
+/// Create a trigger and the handle to give to the listeners
+pub fn channel() -> (ShutdownTrigger, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+    (ShutdownTrigger { sender }, Shutdown { receiver })
+}
+
+/// Held by every task that should be able to finish before the server exits
+#[derive(Clone)]
+pub struct TaskGuard {
+    _sender: mpsc::Sender<()>,
+}
+
+/// Waits until all [`TaskGuard`]s are dropped
+pub struct TaskWaiter {
+    receiver: mpsc::Receiver<()>,
+}
+
+impl TaskWaiter {
+    pub async fn wait(mut self) {
+        // Nothing is ever sent, this returns when all senders are gone
+        self.receiver.recv().await;
+    }
+}
+
+/// Create a guard to clone into tasks and the waiter for those tasks
+pub fn tasks() -> (TaskGuard, TaskWaiter) {
+    let (sender, receiver) = mpsc::channel(1);
+    (TaskGuard { _sender: sender }, TaskWaiter { receiver })
+}
@@ -1,17 +1,90 @@
 use crate::db;
-use anyhow::anyhow;
 use std::collections::HashMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
 use tokio::sync::broadcast::{channel, Receiver, Sender};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
+
+/// Why an update could not be processed, sent back to whoever asked for it
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpdateError {
+    /// The order does not exist
+    NotFound(String),
+    /// The order cannot get the requested status
+    Conflict(String),
+    /// Processing the update failed for another reason
+    Failed(String),
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateError::NotFound(message)
+            | UpdateError::Conflict(message)
+            | UpdateError::Failed(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for UpdateError {}
+
+impl From<&anyhow::Error> for UpdateError {
+    fn from(e: &anyhow::Error) -> Self {
+        match e.downcast_ref::<db::StatusError>() {
+            Some(db::StatusError::NotFound(_)) => UpdateError::NotFound(e.to_string()),
+            Some(db::StatusError::InvalidTransition { .. }) => UpdateError::Conflict(e.to_string()),
+            _ => UpdateError::Failed(e.to_string()),
+        }
+    }
+}
+
+/// Receives the updated order, once the update has been processed
+pub type UpdateReply = oneshot::Sender<Result<db::PendingOrder, UpdateError>>;
 
 /// The message that can be received by
 /// someone subscribing on the updater
 #[derive(Debug)]
 pub enum UpdateOrder {
     /// Remove an order from the screen
-    OrderRetrieved(u32),
+    OrderRetrieved {
+        id: u32,
+        operator: Option<String>,
+        reply: Option<UpdateReply>,
+    },
     /// Order is in transit
-    OrderInTransit(u32),
+    OrderInTransit {
+        id: u32,
+        operator: Option<String>,
+        reply: Option<UpdateReply>,
+    },
+    /// Give the order another status
+    ChangeStatus {
+        id: u32,
+        status: db::OrderStatus,
+        operator: Option<String>,
+        reply: Option<UpdateReply>,
+    },
+}
+
+impl UpdateOrder {
+    /// The order this update is for
+    pub fn id(&self) -> u32 {
+        match self {
+            UpdateOrder::OrderRetrieved { id, .. }
+            | UpdateOrder::OrderInTransit { id, .. }
+            | UpdateOrder::ChangeStatus { id, .. } => *id,
+        }
+    }
+
+    /// Take the channel to reply on, if the sender is waiting for one
+    fn take_reply(&mut self) -> Option<UpdateReply> {
+        match self {
+            UpdateOrder::OrderRetrieved { reply, .. }
+            | UpdateOrder::OrderInTransit { reply, .. }
+            | UpdateOrder::ChangeStatus { reply, .. } => reply.take(),
+        }
+    }
 }
 
 /// This enum signifies published changes to the order
@@ -21,16 +94,35 @@ pub enum OrderPublish {
     AddOrder(db::PendingOrder),
     /// Remove an existing order from the screen
     RemoveOrder(u32),
+    /// The update of an order could not be processed
+    UpdateFailed { id: u32, reason: String },
+    /// An order has been picked up without being paid in full
+    PaymentWarning { id: u32, outstanding_cents: i32 },
 }
 
 /// Defines an OrderRunner backend that can be abstracted over, so we can have
 /// a database backend and a vector backend
 pub trait Backend {
-    /// Tell the backend to update the order
-    fn order_in_transit(&mut self, id: u32) -> anyhow::Result<db::Order>;
+    /// Tell the backend to update the order, the operator is recorded in the order history
+    fn order_in_transit(&mut self, id: u32, operator: Option<&str>) -> anyhow::Result<db::Order>;
 
     /// Tell the backend that the order has been retrieved
-    fn order_retrieved(&mut self, id: u32) -> anyhow::Result<()>;
+    fn order_retrieved(&mut self, id: u32, operator: Option<&str>) -> anyhow::Result<db::Order>;
+
+    /// Give the order another status, backends that can only send and retrieve orders
+    /// reject the other statuses
+    fn change_status(
+        &mut self,
+        id: u32,
+        status: db::OrderStatus,
+        operator: Option<&str>,
+    ) -> anyhow::Result<db::Order> {
+        match status {
+            db::OrderStatus::InTransit => self.order_in_transit(id, operator),
+            db::OrderStatus::PickedUp => self.order_retrieved(id, operator),
+            other => Err(anyhow::anyhow!("Cannot change orders to '{}'", other)),
+        }
+    }
 
     /// Convert an order to a pending order
     fn to_pending(&self, order: db::Order) -> anyhow::Result<db::PendingOrder>;
@@ -42,23 +134,38 @@ pub struct DBBackend {
     max_order: i32,
 }
 
-impl Default for DBBackend {
-    fn default() -> Self {
-        let conn = db::establish_connection(false);
+impl DBBackend {
+    /// Create a backend that works on a connection from the given database
+    pub fn new(database: &db::Database) -> Result<Self, db::DatabaseError> {
+        let conn = database.conn()?;
         let max_order = db::max_order_number(&conn).unwrap_or_default();
-        DBBackend { conn, max_order }
+        Ok(DBBackend { conn, max_order })
     }
 }
 
 impl Backend for DBBackend {
-    fn order_in_transit(&mut self, id: u32) -> anyhow::Result<db::Order> {
-        let result = db::update_order_in_transit(&self.conn, id as i32, self.max_order);
+    fn order_in_transit(&mut self, id: u32, operator: Option<&str>) -> anyhow::Result<db::Order> {
+        let order = db::update_order_in_transit(&self.conn, id as i32, self.max_order, operator)?;
         self.max_order += 1;
-        result
+        Ok(order)
     }
-    fn order_retrieved(&mut self, id: u32) -> anyhow::Result<()> {
-        db::update_order_retrieved(&self.conn, id as i32)?;
-        Ok(())
+    fn order_retrieved(&mut self, id: u32, operator: Option<&str>) -> anyhow::Result<db::Order> {
+        Ok(db::update_order_retrieved(&self.conn, id as i32, operator)?)
+    }
+    fn change_status(
+        &mut self,
+        id: u32,
+        status: db::OrderStatus,
+        operator: Option<&str>,
+    ) -> anyhow::Result<db::Order> {
+        let conn = &self.conn;
+        Ok(match status {
+            db::OrderStatus::InTransit => return self.order_in_transit(id, operator),
+            db::OrderStatus::Ready => db::update_order_ready(conn, id as i32, operator)?,
+            db::OrderStatus::PickedUp => db::update_order_retrieved(conn, id as i32, operator)?,
+            db::OrderStatus::New => db::update_order_new(conn, id as i32, operator)?,
+            db::OrderStatus::Cancelled => db::cancel_order(conn, id as i32, operator)?,
+        })
     }
     fn to_pending(&self, order: db::Order) -> anyhow::Result<db::PendingOrder> {
         db::to_pending(&self.conn, order)
@@ -77,42 +184,62 @@ impl Default for TestBackend {
             db::Order {
                 id: 1,
                 customer_id: 1,
-                in_transit: false,
-                picked_up: false,
                 order_number: Some(1),
+                status: db::OrderStatus::New,
+                imported: false,
             },
         );
         Self { orders: map }
     }
 }
 
-// Backend for simple testing
-impl Backend for TestBackend {
-    fn order_in_transit(&mut self, id: u32) -> anyhow::Result<db::Order> {
+impl TestBackend {
+    /// Change the status of an order, with the same rules as the database
+    fn set_status(&mut self, id: u32, next: db::OrderStatus) -> anyhow::Result<db::Order> {
         let order = self
             .orders
             .get_mut(&id)
-            .ok_or_else(|| anyhow!("Not there"))?;
-        order.picked_up = false;
-        order.in_transit = true;
+            .ok_or(db::StatusError::NotFound(id as i32))?;
+        if !order.status.can_transition_to(next) {
+            return Err(db::StatusError::InvalidTransition {
+                order_id: order.id,
+                from: order.status,
+                to: next,
+            }
+            .into());
+        }
+        order.status = next;
         Ok(*order)
     }
-    fn order_retrieved(&mut self, id: u32) -> anyhow::Result<()> {
-        let order = self
-            .orders
-            .get_mut(&id)
-            .ok_or_else(|| anyhow!("Not there"))?;
-        order.picked_up = true;
-        order.in_transit = false;
-        Ok(())
+}
+
+// Backend for simple testing
+impl Backend for TestBackend {
+    fn order_in_transit(&mut self, id: u32, _operator: Option<&str>) -> anyhow::Result<db::Order> {
+        self.set_status(id, db::OrderStatus::InTransit)
+    }
+    fn order_retrieved(&mut self, id: u32, _operator: Option<&str>) -> anyhow::Result<db::Order> {
+        self.set_status(id, db::OrderStatus::PickedUp)
+    }
+    fn change_status(
+        &mut self,
+        id: u32,
+        status: db::OrderStatus,
+        _operator: Option<&str>,
+    ) -> anyhow::Result<db::Order> {
+        self.set_status(id, status)
     }
     fn to_pending(&self, order: db::Order) -> anyhow::Result<db::PendingOrder> {
         Ok(db::PendingOrder {
             id: order.id as u32,
-            in_transit: true,
-            picked_up: false,
+            status: order.status,
+            in_transit: order.status.is_pending(),
+            picked_up: order.status == db::OrderStatus::PickedUp,
             customer_name: "Piet".to_string(),
             rows: Default::default(),
+            total_cents: 0,
+            paid_cents: 0,
+            outstanding_cents: 0,
         })
     }
 }
@@ -135,31 +262,111 @@ pub struct OrderRunner<T> {
     backend: T,
 }
 
+/// How long to wait before trying to restart a backend again
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
 impl<T: Backend> OrderRunner<T> {
-    /// Receive updates and publishes these over the broadcaster
-    pub async fn run(mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        while let Some(value) = self.receiver.recv().await {
-            log::info!("Got message {:?}", value);
-            let value = match value {
-                UpdateOrder::OrderRetrieved(id) => {
-                    self.backend.order_retrieved(id)?;
-                    // Remove this order from the screen
-                    OrderPublish::RemoveOrder(id)
+    /// Process a single update with the backend
+    fn process(&mut self, update: &UpdateOrder) -> anyhow::Result<db::PendingOrder> {
+        let order = match update {
+            UpdateOrder::OrderRetrieved { id, operator, .. } => {
+                self.backend.order_retrieved(*id, operator.as_deref())?
+            }
+            UpdateOrder::OrderInTransit { id, operator, .. } => {
+                self.backend.order_in_transit(*id, operator.as_deref())?
+            }
+            UpdateOrder::ChangeStatus {
+                id,
+                status,
+                operator,
+                ..
+            } => self
+                .backend
+                .change_status(*id, *status, operator.as_deref())?,
+        };
+        self.backend.to_pending(order)
+    }
+
+    /// Receive updates and publishes these over the broadcaster, until all senders are gone.
+    /// An update that fails is published as [`OrderPublish::UpdateFailed`], the outcome is
+    /// also sent to the reply channel of the update
+    pub async fn run(self)
+    where
+        T: Default,
+    {
+        self.supervise(|| Ok(T::default())).await
+    }
+
+    /// Like [`OrderRunner::run`], but when the backend panics while processing an update
+    /// the runner is restarted with a backend from `restart`, keeping the queued updates
+    pub async fn supervise<F>(mut self, mut restart: F)
+    where
+        F: FnMut() -> anyhow::Result<T>,
+    {
+        while let Some(mut update) = self.receiver.recv().await {
+            let reply = update.take_reply();
+            log::info!("Got message {:?}", update);
+            let id = update.id();
+            let result = match panic::catch_unwind(AssertUnwindSafe(|| self.process(&update))) {
+                Ok(Ok(pending)) => Ok(pending),
+                Ok(Err(e)) => {
+                    log::warn!("Could not process {:?}: {}", update, e);
+                    Err(UpdateError::from(&e))
                 }
-                UpdateOrder::OrderInTransit(id) => {
-                    let order = self.backend.order_in_transit(id)?;
-                    // Add a new order to the screen
-                    OrderPublish::AddOrder(self.backend.to_pending(order)?)
+                Err(_) => {
+                    log::error!("Runner crashed processing {:?}, restarting", update);
+                    self.backend = loop {
+                        match restart() {
+                            Ok(backend) => break backend,
+                            Err(e) => {
+                                log::error!("Could not restart runner: {}", e);
+                                tokio::time::delay_for(RESTART_DELAY).await;
+                            }
+                        }
+                    };
+                    Err(UpdateError::Failed(
+                        "The update crashed the runner".to_string(),
+                    ))
                 }
             };
+
+            let value = match &result {
+                // Add a new order to the screen, or show its new status
+                Ok(pending) if pending.status.is_pending() => {
+                    OrderPublish::AddOrder(pending.clone())
+                }
+                // Remove this order from the screen
+                Ok(_) => OrderPublish::RemoveOrder(id),
+                Err(e) => OrderPublish::UpdateFailed {
+                    id,
+                    reason: e.to_string(),
+                },
+            };
             // Do nothing in case of ok or an error, just keep on sending
-            if self.publisher.send(value).is_ok() {}
+            let _ = self.publisher.send(value);
+            if let Ok(pending) = &result {
+                if pending.status == db::OrderStatus::PickedUp && !pending.is_paid() {
+                    log::warn!(
+                        "Order {} picked up with {} cents outstanding",
+                        id,
+                        pending.outstanding_cents
+                    );
+                    let _ = self.publisher.send(OrderPublish::PaymentWarning {
+                        id,
+                        outstanding_cents: pending.outstanding_cents,
+                    });
+                }
+            }
+            // The sender may have stopped waiting, which is fine as well
+            if let Some(reply) = reply {
+                let _ = reply.send(result);
+            }
         }
-        Ok(())
     }
 }
 
 /// Gives out subscriptions to receive updates to orders
+#[derive(Clone)]
 pub struct OrderSubscriber {
     /// Publisher, used to give out new subscriptions
     publisher: Sender<OrderPublish>,
@@ -171,17 +378,43 @@ impl OrderSubscriber {
     pub fn subscribe(&self) -> Receiver<OrderPublish> {
         self.publisher.subscribe()
     }
+
+    /// Publish a change that was made without the runner, like an order whose vlaaien
+    /// have been changed
+    pub fn publish(&self, change: OrderPublish) {
+        // Nobody may be listening, which is fine
+        let _ = self.publisher.send(change);
+    }
 }
 
 impl<T: Backend + Default> OrderStatusUpdater<T> {
     pub fn new(receiver: mpsc::Receiver<UpdateOrder>) -> OrderStatusUpdater<T> {
+        OrderStatusUpdater::with_backend(receiver, Default::default())
+    }
+}
+
+impl<T: Backend> OrderStatusUpdater<T> {
+    /// Create an updater that processes the updates with the given backend
+    pub fn with_backend(
+        receiver: mpsc::Receiver<UpdateOrder>,
+        backend: T,
+    ) -> OrderStatusUpdater<T> {
+        OrderStatusUpdater::with_capacity(receiver, backend, 100)
+    }
+
+    /// Create an updater where subscribers can lag `capacity` published changes behind
+    pub fn with_capacity(
+        receiver: mpsc::Receiver<UpdateOrder>,
+        backend: T,
+        capacity: usize,
+    ) -> OrderStatusUpdater<T> {
         // This is the async channel
-        let (sender, _) = channel(100);
+        let (sender, _) = channel(capacity);
 
         OrderStatusUpdater {
             publisher: sender,
             receiver,
-            backend: Default::default(),
+            backend,
         }
     }
 
@@ -207,7 +440,111 @@ impl<T: Backend + Default> OrderStatusUpdater<T> {
 #[cfg(test)]
 mod tests {
 
-    use super::{TestBackend, UpdateOrder};
+    use super::{Backend, DBBackend, OrderPublish, TestBackend, UpdateOrder};
+    use crate::db;
+    use crate::test_support::TestDatabase;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Order 2 does not exist and order 3 crashes the backend, the others are fine
+    #[derive(Default)]
+    struct FailingBackend {
+        inner: TestBackend,
+    }
+
+    impl FailingBackend {
+        fn check(id: u32) -> anyhow::Result<()> {
+            match id {
+                2 => Err(db::StatusError::NotFound(2).into()),
+                3 => panic!("Backend crashed on order 3"),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl Backend for FailingBackend {
+        fn order_in_transit(
+            &mut self,
+            id: u32,
+            operator: Option<&str>,
+        ) -> anyhow::Result<db::Order> {
+            FailingBackend::check(id)?;
+            self.inner.order_in_transit(id, operator)
+        }
+        fn order_retrieved(
+            &mut self,
+            id: u32,
+            operator: Option<&str>,
+        ) -> anyhow::Result<db::Order> {
+            FailingBackend::check(id)?;
+            self.inner.order_retrieved(id, operator)
+        }
+        fn to_pending(&self, order: db::Order) -> anyhow::Result<db::PendingOrder> {
+            self.inner.to_pending(order)
+        }
+    }
+
+    fn in_transit(id: u32) -> UpdateOrder {
+        UpdateOrder::OrderInTransit {
+            id,
+            operator: None,
+            reply: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn runner_survives_failed_updates() {
+        let (mut sender, receiver) = tokio::sync::mpsc::channel(100);
+        let (subscriber, runner) =
+            super::OrderStatusUpdater::<FailingBackend>::new(receiver).order_mutator();
+        let mut receiver = subscriber.subscribe();
+        tokio::spawn(async { runner.run().await });
+
+        sender.send(in_transit(2)).await.unwrap();
+        sender.send(in_transit(1)).await.unwrap();
+
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            OrderPublish::UpdateFailed {
+                id: 2,
+                reason: "Order 2 does not exist".to_string()
+            }
+        );
+        // The next update is processed as usual
+        assert!(matches!(receiver.recv().await.unwrap(), OrderPublish::AddOrder(o) if o.id == 1));
+    }
+
+    #[tokio::test]
+    async fn runner_restarts_after_crash() {
+        let (mut sender, receiver) = tokio::sync::mpsc::channel(100);
+        let (subscriber, runner) =
+            super::OrderStatusUpdater::<FailingBackend>::new(receiver).order_mutator();
+        let mut receiver = subscriber.subscribe();
+
+        let restarts = Arc::new(AtomicUsize::new(0));
+        let counter = restarts.clone();
+        let runner = tokio::spawn(async move {
+            runner
+                .supervise(|| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    Ok(FailingBackend::default())
+                })
+                .await
+        });
+
+        sender.send(in_transit(3)).await.unwrap();
+        sender.send(in_transit(1)).await.unwrap();
+
+        assert!(
+            matches!(receiver.recv().await.unwrap(), OrderPublish::UpdateFailed { id, .. } if id == 3)
+        );
+        assert!(matches!(receiver.recv().await.unwrap(), OrderPublish::AddOrder(o) if o.id == 1));
+        assert_eq!(restarts.load(Ordering::SeqCst), 1);
+
+        // The restarted runner still stops when the senders are gone
+        drop(sender);
+        runner.await.unwrap();
+    }
 
     #[tokio::test]
     async fn test_update() {
@@ -220,7 +557,14 @@ mod tests {
         tokio::spawn(async { runner.run().await });
 
         // Set that the order is in transit
-        assert!(sender.send(UpdateOrder::OrderInTransit(1)).await.is_ok());
+        assert!(sender
+            .send(UpdateOrder::OrderInTransit {
+                id: 1,
+                operator: None,
+                reply: None,
+            })
+            .await
+            .is_ok());
 
         // Expect to get an update
         let publish_update = receiver.recv().await.unwrap();
@@ -232,7 +576,14 @@ mod tests {
         }
 
         // Set that the order has been picked up
-        assert!(sender.send(UpdateOrder::OrderRetrieved(1)).await.is_ok());
+        assert!(sender
+            .send(UpdateOrder::OrderRetrieved {
+                id: 1,
+                operator: None,
+                reply: None,
+            })
+            .await
+            .is_ok());
 
         // Expect to get an update
         let publish_update = receiver.recv().await.unwrap();
@@ -245,4 +596,79 @@ mod tests {
 
         assert!(receiver.try_recv().is_err())
     }
+
+    #[tokio::test]
+    async fn test_db_backend() {
+        let db = TestDatabase::seeded();
+        let (mut sender, receiver) = tokio::sync::mpsc::channel(100);
+        let order_updater = super::OrderStatusUpdater::with_backend(
+            receiver,
+            DBBackend::new(&db.database()).unwrap(),
+        );
+        let (subscriber, runner) = order_updater.order_mutator();
+        let mut receiver = subscriber.subscribe();
+
+        let database = db.database();
+        tokio::spawn(async move { runner.supervise(|| Ok(DBBackend::new(&database)?)).await });
+
+        // An order that does not exist does not stop the runner
+        sender
+            .send(UpdateOrder::OrderRetrieved {
+                id: 9999,
+                operator: None,
+                reply: None,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            receiver.recv().await.unwrap(),
+            super::OrderPublish::UpdateFailed { id: 9999, .. }
+        ));
+
+        // Order 1 is in transit in the seed, so we can pick it up
+        assert!(sender
+            .send(UpdateOrder::OrderRetrieved {
+                id: 1,
+                operator: None,
+                reply: None,
+            })
+            .await
+            .is_ok());
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            super::OrderPublish::RemoveOrder(1)
+        );
+
+        let order = crate::db::orders_for_customer(&db.conn(), 1).unwrap()[0];
+        assert_eq!(order.status, crate::db::OrderStatus::PickedUp);
+
+        // Picking up an order that has not been paid comes with a warning
+        let abrikoos = db::VlaaiChange {
+            name: "Abrikoos".to_string(),
+            price_cents: 1250,
+            active: true,
+            image: None,
+            description: None,
+        };
+        db::update_vlaai(&db.conn(), 1, &abrikoos).unwrap();
+        sender
+            .send(UpdateOrder::OrderRetrieved {
+                id: 2,
+                operator: None,
+                reply: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            super::OrderPublish::RemoveOrder(2)
+        );
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            super::OrderPublish::PaymentWarning {
+                id: 2,
+                outstanding_cents: 1250
+            }
+        );
+    }
 }
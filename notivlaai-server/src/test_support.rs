@@ -0,0 +1,112 @@
+//! Helpers to run tests against a fresh database instead of the committed `notivlaai.sqlite3`
+use crate::db::{self, ConnectionOptions, Database, OrderStatus, PooledConnection};
+use crate::schema::*;
+use diesel::prelude::*;
+use diesel::SqliteConnection;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Used to give every test database in this process its own file
+static DATABASE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A database in a temporary file with all migrations applied, the file is removed on drop.
+/// A file is used instead of `:memory:` so that every connection in the pool sees the same data
+pub struct TestDatabase {
+    path: PathBuf,
+    database: Database,
+}
+
+impl TestDatabase {
+    /// Create a database without any data in it
+    pub fn empty() -> TestDatabase {
+        TestDatabase::with_seed(|_| Ok(()))
+    }
+
+    /// Create a database filled with the default seed, see [`default_seed`]
+    pub fn seeded() -> TestDatabase {
+        TestDatabase::with_seed(default_seed)
+    }
+
+    /// Create a database and fill it using the `seed` function
+    pub fn with_seed<F>(seed: F) -> TestDatabase
+    where
+        F: FnOnce(&SqliteConnection) -> QueryResult<()>,
+    {
+        let path = std::env::temp_dir().join(format!(
+            "notivlaai-test-{}-{}.sqlite3",
+            std::process::id(),
+            DATABASE_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        // Left over from an earlier run that got killed
+        let _ = std::fs::remove_file(&path);
+
+        let database = Database::open(&path.to_string_lossy(), ConnectionOptions::default())
+            .expect("Could not open test database");
+
+        let test_database = TestDatabase { path, database };
+        let conn = test_database.conn();
+        db::run_migrations(&conn).expect("Could not run migrations on test database");
+        conn.transaction(|| seed(&conn))
+            .expect("Could not seed test database");
+        test_database
+    }
+
+    /// Get a connection to the test database
+    pub fn conn(&self) -> PooledConnection {
+        self.database.conn().expect("Could not get test connection")
+    }
+
+    /// Handle to the test database, to pass to the parts of the server under test
+    pub fn database(&self) -> Database {
+        self.database.clone()
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Fills the database with five vlaaien, two customers and an order for each of them with an
+/// Abrikoos and a Kers vlaai, both in transit with order number 1
+pub fn default_seed(conn: &SqliteConnection) -> QueryResult<()> {
+    for name in &["Abrikoos", "HalfHalf", "Kers", "Appel", "Kruimelpudding"] {
+        diesel::insert_into(vlaai::table)
+            .values(db::NewVlaai { name })
+            .execute(conn)?;
+    }
+
+    for (name, email) in &[
+        ("Peter Bergmans", "peter@peter.nl"),
+        ("Piet Pokerface", "pokeren@pokerface.nl"),
+    ] {
+        diesel::insert_into(customer::table)
+            .values(db::NewCustomer {
+                name,
+                email: Some(email),
+                section_id: None,
+            })
+            .execute(conn)?;
+    }
+
+    for customer_id in 1..=2 {
+        diesel::insert_into(order::table)
+            .values(db::NewOrder {
+                customer_id,
+                order_number: Some(1),
+                status: OrderStatus::InTransit,
+            })
+            .execute(conn)?;
+        for vlaai_id in &[1, 3] {
+            diesel::insert_into(vlaai_to_order::table)
+                .values(db::NewVlaaiToOrder {
+                    order_id: customer_id,
+                    vlaai_id: *vlaai_id,
+                    amount: 1,
+                })
+                .execute(conn)?;
+        }
+    }
+    Ok(())
+}
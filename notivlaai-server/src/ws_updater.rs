@@ -1,31 +1,52 @@
 use crate::db;
-use crate::status_updater::{Backend, OrderPublish, OrderRunner, OrderSubscriber};
-use futures_util::sink::SinkExt;
-use futures_util::{stream::TryStreamExt, StreamExt};
+use crate::shutdown::{Shutdown, TaskGuard};
+use crate::status_updater::{OrderPublish, OrderSubscriber};
+use diesel::SqliteConnection;
+use futures_util::sink::{Sink, SinkExt};
+use futures_util::stream::{Stream, TryStreamExt};
+use futures_util::StreamExt;
 use log::info;
 use serde::Serialize;
+use std::fmt::Display;
+use std::net::SocketAddr;
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::broadcast::{Receiver, RecvError},
 };
-use tungstenite::protocol::Message;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, Message};
+use warp::Filter;
 
-/// Update the order screen using websockets
+/// Update the order screen using websockets on a separate port, next to the
+/// `/ws/orders` route of the HTTP server
 pub struct WsUpdater {
-    port: u32,
+    addr: SocketAddr,
+    database: db::Database,
 }
 
 impl WsUpdater {
-    pub fn new(port: u32) -> WsUpdater {
-        WsUpdater { port }
+    pub fn new(addr: SocketAddr, database: db::Database) -> WsUpdater {
+        WsUpdater { addr, database }
     }
 
-    pub async fn start<BackendImpl: Backend + Send + 'static>(
+    /// Accept connections until `shutdown` is triggered, the connections are closed when
+    /// `closing` is triggered and hold on to `tasks` until then
+    pub async fn start(
         self,
         subscriber: OrderSubscriber,
-        runner: OrderRunner<BackendImpl>,
+        shutdown: Shutdown,
+        closing: Shutdown,
+        tasks: TaskGuard,
     ) {
-        start_server(self.port, subscriber, runner).await;
+        start_server(
+            self.addr,
+            self.database,
+            subscriber,
+            shutdown,
+            closing,
+            tasks,
+        )
+        .await;
     }
 }
 
@@ -39,6 +60,16 @@ pub enum OrderNotification {
     AddOrder(db::PendingOrder),
     /// Remove an order
     RemoveOrder(u32),
+    /// An update of an order could not be processed
+    UpdateFailed { id: u32, reason: String },
+    /// An order has been picked up but has not been paid in full
+    #[serde(rename_all = "camelCase")]
+    PaymentWarning { id: u32, outstanding_cents: i32 },
+}
+
+/// The first notification a client receives, containing all orders currently on the screen
+pub fn initialize_notification(conn: &SqliteConnection) -> anyhow::Result<OrderNotification> {
+    Ok(OrderNotification::Initialize(db::all_pending_orders(conn)?))
 }
 
 impl From<OrderPublish> for OrderNotification {
@@ -46,63 +77,127 @@ impl From<OrderPublish> for OrderNotification {
         match pubish {
             OrderPublish::AddOrder(p) => OrderNotification::AddOrder(p),
             OrderPublish::RemoveOrder(idx) => OrderNotification::RemoveOrder(idx),
+            OrderPublish::UpdateFailed { id, reason } => {
+                OrderNotification::UpdateFailed { id, reason }
+            }
+            OrderPublish::PaymentWarning {
+                id,
+                outstanding_cents,
+            } => OrderNotification::PaymentWarning {
+                id,
+                outstanding_cents,
+            },
         }
     }
 }
 
-async fn handle_connection(
-    stream: TcpStream,
-    addr: std::net::SocketAddr,
-    mut receiver: Receiver<OrderPublish>,
-) {
-    info!("Incoming TCP connection from: {}", addr);
+/// Sent to the clients in the close frame when the server stops
+pub const SHUTDOWN_REASON: &str = "Server is shutting down";
 
-    let ws_stream = tokio_tungstenite::accept_async(stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
-    info!("WebSocket connection established: {}", addr);
+/// Close code telling the client the server is going down
+const GOING_AWAY: u16 = 1001;
+
+/// Websocket messages we send, for both websocket implementations we use
+trait ClientMessage {
+    fn text(text: String) -> Self;
+    /// Tell the client we are going away
+    fn going_away(reason: &'static str) -> Self;
+}
+
+impl ClientMessage for Message {
+    fn text(text: String) -> Self {
+        Message::text(text)
+    }
+
+    fn going_away(reason: &'static str) -> Self {
+        Message::Close(Some(CloseFrame {
+            code: CloseCode::from(GOING_AWAY),
+            reason: reason.into(),
+        }))
+    }
+}
+
+impl ClientMessage for warp::ws::Message {
+    fn text(text: String) -> Self {
+        warp::ws::Message::text(text)
+    }
+
+    fn going_away(reason: &'static str) -> Self {
+        warp::ws::Message::close_with(GOING_AWAY, reason)
+    }
+}
+
+/// Sends the pending orders and then every order change to a client, until either side
+/// closes the connection. When `closing` is triggered the changes that were already
+/// published are sent, followed by a close frame
+async fn notify_client<Out, In, M, T, E>(
+    mut outgoing: Out,
+    incoming: In,
+    addr: Option<SocketAddr>,
+    database: db::Database,
+    mut receiver: Receiver<OrderPublish>,
+    mut closing: Shutdown,
+) where
+    M: ClientMessage,
+    Out: Sink<M> + Unpin,
+    Out::Error: Display,
+    In: Stream<Item = Result<T, E>> + Unpin,
+{
+    let peer = addr.map_or_else(|| "unknown client".to_string(), |a| a.to_string());
 
     // Create a sqlite connection
-    let conn = crate::db::establish_connection(false);
-
-    let (mut outgoing, incoming) = ws_stream.split();
-
-    let broadcast_incoming = incoming.try_for_each(|_msg| {
-        // println!(
-        //     "Received a message from {}: {}",
-        //     addr,
-        //     msg.to_text().unwrap()
-        // );
-        futures_util::future::ready(Ok(()))
-    });
-
-    let pending =
-        crate::db::all_pending_orders(&conn).expect("Could not get pending orders from database");
-    let json = serde_json::to_string(&OrderNotification::Initialize(pending))
-        .expect("Could not serialze pending orders to json");
-    outgoing
-        .send(Message::text(json))
-        .await
-        .expect("Could not send message");
+    let initialize = database
+        .conn()
+        .map_err(anyhow::Error::from)
+        .and_then(|conn| initialize_notification(&conn));
+    let initialize = match initialize {
+        Ok(initialize) => initialize,
+        Err(e) => {
+            log::error!("Closing connection to {}: {}", peer, e);
+            return;
+        }
+    };
+
+    // We do not expect anything from the client, but keep reading to notice when it leaves
+    let broadcast_incoming = incoming.try_for_each(|_msg| futures_util::future::ready(Ok(())));
+
+    let json =
+        serde_json::to_string(&initialize).expect("Could not serialze pending orders to json");
+    if let Err(e) = outgoing.send(M::text(json)).await {
+        log::warn!("Could not send pending orders to {}: {}", peer, e);
+        return;
+    }
 
     let send_message = async move {
         // Receive order updates
         loop {
-            let message = receiver.recv().await;
-            // If we are closed break out of this
-            if let Err(RecvError::Closed) = message {
-                break;
-            }
-            // Otherwise just go on
-            if let Ok(value) = message {
-                outgoing
-                    .send(Message::text(
-                        serde_json::to_string(&OrderNotification::from(value))
-                            .expect("Could not convert update to json"),
-                    ))
-                    .await
-                    .expect("Could not send update");
+            let message = tokio::select! {
+                message = receiver.recv() => message,
+                _ = closing.wait() => break,
             };
+            match message {
+                Ok(value) => {
+                    if let Err(e) = send_update(&mut outgoing, value).await {
+                        log::warn!("Could not send update to {}: {}", peer, e);
+                        return;
+                    }
+                }
+                // Otherwise just go on
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            }
+        }
+
+        // The server is stopping, send the changes published before that
+        while let Ok(value) = receiver.try_recv() {
+            if let Err(e) = send_update(&mut outgoing, value).await {
+                log::warn!("Could not send update to {}: {}", peer, e);
+                return;
+            }
+        }
+        info!("Closing connection to {}", peer);
+        if let Err(e) = outgoing.send(M::going_away(SHUTDOWN_REASON)).await {
+            log::warn!("Could not close connection to {}: {}", peer, e);
         }
     };
 
@@ -112,23 +207,271 @@ async fn handle_connection(
     }
 }
 
-async fn start_server<BackendImpl: Backend + Send + 'static>(
-    port: u32,
+/// Send a published change to a client
+async fn send_update<Out, M>(outgoing: &mut Out, value: OrderPublish) -> Result<(), Out::Error>
+where
+    M: ClientMessage,
+    Out: Sink<M> + Unpin,
+{
+    let json = serde_json::to_string(&OrderNotification::from(value))
+        .expect("Could not convert update to json");
+    outgoing.send(M::text(json)).await
+}
+
+/// GET /ws/orders, upgrades to a websocket that receives the order notifications
+pub fn orders_filter(
     subscriber: OrderSubscriber,
-    runner: OrderRunner<BackendImpl>,
+    database: db::Database,
+    closing: Shutdown,
+    tasks: TaskGuard,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("ws" / "orders")
+        .and(warp::ws())
+        .and(warp::addr::remote())
+        .map(move |ws: warp::ws::Ws, addr: Option<SocketAddr>| {
+            info!("WebSocket connection requested by: {:?}", addr);
+            let receiver = subscriber.subscribe();
+            let database = database.clone();
+            let closing = closing.clone();
+            let tasks = tasks.clone();
+            ws.on_upgrade(move |socket| async move {
+                let (outgoing, incoming) = socket.split();
+                notify_client(outgoing, incoming, addr, database, receiver, closing).await;
+                drop(tasks);
+            })
+        })
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    database: db::Database,
+    receiver: Receiver<OrderPublish>,
+    closing: Shutdown,
 ) {
-    let addr = format!("0.0.0.0:{}", port);
+    info!("Incoming TCP connection from: {}", addr);
 
-    // Wait for new updates
-    tokio::spawn(async move { runner.run().await });
+    let ws_stream = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            log::warn!("Error during the websocket handshake with {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("WebSocket connection established: {}", addr);
+
+    let (outgoing, incoming) = ws_stream.split();
+    notify_client(outgoing, incoming, Some(addr), database, receiver, closing).await;
+}
 
+async fn start_server(
+    addr: SocketAddr,
+    database: db::Database,
+    subscriber: OrderSubscriber,
+    mut shutdown: Shutdown,
+    closing: Shutdown,
+    tasks: TaskGuard,
+) {
     // Create the event loop and TCP listener we'll accept connections on.
     let try_socket = TcpListener::bind(&addr).await;
     let mut listener = try_socket.expect("Failed to bind");
     info!("Listening on: {}", addr);
     // Let's spawn the handling of each connection in a separate task.
-    while let Ok((stream, addr)) = listener.accept().await {
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            _ = shutdown.wait() => break,
+        };
         let receiver = subscriber.subscribe();
-        tokio::spawn(handle_connection(stream, addr, receiver));
+        let database = database.clone();
+        let closing = closing.clone();
+        let tasks = tasks.clone();
+        tokio::spawn(async move {
+            handle_connection(stream, addr, database, receiver, closing).await;
+            drop(tasks);
+        });
+    }
+    info!("Stopped listening on: {}", addr);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::test::{misaligned_database, MISALIGNED_NAMES};
+    use crate::shutdown;
+    use crate::status_updater::{OrderStatusUpdater, TestBackend, UpdateOrder};
+    use crate::test_support::TestDatabase;
+    use futures_util::StreamExt;
+    use tungstenite::protocol::frame::coding::CloseCode;
+    use tungstenite::protocol::Message;
+
+    /// Parse the next message of the websocket as json
+    async fn next_json(client: &mut warp::test::WsClient) -> serde_json::Value {
+        let message = client.recv().await.expect("Websocket closed");
+        serde_json::from_str(message.to_str().expect("Expected a text message")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn orders_route() {
+        let db = TestDatabase::seeded();
+        let (mut sender, receiver) = tokio::sync::mpsc::channel(100);
+        let (subscriber, runner) = OrderStatusUpdater::<TestBackend>::new(receiver).order_mutator();
+        tokio::spawn(async { runner.run().await });
+
+        let (_close_clients, closing) = shutdown::channel();
+        let (tasks, _) = shutdown::tasks();
+        let filter = super::orders_filter(subscriber, db.database(), closing, tasks);
+        let mut client = warp::test::ws()
+            .path("/ws/orders")
+            .handshake(filter)
+            .await
+            .expect("Handshake failed");
+
+        // First we get all pending orders
+        let json = next_json(&mut client).await;
+        assert_eq!(json["initialize"].as_array().unwrap().len(), 2);
+
+        // Followed by the changes
+        sender
+            .send(UpdateOrder::OrderInTransit {
+                id: 1,
+                operator: None,
+                reply: None,
+            })
+            .await
+            .unwrap();
+        let json = next_json(&mut client).await;
+        assert_eq!(json["addOrder"]["id"], 1);
+    }
+
+    #[test]
+    fn initialize_customer_names() {
+        let db = misaligned_database();
+        let notification = super::initialize_notification(&db.conn()).unwrap();
+
+        // Check the names as the client will see them
+        let json = serde_json::to_value(&notification).unwrap();
+        let names: Vec<_> = json["initialize"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|o| {
+                (
+                    o["id"].as_u64().unwrap() as u32,
+                    o["customerName"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(names, MISALIGNED_NAMES.to_vec());
+    }
+
+    /// Updates that are still queued when the server stops
+    fn queued_updates() -> Vec<UpdateOrder> {
+        vec![
+            UpdateOrder::OrderInTransit {
+                id: 1,
+                operator: None,
+                reply: None,
+            },
+            UpdateOrder::OrderRetrieved {
+                id: 1,
+                operator: None,
+                reply: None,
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_updates_before_closing() {
+        let db = TestDatabase::seeded();
+        let (mut sender, receiver) = tokio::sync::mpsc::channel(100);
+        let (subscriber, runner) = OrderStatusUpdater::<TestBackend>::new(receiver).order_mutator();
+        let runner = tokio::spawn(async { runner.run().await });
+
+        let (close_clients, closing) = shutdown::channel();
+        let (tasks, _) = shutdown::tasks();
+        let filter = super::orders_filter(subscriber, db.database(), closing, tasks);
+        let mut client = warp::test::ws()
+            .path("/ws/orders")
+            .handshake(filter)
+            .await
+            .expect("Handshake failed");
+        next_json(&mut client).await;
+
+        for update in queued_updates() {
+            sender.send(update).await.unwrap();
+        }
+
+        // Stopping the HTTP server drops the sender, after which the queue is processed
+        drop(sender);
+        runner.await.unwrap();
+        close_clients.shutdown();
+
+        // The client still gets every update before the connection is closed
+        let json = next_json(&mut client).await;
+        assert_eq!(json["addOrder"]["id"], 1);
+        let json = next_json(&mut client).await;
+        assert_eq!(json["removeOrder"], 1);
+        client.recv_closed().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn standalone_shutdown_sends_close_reason() {
+        let db = TestDatabase::seeded();
+        let (mut sender, receiver) = tokio::sync::mpsc::channel(100);
+        let (subscriber, runner) = OrderStatusUpdater::<TestBackend>::new(receiver).order_mutator();
+        let runner = tokio::spawn(async { runner.run().await });
+
+        let (stop_listening, shutdown) = shutdown::channel();
+        let (close_clients, closing) = shutdown::channel();
+        let (tasks, clients_closed) = shutdown::tasks();
+
+        // Find a free port for the listener
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let updater = super::WsUpdater::new(addr, db.database());
+        let listener = tokio::spawn(updater.start(subscriber, shutdown, closing, tasks));
+
+        // Wait until the listener is up
+        let url = format!("ws://{}", addr);
+        let mut client = loop {
+            match tokio_tungstenite::connect_async(url.as_str()).await {
+                Ok((client, _)) => break client,
+                Err(_) => tokio::time::delay_for(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        client.next().await.unwrap().unwrap();
+
+        for update in queued_updates() {
+            sender.send(update).await.unwrap();
+        }
+
+        // Go through the same steps as the server does
+        stop_listening.shutdown();
+        listener.await.unwrap();
+        drop(sender);
+        runner.await.unwrap();
+        close_clients.shutdown();
+
+        let mut messages = Vec::new();
+        while let Some(Ok(message)) = client.next().await {
+            messages.push(message);
+        }
+        assert_eq!(messages.len(), 3);
+        assert!(messages[..2].iter().all(|m| m.is_text()));
+        match &messages[2] {
+            Message::Close(Some(frame)) => {
+                assert_eq!(frame.code, CloseCode::Away);
+                assert_eq!(frame.reason, super::SHUTDOWN_REASON);
+            }
+            other => panic!("Expected a close frame, got {:?}", other),
+        }
+
+        // Once the client is gone the server can exit
+        drop(client);
+        clients_closed.wait().await;
     }
 }